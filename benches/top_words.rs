@@ -0,0 +1,48 @@
+use criterion::{BatchSize, Criterion, criterion_group, criterion_main};
+use std::collections::HashMap;
+
+// The module's tests don't run here, only the functions benchmarked are used
+#[allow(dead_code, unused_imports)]
+#[path = "../src/rank.rs"]
+mod rank;
+
+// Distinct words in the benchmark, about a large group chat's vocabulary
+const WORDS: usize = 200_000;
+const TOP: usize = 200;
+
+fn word_counts() -> HashMap<String, usize> {
+    // Zipf-like counts: the n-th word is used about WORDS / n times
+    (1..=WORDS)
+        .map(|rank| (format!("word{}", rank), WORDS / rank))
+        .collect()
+}
+
+fn bench_top_words(c: &mut Criterion) {
+    let word_counts = word_counts();
+    let weight_of = |_: &str, count: usize| count as f32;
+
+    let mut group = c.benchmark_group("top_words");
+    group.bench_function("heap", |b| {
+        b.iter_batched(
+            || word_counts.clone(),
+            |word_counts| rank::top_words(word_counts, TOP, weight_of),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("sort_and_truncate", |b| {
+        b.iter_batched(
+            || word_counts.clone(),
+            |word_counts| {
+                let mut words: Vec<_> = word_counts.into_iter().collect();
+                rank::sort_by_weight(&mut words, weight_of);
+                words.truncate(TOP);
+                words
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_top_words);
+criterion_main!(benches);
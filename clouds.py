@@ -10,6 +10,16 @@ from wordcloud import WordCloud
 import argparse
 from pathlib import Path
 
+def proportion(value):
+    """argparse type for a fraction between 0.0 and 1.0"""
+    try:
+        number = float(value)
+    except ValueError:
+        raise argparse.ArgumentTypeError(f"invalid number: {value!r}")
+    if not 0.0 <= number <= 1.0:
+        raise argparse.ArgumentTypeError(f"must be between 0.0 and 1.0, got {value}")
+    return number
+
 def main():
     parser = argparse.ArgumentParser(description='Generate word cloud from preprocessed data')
     parser.add_argument('--input', '-i', required=True, help='Input file with word counts')
@@ -19,17 +29,29 @@ def main():
     parser.add_argument('--font', default='DejaVu Sans', help='Font to use (must support Cyrillic)')
     parser.add_argument('--max-font-size', type=int, default=120, help='Maximum font size')
     parser.add_argument('--background', default='black', help='Background color')
+    parser.add_argument('--rotate-proportion', type=proportion, default=0.1,
+                        help='Fraction of words drawn vertically, 0.0 for all horizontal')
+    parser.add_argument('--encoding', default='utf-8-sig',
+                        help='Encoding of the input file (see --dump-encoding)')
     args = parser.parse_args()
     
     # Read the preprocessed word counts
     word_dict = {}
-    with open(args.input, 'r', encoding='utf-8') as f:
+    color_dict = {}
+    with open(args.input, 'r', encoding=args.encoding) as f:
         for line in f:
             parts = line.strip().split(' ')
+            # Optional trailing "#rrggbb" color column (see --color-by)
+            color = None
+            if len(parts) >= 3 and parts[-1].startswith('#'):
+                color = parts.pop()
             if len(parts) >= 2:
                 word = ' '.join(parts[:-1])  # Handle multi-word tokens if any
-                count = int(parts[-1])
-                word_dict[word] = count
+                # Counts, or weights with --recency-halflife / --weight-emphasized
+                weight = float(parts[-1])
+                word_dict[word] = weight
+                if color:
+                    color_dict[word] = color
     
     print(f"Loaded {len(word_dict)} words")
     
@@ -43,8 +65,10 @@ def main():
         font_step=1,
         regexp=r"\S+",  # Match any non-whitespace characters
         collocations=False,  # Don't include bigrams
-        prefer_horizontal=0.9,  # Allow some vertical words
+        prefer_horizontal=1.0 - args.rotate_proportion,
     ).generate_from_frequencies(word_dict)
+    if color_dict:
+        wordcloud.recolor(color_func=lambda word, **kwargs: color_dict.get(word, 'white'))
     
     # Save the image
     plt.figure(figsize=(args.width / 100, args.height / 100), dpi=100)
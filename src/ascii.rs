@@ -0,0 +1,67 @@
+// Terminal width the cloud is laid out in, in characters
+const LINE_WIDTH: usize = 80;
+// Words shown, the tail of a long list is unreadable as text anyway
+const MAX_WORDS: usize = 60;
+
+/// Lay out a rough text cloud, heaviest words first. Words at half the top
+/// weight or more are spaced out capitals, at a fifth or more capitals, the
+/// rest lowercase. Lines are centered.
+pub fn render_ascii(words: &[(String, f32)]) -> String {
+    let max_weight = words.first().map(|(_, weight)| *weight).unwrap_or(0.0);
+
+    let mut lines: Vec<String> = Vec::new();
+    let mut line = String::new();
+    for (word, weight) in words.iter().take(MAX_WORDS) {
+        let styled = style_word(word, *weight, max_weight);
+        let needed = styled.chars().count() + usize::from(!line.is_empty());
+        if !line.is_empty() && line.chars().count() + needed > LINE_WIDTH {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(&styled);
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+
+    lines
+        .iter()
+        .map(|line| {
+            let padding = LINE_WIDTH.saturating_sub(line.chars().count()) / 2;
+            format!("{}{}\n", " ".repeat(padding), line)
+        })
+        .collect()
+}
+
+fn style_word(word: &str, weight: f32, max_weight: f32) -> String {
+    if weight * 2.0 >= max_weight {
+        let upper = word.to_uppercase();
+        let letters: Vec<String> = upper.chars().map(String::from).collect();
+        format!(" {} ", letters.join(" "))
+    } else if weight * 5.0 >= max_weight {
+        word.to_uppercase()
+    } else {
+        word.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn top_word_is_spaced_capitals() {
+        let words: Vec<(String, f32)> =
+            [("rust", 10.0), ("cargo", 3.0), ("crab", 1.0)]
+                .into_iter()
+                .map(|(word, weight)| (word.to_string(), weight))
+                .collect();
+        let cloud = render_ascii(&words);
+        assert_eq!(cloud.lines().count(), 1);
+        // Centered in the line width
+        let line = " R U S T  CARGO crab";
+        assert_eq!(cloud, format!("{}{}\n", " ".repeat(30), line));
+    }
+}
@@ -0,0 +1,239 @@
+use anyhow::{Context, Result, bail};
+use clap::ValueEnum;
+use std::path::Path;
+
+pub type Rgb = [u8; 3];
+
+/// How word colors are picked for the cloud
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ColorBy {
+    /// Leave colors to the renderer
+    Random,
+    /// Gradient from cold (light words) to hot (heavy words)
+    Frequency,
+    /// Color derived from the word itself, same word always same color
+    Hash,
+}
+
+// Ends of the heatmap gradient
+const COLD: Rgb = [49, 54, 149];
+const HOT: Rgb = [215, 48, 39];
+
+/// Map a weight onto the cold-to-hot gradient, relative to the weight
+/// range
+pub fn frequency_color(weight: f32, min_weight: f32, max_weight: f32) -> Rgb {
+    lerp(
+        COLD,
+        HOT,
+        frequency_position(weight, min_weight, max_weight),
+    )
+}
+
+/// Where a weight sits in the weight range, from 0 (lightest) to 1
+fn frequency_position(weight: f32, min_weight: f32, max_weight: f32) -> f32 {
+    let t = if max_weight > min_weight {
+        (weight - min_weight) / (max_weight - min_weight)
+    } else {
+        1.0
+    };
+    t.clamp(0.0, 1.0)
+}
+
+/// Stable color for a word: FNV-1a hash mapped onto the hue wheel
+pub fn hash_color(word: &str) -> Rgb {
+    let hue = (fnv1a(word) % 360) as f32;
+    hsl_to_rgb(hue, 0.65, 0.55)
+}
+
+// FNV-1a, fixed so colors don't change between builds or platforms
+fn fnv1a(word: &str) -> u32 {
+    let mut hash: u32 = 0x811c_9dc5;
+    for byte in word.bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash
+}
+
+/// Pick a color for every word, or `None` when the renderer decides.
+/// With a `palette` every color comes from it: words cycle through it in
+/// rank order, hash into it, or map their weight onto it from first
+/// (light) to last (heavy).
+pub fn assign_colors(
+    words: &[(String, f32)],
+    color_by: ColorBy,
+    palette: Option<&[Rgb]>,
+) -> Option<Vec<Rgb>> {
+    let min_weight = words.iter().map(|(_, w)| *w).reduce(f32::min);
+    let max_weight = words.iter().map(|(_, w)| *w).reduce(f32::max);
+    let min_weight = min_weight.unwrap_or(0.0);
+    let max_weight = max_weight.unwrap_or(0.0);
+    if let Some(palette) = palette {
+        let colors = words.iter().enumerate().map(|(rank, (word, weight))| {
+            let index = match color_by {
+                ColorBy::Random => rank % palette.len(),
+                ColorBy::Frequency => {
+                    let t = frequency_position(*weight, min_weight, max_weight);
+                    (t * (palette.len() - 1) as f32).round() as usize
+                }
+                ColorBy::Hash => fnv1a(word) as usize % palette.len(),
+            };
+            palette[index]
+        });
+        return Some(colors.collect());
+    }
+
+    match color_by {
+        ColorBy::Random => None,
+        ColorBy::Frequency => Some(
+            words
+                .iter()
+                .map(|(_, weight)| {
+                    frequency_color(*weight, min_weight, max_weight)
+                })
+                .collect(),
+        ),
+        ColorBy::Hash => {
+            Some(words.iter().map(|(word, _)| hash_color(word)).collect())
+        }
+    }
+}
+
+/// Read a palette of hex colors (`#rrggbb` or `rrggbb`), one per line.
+/// Blank lines are skipped.
+pub fn read_palette_file(path: &Path) -> Result<Vec<Rgb>> {
+    let content = std::fs::read_to_string(path).with_context(|| {
+        format!("Failed to read palette file {}", path.display())
+    })?;
+
+    let mut palette = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let Some(color) = parse_hex(line) else {
+            bail!(
+                "Invalid color '{}' on line {} of {}, expected #rrggbb",
+                line,
+                number + 1,
+                path.display()
+            );
+        };
+        palette.push(color);
+    }
+    if palette.is_empty() {
+        bail!("Palette file {} has no colors", path.display());
+    }
+    Ok(palette)
+}
+
+fn parse_hex(value: &str) -> Option<Rgb> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    if hex.len() != 6 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+pub fn to_hex(color: Rgb) -> String {
+    format!("#{:02x}{:02x}{:02x}", color[0], color[1], color[2])
+}
+
+fn lerp(from: Rgb, to: Rgb, t: f32) -> Rgb {
+    let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round() as u8;
+    [
+        mix(from[0], to[0]),
+        mix(from[1], to[1]),
+        mix(from[2], to[2]),
+    ]
+}
+
+/// `hue` in degrees, `saturation` and `lightness` in 0..=1
+fn hsl_to_rgb(hue: f32, saturation: f32, lightness: f32) -> Rgb {
+    let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let h = hue / 60.0;
+    let x = chroma * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = lightness - chroma / 2.0;
+    let channel = |v: f32| ((v + m) * 255.0).round() as u8;
+    [channel(r), channel(g), channel(b)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frequency_color_spans_gradient() {
+        assert_eq!(frequency_color(2.0, 2.0, 10.0), COLD);
+        assert_eq!(frequency_color(6.0, 2.0, 10.0), [132, 51, 94]);
+        assert_eq!(frequency_color(10.0, 2.0, 10.0), HOT);
+        // Fractional weights and a single weight
+        assert_eq!(frequency_color(0.75, 0.5, 1.0), [132, 51, 94]);
+        assert_eq!(frequency_color(3.0, 3.0, 3.0), HOT);
+    }
+
+    #[test]
+    fn colors_only_from_palette() {
+        let path = crate::tests::temp_path("palette.txt");
+        std::fs::write(&path, "#ff0000\n\n00ff00\n#0000FF\n").unwrap();
+        let palette = read_palette_file(&path).unwrap();
+        assert_eq!(palette, [[255, 0, 0], [0, 255, 0], [0, 0, 255]]);
+
+        let words: Vec<(String, f32)> = (0..20)
+            .map(|i| (format!("word{}", i), (i * i) as f32 + 0.5))
+            .collect();
+        for color_by in [ColorBy::Random, ColorBy::Frequency, ColorBy::Hash] {
+            let colors = assign_colors(&words, color_by, Some(&palette)).unwrap();
+            assert_eq!(colors.len(), words.len());
+            assert!(colors.iter().all(|color| palette.contains(color)));
+        }
+
+        std::fs::write(&path, "#ff0000\nred\n").unwrap();
+        let error = read_palette_file(&path).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("Invalid color 'red' on line 2 of ")
+        );
+    }
+
+    #[test]
+    fn hash_color_stable_per_word() {
+        // Pinned, so a change of hash or hue mapping shows up here
+        assert_eq!(hash_color("rust"), [183, 66, 215]);
+        assert_eq!(hash_color("rust"), hash_color("rust"));
+        assert_ne!(hash_color("rust"), hash_color("crab"));
+
+        // Neither rank nor weight changes a word's color
+        let words = |pairs: &[(&str, f32)]| -> Vec<(String, f32)> {
+            pairs
+                .iter()
+                .map(|(word, weight)| (word.to_string(), *weight))
+                .collect()
+        };
+        let first = assign_colors(
+            &words(&[("rust", 9.0), ("crab", 2.0)]),
+            ColorBy::Hash,
+            None,
+        )
+        .unwrap();
+        let second = assign_colors(
+            &words(&[("crab", 50.0), ("borrow", 7.0), ("rust", 1.0)]),
+            ColorBy::Hash,
+            None,
+        )
+        .unwrap();
+        assert_eq!(first[0], second[2]);
+        assert_eq!(first[1], second[0]);
+    }
+}
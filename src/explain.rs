@@ -0,0 +1,233 @@
+use crate::parse::SimpleMessage;
+use crate::pos::{self, PartOfSpeech};
+use crate::tokenizer::{self, TokenizeOptions};
+use std::collections::{HashMap, HashSet};
+
+/// Settings of the stages a word passes through before it is counted
+pub struct Pipeline<'a> {
+    pub tokenize_options: &'a TokenizeOptions,
+    /// Already stemmed with `stem_stopwords`
+    pub stop_words: &'a [String],
+    pub stem_stopwords: bool,
+    pub langs: &'a [String],
+    pub stem_exceptions: &'a HashSet<String>,
+    pub pos: PartOfSpeech,
+}
+
+/// What happened to one surface word along the pipeline, for --explain
+pub struct Trace {
+    word: String,
+    steps: Vec<String>,
+    /// The form the word is counted as, `None` once it was dropped
+    stem: Option<String>,
+    /// Count of `stem` at the last check
+    count: Option<usize>,
+}
+
+impl Trace {
+    /// Run `word` through tokenizing, stop words, part of speech filtering
+    /// and stemming
+    pub fn new(word: &str, pipeline: &Pipeline) -> Self {
+        let mut trace = Self {
+            word: word.to_string(),
+            steps: Vec::new(),
+            stem: None,
+            count: None,
+        };
+        trace.stem = trace.follow(pipeline);
+        trace
+    }
+
+    fn follow(&mut self, pipeline: &Pipeline) -> Option<String> {
+        let options = pipeline.tokenize_options;
+        let message = SimpleMessage {
+            text: self.word.clone(),
+            ..Default::default()
+        };
+        let tokens =
+            tokenizer::tokenize_messages(std::slice::from_ref(&message), options);
+
+        let parts: Vec<String> =
+            tokens.iter().map(|token| token.word.clone()).collect();
+        let length = self.word.chars().count();
+        let Some(token) = tokens.into_iter().next() else {
+            let reason = if length < options.min_length {
+                format!("shorter than --min-length {}", options.min_length)
+            } else if options.max_length.is_some_and(|max| length > max) {
+                "longer than --max-length".to_string()
+            } else if options.letters_only {
+                "has characters other than letters (--letters-only)".to_string()
+            } else {
+                "not a word to the tokenizer".to_string()
+            };
+            self.steps.push(format!("dropped: {}", reason));
+            return None;
+        };
+        if parts.len() > 1 {
+            self.steps.push(format!(
+                "split into {}, following '{}'",
+                parts.join(", "),
+                token.word
+            ));
+        } else {
+            self.steps.push(format!("tokenized as '{}'", token.word));
+        }
+
+        let tokens = vec![token];
+        let tokens = if pipeline.stem_stopwords {
+            tokens
+        } else {
+            tokenizer::filter_stop_words(
+                tokens,
+                pipeline.stop_words,
+                options.locale,
+            )
+        };
+        if tokens.is_empty() {
+            self.steps.push("dropped: stop word".to_string());
+            return None;
+        }
+
+        let tokens = pos::filter_pos(tokens, pipeline.langs, pipeline.pos);
+        if tokens.is_empty() {
+            self.steps.push(format!(
+                "dropped: not tagged as {:?} (--pos)",
+                pipeline.pos
+            ));
+            return None;
+        }
+
+        let excepted = pipeline.stem_exceptions.contains(&tokens[0].word);
+        let tokens = tokenizer::stem_tokens(
+            tokens,
+            pipeline.langs,
+            pipeline.stem_exceptions,
+        );
+        let stem = tokens[0].word.clone();
+        if excepted {
+            self.steps
+                .push("kept as is, listed in --stem-exceptions-file".to_string());
+        } else if stem == tokens[0].original {
+            self.steps.push("unchanged by stemming".to_string());
+        } else {
+            self.steps.push(format!("stemmed to '{}'", stem));
+        }
+
+        if pipeline.stem_stopwords
+            && tokenizer::filter_stop_words(
+                tokens,
+                pipeline.stop_words,
+                options.locale,
+            )
+            .is_empty()
+        {
+            self.steps
+                .push("dropped: stemmed form is a stop word".to_string());
+            return None;
+        }
+
+        Some(stem)
+    }
+
+    /// Note how `stage` changed the count of the word. The first check
+    /// tells whether the word occurs in the selected messages at all.
+    pub fn check_counts(&mut self, counts: &HashMap<String, usize>, stage: &str) {
+        let Some(stem) = &self.stem else {
+            return;
+        };
+        let count = counts.get(stem).copied();
+        match (self.count, count) {
+            (None, Some(count)) => {
+                self.steps.push(format!("counted {} times", count));
+            }
+            (None, None) => {
+                self.steps
+                    .push("dropped: not found in the selected messages".into());
+                self.stem = None;
+            }
+            (Some(_), None) => {
+                self.steps.push(format!("dropped by {}", stage));
+                self.stem = None;
+            }
+            (Some(before), Some(after)) if before != after => {
+                self.steps
+                    .push(format!("count changed to {} by {}", after, stage));
+            }
+            (Some(_), Some(_)) => {}
+        }
+        self.count = count;
+    }
+
+    /// Note where the word ranks in the sorted list and whether it makes
+    /// the --max-words cut
+    pub fn check_rank(&mut self, words: &[(String, usize)], max_words: usize) {
+        let Some(stem) = &self.stem else {
+            return;
+        };
+        let Some(rank) = words.iter().position(|(word, _)| word == stem) else {
+            return;
+        };
+        if max_words > 0 && rank >= max_words {
+            self.steps.push(format!(
+                "dropped: rank {} is below the --max-words {} cutoff",
+                rank + 1,
+                max_words
+            ));
+        } else {
+            self.steps
+                .push(format!("in the cloud at rank {}", rank + 1));
+        }
+    }
+
+    pub fn print(&self) {
+        println!("Explain '{}':", self.word);
+        for step in &self.steps {
+            println!("  {}", step);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stop_word_rejection_traced() {
+        let tokenize_options = TokenizeOptions::default();
+        let stop_words = ["the".to_string(), "cat".to_string()];
+        let langs = ["en".to_string()];
+        let stem_exceptions = HashSet::new();
+        let pipeline = Pipeline {
+            tokenize_options: &tokenize_options,
+            stop_words: &stop_words,
+            stem_stopwords: false,
+            langs: &langs,
+            stem_exceptions: &stem_exceptions,
+            pos: PartOfSpeech::Any,
+        };
+
+        let trace = Trace::new("The", &pipeline);
+        assert_eq!(trace.steps, ["tokenized as 'the'", "dropped: stop word"]);
+        assert_eq!(trace.stem, None);
+
+        // "cats" is no stop word until it is stemmed to "cat"
+        let trace = Trace::new("cats", &pipeline);
+        assert_eq!(trace.steps, ["tokenized as 'cats'", "stemmed to 'cat'"]);
+        let pipeline = Pipeline {
+            stem_stopwords: true,
+            ..pipeline
+        };
+        let trace = Trace::new("cats", &pipeline);
+        assert_eq!(
+            trace.steps,
+            [
+                "tokenized as 'cats'",
+                "stemmed to 'cat'",
+                "dropped: stemmed form is a stop word"
+            ]
+        );
+
+        let trace = Trace::new("is", &pipeline);
+        assert_eq!(trace.steps, ["dropped: shorter than --min-length 3"]);
+    }
+}
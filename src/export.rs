@@ -0,0 +1,570 @@
+use crate::schema::ChatType;
+use crate::stats::{self, Funnel, MessageSummary, WordDelta};
+use crate::time;
+use crate::tokenizer::Token;
+use anyhow::Result;
+use encoding_rs::Encoding;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Parse an output encoding label (`utf-8`, `cp1251`, ...). UTF-16 is
+/// refused, `encoding_rs` can only decode it.
+pub fn parse_output_encoding(
+    value: &str,
+) -> std::result::Result<&'static Encoding, String> {
+    let encoding = Encoding::for_label(value.as_bytes())
+        .ok_or_else(|| format!("unknown encoding '{}'", value))?;
+    if encoding.output_encoding() != encoding {
+        return Err(format!("can't write {} output", encoding.name()));
+    }
+    Ok(encoding)
+}
+
+/// Write `stem -> form:count, form:count` lines, most frequent stems first
+pub fn save_stem_map(
+    stem_forms: &HashMap<String, HashMap<String, usize>>,
+    output_path: &Path,
+) -> Result<()> {
+    let output_file = File::create(output_path)?;
+    let mut writer = BufWriter::new(output_file);
+
+    let mut stems: Vec<_> = stem_forms
+        .iter()
+        .map(|(stem, forms)| (stem, forms, forms.values().sum::<usize>()))
+        .collect();
+    stems.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| a.0.cmp(b.0)));
+
+    for (stem, forms, _) in stems {
+        let mut forms: Vec<_> = forms.iter().collect();
+        forms.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+
+        let forms = forms
+            .iter()
+            .map(|(form, count)| format!("{}:{}", form, count))
+            .collect::<Vec<_>>()
+            .join(", ");
+        writeln!(writer, "{} -> {}", stem, forms)?;
+    }
+
+    Ok(())
+}
+
+/// Write `rank frequency word` lines, with the fitted slope as a comment
+pub fn save_zipf_report(
+    ranked: &[(String, usize)],
+    slope: Option<f64>,
+    output_path: &Path,
+) -> Result<()> {
+    let output_file = File::create(output_path)?;
+    let mut writer = BufWriter::new(output_file);
+
+    if let Some(slope) = slope {
+        writeln!(writer, "# slope {:.4}", slope)?;
+    }
+    writeln!(writer, "rank frequency word")?;
+    for (i, (word, count)) in ranked.iter().enumerate() {
+        writeln!(writer, "{} {} {}", i + 1, count, word)?;
+    }
+
+    Ok(())
+}
+
+/// Write the final token stream, one message per line, tokens space-joined
+pub fn save_corpus(tokens: &[Token], output_path: &Path) -> Result<()> {
+    let output_file = File::create(output_path)?;
+    let mut writer = BufWriter::new(output_file);
+
+    for message_tokens in tokens.chunk_by(|a, b| a.message == b.message) {
+        let line = message_tokens
+            .iter()
+            .map(|token| token.word.as_str())
+            .collect::<Vec<_>>()
+            .join(" ");
+        writeln!(writer, "{}", line)?;
+    }
+
+    Ok(())
+}
+
+/// Write one word per line
+pub fn save_word_list(words: &[String], output_path: &Path) -> Result<()> {
+    let output_file = File::create(output_path)?;
+    let mut writer = BufWriter::new(output_file);
+
+    for word in words {
+        writeln!(writer, "{}", word)?;
+    }
+
+    Ok(())
+}
+
+/// Write `label: word:count, ...` lines with each bucket's `top` words,
+/// skipping empty buckets
+pub fn save_bucketed_counts(
+    buckets: &[(String, HashMap<String, usize>)],
+    top: usize,
+    output_path: &Path,
+) -> Result<()> {
+    let output_file = File::create(output_path)?;
+    let mut writer = BufWriter::new(output_file);
+
+    for (label, counts) in buckets {
+        if counts.is_empty() {
+            continue;
+        }
+        let mut words: Vec<_> = counts.iter().collect();
+        words.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+
+        let words = words
+            .iter()
+            .take(top)
+            .map(|(word, count)| format!("{}:{}", word, count))
+            .collect::<Vec<_>>()
+            .join(", ");
+        writeln!(writer, "{}: {}", label, words)?;
+    }
+
+    Ok(())
+}
+
+/// Write the pipeline funnel as a JSON object
+pub fn save_funnel(funnel: &Funnel, output_path: &Path) -> Result<()> {
+    let output_file = File::create(output_path)?;
+    let mut writer = BufWriter::new(output_file);
+    serde_json::to_writer_pretty(&mut writer, funnel)?;
+    writeln!(writer)?;
+    Ok(())
+}
+
+/// A word as handed to wordcloud-rs, `Token::Text(word)` with its weight
+#[derive(Serialize)]
+struct CloudToken<'a> {
+    word: &'a str,
+    weight: f32,
+}
+
+/// Write the words and final weights the cloud is generated from as a
+/// JSON array, in cloud order
+pub fn save_cloud_tokens(
+    words: &[(&str, f32)],
+    output_path: &Path,
+) -> Result<()> {
+    let tokens: Vec<CloudToken> = words
+        .iter()
+        .map(|(word, weight)| CloudToken {
+            word,
+            weight: *weight,
+        })
+        .collect();
+    let output_file = File::create(output_path)?;
+    let mut writer = BufWriter::new(output_file);
+    serde_json::to_writer_pretty(&mut writer, &tokens)?;
+    writeln!(writer)?;
+    Ok(())
+}
+
+/// Write `change delta before after word` lines, biggest changes first
+pub fn save_diff(deltas: &[WordDelta], output_path: &Path) -> Result<()> {
+    let output_file = File::create(output_path)?;
+    let mut writer = BufWriter::new(output_file);
+
+    writeln!(writer, "# change delta before after word")?;
+    for delta in deltas {
+        writeln!(
+            writer,
+            "{} {:+} {} {} {}",
+            delta.change(),
+            delta.delta(),
+            delta.before,
+            delta.after,
+            delta.word
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Write tab separated `from to replies` lines, most replies first
+pub fn save_reply_matrix(
+    counts: &[(String, String, usize)],
+    output_path: &Path,
+) -> Result<()> {
+    let output_file = File::create(output_path)?;
+    let mut writer = BufWriter::new(output_file);
+
+    writeln!(writer, "from\tto\treplies")?;
+    for (from, to, replies) in counts {
+        writeln!(writer, "{}\t{}\t{}", from, to, replies)?;
+    }
+
+    Ok(())
+}
+
+/// Write `char,count` lines. Whitespace and control characters are
+/// written as `U+XXXX` so every line stays readable.
+pub fn save_char_frequencies(
+    counts: &[(char, usize)],
+    output_path: &Path,
+) -> Result<()> {
+    let output_file = File::create(output_path)?;
+    let mut writer = BufWriter::new(output_file);
+
+    writeln!(writer, "char,count")?;
+    for (c, count) in counts {
+        if c.is_whitespace() || c.is_control() {
+            writeln!(writer, "U+{:04X},{}", *c as u32, count)?;
+        } else {
+            writeln!(writer, "{},{}", c, count)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Write all words to a SQLite database as `word_counts(word, count,
+/// weight)` plus a `meta(key, value)` table describing the run. Existing
+/// tables of those names are replaced.
+pub fn save_sqlite(
+    words: &[(&str, usize, f32)],
+    meta: &[(&str, String)],
+    output_path: &Path,
+) -> Result<()> {
+    let mut conn = rusqlite::Connection::open(output_path)?;
+    conn.execute_batch(
+        "DROP TABLE IF EXISTS word_counts;
+         DROP TABLE IF EXISTS meta;
+         CREATE TABLE word_counts (
+             word TEXT PRIMARY KEY,
+             count INTEGER NOT NULL,
+             weight REAL NOT NULL
+         );
+         CREATE TABLE meta (key TEXT PRIMARY KEY, value TEXT NOT NULL);",
+    )?;
+
+    let tx = conn.transaction()?;
+    {
+        let mut insert_word = tx.prepare(
+            "INSERT INTO word_counts (word, count, weight) VALUES (?1, ?2, ?3)",
+        )?;
+        for (word, count, weight) in words {
+            insert_word.execute(rusqlite::params![
+                word,
+                *count as i64,
+                *weight as f64
+            ])?;
+        }
+        let mut insert_meta =
+            tx.prepare("INSERT INTO meta (key, value) VALUES (?1, ?2)")?;
+        for (key, value) in meta {
+            insert_meta.execute(rusqlite::params![key, value])?;
+        }
+    }
+    tx.commit()?;
+
+    Ok(())
+}
+
+/// Write `from-to messages` lines of a message length histogram
+pub fn save_length_histogram(
+    buckets: &[(usize, usize, usize)],
+    output_path: &Path,
+) -> Result<()> {
+    let output_file = File::create(output_path)?;
+    let mut writer = BufWriter::new(output_file);
+
+    for (from, to, messages) in buckets {
+        if from == to {
+            writeln!(writer, "{} {}", from, messages)?;
+        } else {
+            writeln!(writer, "{}-{} {}", from, to, messages)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Everything that goes into the markdown report
+pub struct Report<'a> {
+    pub chat_name: Option<&'a str>,
+    pub chat_type: Option<ChatType>,
+    pub summary: &'a MessageSummary,
+    /// Words with their count, or their weight when `weighted`
+    pub words: &'a [(String, f32)],
+    pub weighted: bool,
+    /// UTC offset for dates, in seconds
+    pub utc_offset: i64,
+    pub command_line: String,
+}
+
+// Rows in the report's top words and emoji tables
+const REPORT_TOP_WORDS: usize = 30;
+const REPORT_TOP_EMOJI: usize = 10;
+
+/// Write a markdown summary of the chat and its most used words
+pub fn save_markdown_report(report: &Report, output_path: &Path) -> Result<()> {
+    let output_file = File::create(output_path)?;
+    let mut writer = BufWriter::new(output_file);
+
+    let title = report.chat_name.unwrap_or("Telegram chat");
+    writeln!(writer, "# Word cloud report: {}", escape_markdown(title))?;
+    writeln!(writer)?;
+
+    if let Some(chat_type) = report.chat_type {
+        writeln!(writer, "- Chat type: {}", chat_type)?;
+    }
+    let range = &report.summary.time_range;
+    if let (Some(first), Some(last), Some(span)) =
+        (range.first, range.last, range.span_days())
+    {
+        writeln!(
+            writer,
+            "- Period: {} to {} ({:.1} days)",
+            time::format_datetime(first, report.utc_offset),
+            time::format_datetime(last, report.utc_offset),
+            span
+        )?;
+    }
+    writeln!(
+        writer,
+        "- Participants: {}",
+        report.summary.participants.len()
+    )?;
+    writeln!(writer, "- Words in the cloud: {}", report.words.len())?;
+    writeln!(writer)?;
+
+    writeln!(writer, "## Top words")?;
+    writeln!(writer)?;
+    if report.weighted {
+        writeln!(writer, "| # | Word | Weight |")?;
+        writeln!(writer, "|---|------|--------|")?;
+    } else {
+        writeln!(writer, "| # | Word | Count |")?;
+        writeln!(writer, "|---|------|-------|")?;
+    }
+    for (i, (word, weight)) in
+        report.words.iter().take(REPORT_TOP_WORDS).enumerate()
+    {
+        writeln!(
+            writer,
+            "| {} | {} | {} |",
+            i + 1,
+            escape_markdown(word),
+            stats::format_weight(*weight)
+        )?;
+    }
+    writeln!(writer)?;
+
+    let emoji = stats::rank_words(&report.summary.emoji);
+    if !emoji.is_empty() {
+        writeln!(writer, "## Top emoji")?;
+        writeln!(writer)?;
+        writeln!(writer, "| Emoji | Count |")?;
+        writeln!(writer, "|-------|-------|")?;
+        for (emoji, count) in emoji.iter().take(REPORT_TOP_EMOJI) {
+            writeln!(writer, "| {} | {} |", emoji, count)?;
+        }
+        writeln!(writer)?;
+    }
+
+    writeln!(writer, "## Parameters")?;
+    writeln!(writer)?;
+    writeln!(writer, "```")?;
+    writeln!(writer, "{}", report.command_line)?;
+    writeln!(writer, "```")?;
+
+    Ok(())
+}
+
+fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '|' | '*' | '_' | '`' | '[' | ']' | '#' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sqlite_word_count_queried_back() {
+        let path = crate::tests::temp_path("counts.db");
+        let _ = std::fs::remove_file(&path);
+        let words = [("rust", 7, 7.0), ("borrow", 3, 2.5)];
+        let meta = [("lang", "en,ru".to_string())];
+        save_sqlite(&words, &meta, &path).unwrap();
+
+        let conn = rusqlite::Connection::open(&path).unwrap();
+        let count: i64 = conn
+            .query_row(
+                "SELECT count FROM word_counts WHERE word = ?1",
+                rusqlite::params!["borrow"],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(count, 3);
+        let weight: f64 = conn
+            .query_row(
+                "SELECT weight FROM word_counts WHERE word = ?1",
+                rusqlite::params!["borrow"],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(weight, 2.5);
+        let lang: String = conn
+            .query_row(
+                "SELECT value FROM meta WHERE key = ?1",
+                rusqlite::params!["lang"],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(lang, "en,ru");
+    }
+
+    #[test]
+    fn cloud_tokens_keep_words_and_weights() {
+        let path = crate::tests::temp_path("tokens.json");
+        // Weights as the cloud gets them, after --weight-clamp 1,100
+        let clamp = crate::render::WeightClamp {
+            min: 1.0,
+            max: 100.0,
+        };
+        let words: Vec<(&str, f32)> =
+            [("rust", 250.0), ("borrow", 2.35), ("crab", 0.1)]
+                .into_iter()
+                .map(|(word, weight)| (word, clamp.apply(weight)))
+                .collect();
+        save_cloud_tokens(&words, &path).unwrap();
+
+        let saved: Vec<serde_json::Value> =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap())
+                .unwrap();
+        let read_back: Vec<(&str, f32)> = saved
+            .iter()
+            .map(|token| {
+                let weight = token["weight"].as_f64().unwrap() as f32;
+                (token["word"].as_str().unwrap(), weight)
+            })
+            .collect();
+        assert_eq!(
+            read_back,
+            [("rust", 100.0), ("borrow", 2.35), ("crab", 1.0)]
+        );
+        assert_eq!(read_back, words);
+    }
+
+    #[test]
+    fn zipf_report_of_a_zipf_distribution() {
+        // Frequency inversely proportional to rank
+        let ranked: Vec<(String, usize)> = ["the", "of", "and", "to", "in", "is"]
+            .iter()
+            .enumerate()
+            .map(|(i, word)| (word.to_string(), 1200 / (i + 1)))
+            .collect();
+        let slope = stats::zipf_slope(&ranked).unwrap();
+        assert!((slope + 1.0).abs() < 1e-9, "slope {}", slope);
+        assert_eq!(stats::zipf_slope(&ranked[..1]), None);
+
+        let path = crate::tests::temp_path("zipf.txt");
+        save_zipf_report(&ranked, Some(slope), &path).unwrap();
+        let report = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "# slope -1.0000",
+                "rank frequency word",
+                "1 1200 the",
+                "2 600 of",
+                "3 400 and",
+                "4 300 to",
+                "5 240 in",
+                "6 200 is"
+            ]
+        );
+    }
+
+    #[test]
+    fn markdown_report_escapes_and_lists_top_words() {
+        let mut summary = MessageSummary::default();
+        summary
+            .participants
+            .extend(["Alice".to_string(), "Bob".to_string()]);
+        let words = [("rust".to_string(), 7.0), ("snake_case".to_string(), 2.5)];
+        let report = |weighted| Report {
+            chat_name: Some("C# | *dev*"),
+            chat_type: Some(ChatType::PrivateGroup),
+            summary: &summary,
+            words: &words,
+            weighted,
+            utc_offset: 0,
+            command_line: "tg-dump-word-cloud -i result.json".to_string(),
+        };
+        let saved = |report: &Report| {
+            let path = crate::tests::temp_path("report.md");
+            save_markdown_report(report, &path).unwrap();
+            let content = std::fs::read_to_string(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            content
+        };
+
+        let counted = saved(&report(false));
+        assert_eq!(
+            counted,
+            r"# Word cloud report: C\# \| \*dev\*
+
+- Chat type: private group
+- Participants: 2
+- Words in the cloud: 2
+
+## Top words
+
+| # | Word | Count |
+|---|------|-------|
+| 1 | rust | 7 |
+| 2 | snake\_case | 2.50 |
+
+## Parameters
+
+```
+tg-dump-word-cloud -i result.json
+```
+"
+        );
+
+        // Only the column header changes with weights
+        let weighted = saved(&report(true));
+        assert_eq!(
+            weighted,
+            counted.replace(
+                "| Count |\n|---|------|-------|",
+                "| Weight |\n|---|------|--------|"
+            )
+        );
+        assert_ne!(weighted, counted);
+
+        // The table stops at the top 30 words
+        let words: Vec<(String, f32)> = (1..=40)
+            .map(|i| (format!("word{}", i), (41 - i) as f32))
+            .collect();
+        let report = Report {
+            words: &words,
+            ..report(false)
+        };
+        let content = saved(&report);
+        let rows: Vec<&str> = content
+            .lines()
+            .filter(|line| line.contains("| word"))
+            .collect();
+        assert_eq!(rows.len(), REPORT_TOP_WORDS);
+        assert_eq!(rows[29], "| 30 | word30 | 11 |");
+        assert!(content.contains("- Words in the cloud: 40"));
+    }
+}
@@ -0,0 +1,389 @@
+use crate::parse::{Message, SimpleMessage};
+use crate::time;
+use anyhow::{Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::time::Duration;
+
+/// Date window for messages, all bounds inclusive and in `YYYY-MM-DD` form
+#[derive(Debug, Default)]
+pub struct DateFilter {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub exclude_from: Option<String>,
+    pub exclude_to: Option<String>,
+    /// UTC offset of the days read from `date_unixtime`, --tz
+    pub utc_offset: i64,
+}
+
+impl DateFilter {
+    pub fn is_active(&self) -> bool {
+        self.from.is_some()
+            || self.to.is_some()
+            || self.exclude_from.is_some()
+            || self.exclude_to.is_some()
+    }
+
+    /// Check the day of a message against the window. Messages without a
+    /// readable date are kept.
+    pub fn keeps(&self, msg: &Message) -> bool {
+        match message_day(msg, self.utc_offset) {
+            Some(day) => self.keeps_day(&day),
+            None => true,
+        }
+    }
+
+    fn keeps_day(&self, day: &str) -> bool {
+        if self.from.as_deref().is_some_and(|from| day < from)
+            || self.to.as_deref().is_some_and(|to| day > to)
+        {
+            return false;
+        }
+
+        // Blackout interval, open-ended if only one bound is set
+        if self.exclude_from.is_some() || self.exclude_to.is_some() {
+            let after_start =
+                self.exclude_from.as_deref().is_none_or(|from| day >= from);
+            let before_end =
+                self.exclude_to.as_deref().is_none_or(|to| day <= to);
+            if after_start && before_end {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+pub fn filter_by_date(messages: &mut Vec<Message>, date_filter: &DateFilter) {
+    messages.retain(|msg| date_filter.keeps(msg));
+}
+
+/// Fail on a message whose `date` or `date_unixtime` can't be read, or
+/// that has neither, which the date window would otherwise keep silently
+pub fn check_message_date(msg: &Message) -> Result<()> {
+    if !msg.date.is_empty() && date_day(&msg.date).is_none() {
+        anyhow::bail!("{} has an unreadable date '{}'", msg.label(), msg.date);
+    }
+    if !msg.date_unixtime.is_empty() && msg.date_unixtime.parse::<i64>().is_err()
+    {
+        anyhow::bail!(
+            "{} has an unreadable date_unixtime '{}'",
+            msg.label(),
+            msg.date_unixtime
+        );
+    }
+    if msg.date.is_empty() && msg.date_unixtime.is_empty() {
+        anyhow::bail!("{} has no date", msg.label());
+    }
+    Ok(())
+}
+
+pub fn filter_by_ids(messages: &mut Vec<Message>, excluded: &HashSet<i64>) {
+    messages.retain(|msg| !msg.id.is_some_and(|id| excluded.contains(&id)));
+}
+
+/// Read a list with one entry per line. Blank lines and `#` comments are
+/// skipped.
+pub fn read_word_list(path: &Path) -> Result<Vec<String>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    Ok(content
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// Read message ids, one per line. Blank lines and `#` comments are
+/// skipped.
+pub fn read_id_file(path: &Path) -> Result<Vec<i64>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read id file {}", path.display()))?;
+
+    let mut ids = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let id = line.parse().with_context(|| {
+            format!(
+                "Invalid message id '{}' on line {} of {}",
+                line,
+                number + 1,
+                path.display()
+            )
+        })?;
+        ids.push(id);
+    }
+    Ok(ids)
+}
+
+// How long to wait for a remote word list
+const FETCH_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Download a newline separated word list. Blank lines and `#` comments
+/// are skipped.
+pub fn fetch_word_list(url: &str) -> Result<Vec<String>> {
+    let body = ureq::get(url)
+        .timeout(FETCH_TIMEOUT)
+        .call()
+        .with_context(|| format!("Failed to fetch {}", url))?
+        .into_string()
+        .with_context(|| format!("Failed to read response from {}", url))?;
+
+    Ok(body
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|word| !word.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// Keep messages from the named forum topic, compared case-insensitively
+pub fn filter_by_topic(messages: &mut Vec<Message>, topic: &str) {
+    let topic = topic.to_lowercase();
+    messages.retain(|msg| {
+        msg.topic
+            .as_ref()
+            .is_some_and(|name| name.to_lowercase() == topic)
+    });
+}
+
+/// Validate a `YYYY-MM-DD` command line date
+pub fn parse_date_arg(value: &str) -> Result<String, String> {
+    let parts: Vec<&str> = value.split('-').collect();
+    let valid = parts.len() == 3
+        && [4, 2, 2].iter().zip(&parts).all(|(len, part)| {
+            part.len() == *len && part.chars().all(|c| c.is_ascii_digit())
+        });
+
+    if valid {
+        Ok(value.to_string())
+    } else {
+        Err(format!("expected date as YYYY-MM-DD, got '{}'", value))
+    }
+}
+
+/// `YYYY-MM-DD` day of a message: from `date`, which is in the exporter's
+/// time zone, else from `date_unixtime` shifted by `utc_offset`
+fn message_day(msg: &Message, utc_offset: i64) -> Option<String> {
+    if let Some(day) = date_day(&msg.date) {
+        return Some(day.to_string());
+    }
+    let timestamp = msg.date_unixtime.parse().ok()?;
+    Some(time::format_date(timestamp, utc_offset))
+}
+
+fn date_day(date: &str) -> Option<&str> {
+    date.get(..10).filter(|day| parse_date_arg(day).is_ok())
+}
+
+/// Lowercase a `--contains` search term, rejecting empty ones
+pub fn parse_search_term(value: &str) -> Result<String, String> {
+    let term = value.trim().to_lowercase();
+    if term.is_empty() {
+        return Err("search term must not be empty".to_string());
+    }
+    Ok(term)
+}
+
+/// Whether `text` contains any of the lowercased `terms`, ignoring case
+pub fn contains_any_term(text: &str, terms: &[String]) -> bool {
+    let text = text.to_lowercase();
+    terms.iter().any(|term| text.contains(term.as_str()))
+}
+
+pub fn filter_by_terms(
+    messages: Vec<SimpleMessage>,
+    terms: &[String],
+) -> Vec<SimpleMessage> {
+    messages
+        .into_iter()
+        .filter(|message| contains_any_term(&message.text, terms))
+        .collect()
+}
+
+/// Collapse runs of identical messages from the same user into one.
+/// Text is compared case-insensitively with whitespace collapsed.
+pub fn dedup_consecutive(mut messages: Vec<SimpleMessage>) -> Vec<SimpleMessage> {
+    messages.dedup_by(|next, prev| {
+        next.username == prev.username
+            && normalize_text(&next.text) == normalize_text(&prev.text)
+    });
+    messages
+}
+
+/// Validate a `--despam-similarity` value between 0 and 1
+pub fn parse_similarity(value: &str) -> Result<f64, String> {
+    let similarity: f64 = value
+        .parse()
+        .map_err(|_| format!("invalid similarity '{}'", value))?;
+    if !(0.0..=1.0).contains(&similarity) {
+        return Err(format!(
+            "similarity must be between 0 and 1, got '{}'",
+            value
+        ));
+    }
+    Ok(similarity)
+}
+
+/// Collapse floods: a message is dropped when the same user posted a
+/// message at least `similarity` alike (Jaccard index of the word sets)
+/// within `window_secs` before it. Each dropped message extends the burst,
+/// so a long flood collapses into its first message. Messages without a
+/// timestamp are kept.
+pub fn despam(
+    messages: Vec<SimpleMessage>,
+    window_secs: i64,
+    similarity: f64,
+) -> Vec<SimpleMessage> {
+    // Per user: words of the message that opened the burst, and the time of
+    // the burst's latest message
+    let mut bursts: HashMap<String, (HashSet<String>, i64)> = HashMap::new();
+    messages
+        .into_iter()
+        .filter(|msg| {
+            let Some(timestamp) = msg.timestamp else {
+                return true;
+            };
+            let words = word_set(&msg.text);
+            if let Some((first, last)) = bursts.get_mut(&msg.username)
+                && timestamp - *last <= window_secs
+                && jaccard(first, &words) >= similarity
+            {
+                *last = timestamp;
+                return false;
+            }
+            bursts.insert(msg.username.clone(), (words, timestamp));
+            true
+        })
+        .collect()
+}
+
+fn word_set(text: &str) -> HashSet<String> {
+    text.split_whitespace().map(str::to_lowercase).collect()
+}
+
+fn jaccard(a: &HashSet<String>, b: &HashSet<String>) -> f64 {
+    let union = a.union(b).count();
+    if union == 0 {
+        return 1.0;
+    }
+    a.intersection(b).count() as f64 / union as f64
+}
+
+fn normalize_text(text: &str) -> String {
+    text.split_whitespace()
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A message with `date` set to `date`
+    fn dated(date: &str) -> Message {
+        serde_json::from_value(serde_json::json!({"id": 1, "date": date}))
+            .unwrap()
+    }
+
+    #[test]
+    fn blackout_drops_days_inside_bounds_inclusive() {
+        let filter = DateFilter {
+            exclude_from: Some("2024-03-10".to_string()),
+            exclude_to: Some("2024-03-12".to_string()),
+            ..Default::default()
+        };
+        assert!(filter.keeps(&dated("2024-03-09T23:59:59")));
+        assert!(!filter.keeps(&dated("2024-03-10T00:00:00")));
+        assert!(!filter.keeps(&dated("2024-03-11T12:00:00")));
+        assert!(!filter.keeps(&dated("2024-03-12T23:59:59")));
+        assert!(filter.keeps(&dated("2024-03-13T00:00:00")));
+        assert!(filter.keeps(&dated("not a date")));
+
+        // One bound leaves the blackout open-ended
+        let from_only = DateFilter {
+            exclude_from: Some("2024-03-10".to_string()),
+            ..Default::default()
+        };
+        assert!(from_only.keeps(&dated("2024-03-09T12:00:00")));
+        assert!(!from_only.keeps(&dated("2030-01-01T12:00:00")));
+    }
+
+    #[test]
+    fn unixtime_only_message_dated_in_tz() {
+        // 2024-03-09 22:30 UTC, already the 10th at +03:00
+        let message: Message = serde_json::from_value(
+            serde_json::json!({"id": 1, "date_unixtime": "1710023400"}),
+        )
+        .unwrap();
+        let window = |utc_offset| DateFilter {
+            from: Some("2024-03-10".to_string()),
+            utc_offset,
+            ..Default::default()
+        };
+        assert!(!window(0).keeps(&message));
+        assert!(window(3 * 3600).keeps(&message));
+        assert!(check_message_date(&message).is_ok());
+
+        // A readable `date` wins, it is already in local time
+        let mut dated = message;
+        dated.date = "2024-03-10T01:30:00".to_string();
+        assert!(window(0).keeps(&dated));
+
+        let undated: Message =
+            serde_json::from_value(serde_json::json!({"id": 2})).unwrap();
+        assert!(window(0).keeps(&undated));
+        assert_eq!(
+            check_message_date(&undated).unwrap_err().to_string(),
+            "message 2 has no date"
+        );
+    }
+
+    /// Serve one HTTP response per request on a local port, returning the
+    /// server's base URL
+    fn serve(responses: Vec<String>) -> String {
+        use std::io::{BufRead, BufReader, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for (stream, response) in listener.incoming().zip(responses) {
+                let mut stream = stream.unwrap();
+                // Read the request head before answering
+                let mut reader = BufReader::new(&stream);
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn word_list_fetched_over_http() {
+        let body = "# shared stop words\nfoo\n\nbar # inline comment\n";
+        let ok = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        let url = serve(vec![
+            ok,
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                .to_string(),
+        ]);
+
+        let words = fetch_word_list(&format!("{}/stop.txt", url)).unwrap();
+        assert_eq!(words, ["foo", "bar"]);
+
+        let error = fetch_word_list(&format!("{}/missing.txt", url)).unwrap_err();
+        assert!(error.to_string().starts_with("Failed to fetch http://"));
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::HashMap;
+
+/// Only this many of the most frequent words are compared, the comparison
+/// is quadratic
+const MERGE_CANDIDATES: usize = 2000;
+
+/// Fold near-duplicate words (typos) into the most frequent word within
+/// `max_distance` edits. Words need more than `2 * max_distance` characters
+/// to be merged, so short distinct words like "cat"/"car" stay apart.
+/// Also returns the word each folded word went into.
+pub fn merge_similar(
+    word_counts: HashMap<String, usize>,
+    max_distance: usize,
+) -> (HashMap<String, usize>, HashMap<String, String>) {
+    let mut ranked: Vec<(String, usize)> = word_counts.into_iter().collect();
+    ranked.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    let mut representatives: Vec<(Vec<char>, String, usize)> = Vec::new();
+    let mut merged = HashMap::new();
+    let mut merged_into = HashMap::new();
+
+    for (i, (word, count)) in ranked.into_iter().enumerate() {
+        let chars: Vec<char> = word.chars().collect();
+        let mergeable = i < MERGE_CANDIDATES && chars.len() > 2 * max_distance;
+
+        let target = if mergeable {
+            representatives.iter_mut().find(|(rep_chars, _, _)| {
+                levenshtein_within(rep_chars, &chars, max_distance)
+            })
+        } else {
+            None
+        };
+
+        match target {
+            Some((_, rep_word, rep_count)) => {
+                *rep_count += count;
+                merged_into.insert(word, rep_word.clone());
+            }
+            None if mergeable => representatives.push((chars, word, count)),
+            None => {
+                merged.insert(word, count);
+            }
+        }
+    }
+
+    merged.extend(
+        representatives
+            .into_iter()
+            .map(|(_, word, count)| (word, count)),
+    );
+    (merged, merged_into)
+}
+
+/// Add up the weights of words folded together by `merge_similar`
+pub fn merge_weights(
+    weights: HashMap<String, f64>,
+    merged_into: &HashMap<String, String>,
+) -> HashMap<String, f64> {
+    let mut merged = HashMap::with_capacity(weights.len());
+    for (word, weight) in weights {
+        let word = merged_into.get(&word).cloned().unwrap_or(word);
+        *merged.entry(word).or_insert(0.0) += weight;
+    }
+    merged
+}
+
+/// Whether the edit distance between `a` and `b` is at most `max_distance`
+fn levenshtein_within(a: &[char], b: &[char], max_distance: usize) -> bool {
+    if a.len().abs_diff(b.len()) > max_distance {
+        return false;
+    }
+
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+
+    for (i, ca) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current[j + 1] =
+                substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        // Every path through this row already costs too much
+        if current.iter().min().is_some_and(|&min| min > max_distance) {
+            return false;
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+
+    previous[b.len()] <= max_distance
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn misspelling_folded_into_frequent_spelling() {
+        let word_counts: HashMap<String, usize> = [
+            ("привет", 5),
+            ("привт", 1),
+            ("прuвет", 1),
+            ("compiler", 3),
+            ("compilre", 1),
+            ("go", 4),
+            ("so", 2),
+        ]
+        .into_iter()
+        .map(|(word, count)| (word.to_string(), count))
+        .collect();
+
+        let (merged, merged_into) = merge_similar(word_counts.clone(), 1);
+        assert_eq!(merged["привет"], 7);
+        assert!(!merged.contains_key("привт"));
+        assert_eq!(merged_into["прuвет"], "привет");
+        // A swap of two letters is two edits
+        assert_eq!(merged["compiler"], 3);
+        assert_eq!(merged["compilre"], 1);
+        // Too short to merge
+        assert_eq!((merged["go"], merged["so"]), (4, 2));
+
+        let (merged, _) = merge_similar(word_counts, 2);
+        assert_eq!(merged["compiler"], 4);
+
+        let weights = [("привет", 2.0), ("привт", 0.5), ("go", 1.0)]
+            .into_iter()
+            .map(|(word, weight)| (word.to_string(), weight))
+            .collect();
+        let weights = merge_weights(weights, &merged_into);
+        assert_eq!(weights["привет"], 2.5);
+        assert_eq!(weights.len(), 2);
+    }
+}
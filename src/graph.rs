@@ -0,0 +1,232 @@
+use crate::tokenizer::Token;
+use anyhow::Result;
+use clap::ValueEnum;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum GraphFormat {
+    Dot,
+    Graphml,
+}
+
+impl GraphFormat {
+    /// GraphML for `.graphml` files, DOT otherwise
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("graphml") => Self::Graphml,
+            _ => Self::Dot,
+        }
+    }
+}
+
+/// Count, for each pair of distinct words, the messages they share.
+/// Pairs are keyed with the alphabetically smaller word first.
+pub fn count_cooccurrences(tokens: &[Token]) -> HashMap<(String, String), usize> {
+    let mut pairs = HashMap::new();
+
+    for message_tokens in tokens.chunk_by(|a, b| a.message == b.message) {
+        let words: BTreeSet<&str> = message_tokens
+            .iter()
+            .map(|token| token.word.as_str())
+            .collect();
+        let words: Vec<&str> = words.into_iter().collect();
+
+        for (i, first) in words.iter().enumerate() {
+            for second in &words[i + 1..] {
+                *pairs
+                    .entry((first.to_string(), second.to_string()))
+                    .or_insert(0) += 1;
+            }
+        }
+    }
+
+    pairs
+}
+
+/// Write edges with at least `min_weight` shared messages
+pub fn save_graph(
+    pairs: &HashMap<(String, String), usize>,
+    min_weight: usize,
+    format: GraphFormat,
+    output_path: &Path,
+) -> Result<()> {
+    let mut edges: Vec<_> = pairs
+        .iter()
+        .filter(|(_, weight)| **weight >= min_weight)
+        .collect();
+    edges.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+
+    let nodes: BTreeSet<&str> = edges
+        .iter()
+        .flat_map(|((a, b), _)| [a.as_str(), b.as_str()])
+        .collect();
+
+    let output_file = File::create(output_path)?;
+    let mut writer = BufWriter::new(output_file);
+
+    match format {
+        GraphFormat::Dot => {
+            writeln!(writer, "graph cooccurrence {{")?;
+            for node in &nodes {
+                writeln!(writer, "  \"{}\";", escape_dot(node))?;
+            }
+            for ((a, b), weight) in &edges {
+                writeln!(
+                    writer,
+                    "  \"{}\" -- \"{}\" [weight={}];",
+                    escape_dot(a),
+                    escape_dot(b),
+                    weight
+                )?;
+            }
+            writeln!(writer, "}}")?;
+        }
+        GraphFormat::Graphml => {
+            writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+            writeln!(
+                writer,
+                r#"<graphml xmlns="http://graphml.graphdrawing.org/xmlns">"#
+            )?;
+            writeln!(
+                writer,
+                r#"  <key id="weight" for="edge" attr.name="weight" attr.type="int"/>"#
+            )?;
+            writeln!(
+                writer,
+                r#"  <graph id="cooccurrence" edgedefault="undirected">"#
+            )?;
+            for node in &nodes {
+                writeln!(writer, r#"    <node id="{}"/>"#, escape_xml(node))?;
+            }
+            for ((a, b), weight) in &edges {
+                writeln!(
+                    writer,
+                    r#"    <edge source="{}" target="{}"><data key="weight">{}</data></edge>"#,
+                    escape_xml(a),
+                    escape_xml(b),
+                    weight
+                )?;
+            }
+            writeln!(writer, "  </graph>")?;
+            writeln!(writer, "</graphml>")?;
+        }
+    }
+
+    Ok(())
+}
+
+fn escape_dot(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+pub fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::temp_path;
+
+    /// Tokens of messages given as space-separated words
+    fn tokens(messages: &[&str]) -> Vec<Token> {
+        messages
+            .iter()
+            .enumerate()
+            .flat_map(|(message, text)| {
+                text.split(' ').map(move |word| Token {
+                    word: word.to_string(),
+                    original: word.to_string(),
+                    message,
+                    emphasized: false,
+                })
+            })
+            .collect()
+    }
+
+    fn saved(
+        pairs: &HashMap<(String, String), usize>,
+        min_weight: usize,
+        format: GraphFormat,
+    ) -> String {
+        let path = temp_path("graph");
+        save_graph(pairs, min_weight, format, &path).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        content
+    }
+
+    /// Whether every tag is closed in order and no `<` or bare `&` is
+    /// left inside a tag
+    fn well_formed(xml: &str) -> bool {
+        let mut open = Vec::new();
+        let mut rest =
+            xml.trim_start_matches(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+        while let Some(start) = rest.find('<') {
+            let Some(end) = rest[start..].find('>') else {
+                return false;
+            };
+            let tag = &rest[start + 1..start + end];
+            rest = &rest[start + end + 1..];
+            let unescaped_amp = tag.match_indices('&').any(|(i, _)| {
+                !["&amp;", "&lt;", "&gt;", "&quot;", "&apos;"]
+                    .iter()
+                    .any(|entity| tag[i..].starts_with(entity))
+            });
+            if tag.contains('<') || unescaped_amp {
+                return false;
+            }
+            if let Some(name) = tag.strip_prefix('/') {
+                if open.pop() != Some(name) {
+                    return false;
+                }
+            } else if !tag.ends_with('/') {
+                open.push(tag.split(' ').next().unwrap());
+            }
+        }
+        open.is_empty()
+    }
+
+    #[test]
+    fn words_always_together_get_the_strongest_edge() {
+        let pairs =
+            count_cooccurrences(&tokens(&["rust cargo r&d rust", "cargo rust"]));
+        assert_eq!(pairs.len(), 3);
+        assert_eq!(pairs[&("cargo".to_string(), "rust".to_string())], 2);
+        assert_eq!(pairs[&("cargo".to_string(), "r&d".to_string())], 1);
+        assert_eq!(pairs[&("r&d".to_string(), "rust".to_string())], 1);
+
+        let dot = saved(&pairs, 1, GraphFormat::Dot);
+        let edges: Vec<&str> =
+            dot.lines().filter(|line| line.contains("--")).collect();
+        assert_eq!(
+            edges,
+            [
+                r#"  "cargo" -- "rust" [weight=2];"#,
+                r#"  "cargo" -- "r&d" [weight=1];"#,
+                r#"  "r&d" -- "rust" [weight=1];"#,
+            ]
+        );
+        let strong = saved(&pairs, 2, GraphFormat::Dot);
+        assert_eq!(
+            strong,
+            "graph cooccurrence {\n  \"cargo\";\n  \"rust\";\n  \"cargo\" -- \"rust\" [weight=2];\n}\n"
+        );
+
+        let graphml = saved(&pairs, 1, GraphFormat::Graphml);
+        assert!(well_formed(&graphml), "{}", graphml);
+        assert!(graphml.contains(r#"<node id="r&amp;d"/>"#));
+        assert!(graphml.contains(
+            r#"<edge source="cargo" target="rust"><data key="weight">2</data></edge>"#
+        ));
+        assert!(!well_formed("<graph><node id=\"r&d\"/></graph>"));
+        assert!(!well_formed("<graph><node></graph>"));
+    }
+}
@@ -0,0 +1,43 @@
+use anyhow::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// First Ctrl-C asks long loops to stop so partial output can still be
+/// written; a second one exits right away
+pub fn install_handler() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if interrupt() {
+            std::process::exit(130);
+        }
+        eprintln!("Interrupted, finishing with the data processed so far");
+    })?;
+    Ok(())
+}
+
+/// Ask long loops to stop, returns whether they already were
+pub fn interrupt() -> bool {
+    with_flag(|flag| flag.swap(true, Ordering::SeqCst))
+}
+
+pub fn is_interrupted() -> bool {
+    with_flag(|flag| flag.load(Ordering::Relaxed))
+}
+
+#[cfg(test)]
+pub fn reset() {
+    with_flag(|flag| flag.store(false, Ordering::SeqCst));
+}
+
+#[cfg(not(test))]
+fn with_flag<R>(f: impl FnOnce(&AtomicBool) -> R) -> R {
+    static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+    f(&INTERRUPTED)
+}
+
+/// Per thread in tests, so a simulated Ctrl-C only stops the test sending it
+#[cfg(test)]
+fn with_flag<R>(f: impl FnOnce(&AtomicBool) -> R) -> R {
+    thread_local! {
+        static INTERRUPTED: AtomicBool = const { AtomicBool::new(false) };
+    }
+    INTERRUPTED.with(f)
+}
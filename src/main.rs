@@ -1,11 +1,30 @@
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{Context, Result};
+use clap::{CommandFactory, Parser};
+use std::borrow::Cow;
+use std::cell::OnceCell;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::{BufWriter, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use wordcloud_rs::*;
 
+mod ascii;
+mod colors;
+mod explain;
+mod export;
+mod filter;
+mod fuzzy;
+mod graph;
+mod interrupt;
 mod parse;
+mod pos;
+mod rank;
+mod render;
+mod schema;
+mod stats;
+mod stream;
+mod svg;
+mod time;
 mod tokenizer;
 
 #[derive(Parser, Debug)]
@@ -17,121 +36,3307 @@ mod tokenizer;
 )]
 struct Args {
     /// Input file containing Telegram chat dump in JSON format
-    #[arg(short, long, required = true)]
-    input: PathBuf,
+    /// (repeat to combine several exports)
+    #[arg(
+        short,
+        long,
+        required_unless_present_any = ["input_dir", "generate_completions"]
+    )]
+    input: Vec<PathBuf>,
 
-    /// Output file for the word cloud image (PNG)
-    #[arg(short, long, default_value = "wordcloud.png")]
-    output: PathBuf,
+    /// Directory of a split export (messages.json, messages2.json, ...),
+    /// read after any --input files
+    #[arg(long)]
+    input_dir: Option<PathBuf>,
+
+    /// Print shell completions to stdout and exit
+    #[arg(long, value_enum, hide = true)]
+    generate_completions: Option<clap_complete::Shell>,
+
+    /// Output file for the word cloud image (PNG). Defaults to the chat
+    /// name from the export header, or wordcloud.png
+    #[arg(short, long)]
+    output: Option<PathBuf>,
 
-    /// Minimum word length to include
-    #[arg(short, long, default_value_t = 3)]
+    /// Minimum word length (in characters) to include
+    #[arg(
+        short,
+        long,
+        default_value_t = 3,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
     min_length: usize,
 
-    /// Maximum number of words to include in the cloud
+    /// Maximum word length (in characters) to include
+    #[arg(long)]
+    max_length: Option<usize>,
+
+    /// Maximum number of words to include in the cloud, 0 for no limit
     #[arg(long, default_value_t = 100)]
     max_words: usize,
 
-    /// Language code for stemming (en, ru, etc.)
-    #[arg(long, default_value = "en")]
-    lang: String,
+    /// Language codes for stemming (en, ru, etc.), comma separated for
+    /// mixed chats; each word is stemmed by the language of its script
+    #[arg(long, value_delimiter = ',', default_value = "en")]
+    lang: Vec<String>,
+
+    /// Also filter the built-in stop words of every --lang language, on top
+    /// of the Russian list that is always applied
+    #[arg(long)]
+    merge_stopword_lists: bool,
 
     /// List of users to include (default: all)
     #[arg(short, long)]
     users: Option<Vec<String>>,
 
     /// Skip messages before this date (format: YYYY-MM-DD)
-    #[arg(long)]
+    #[arg(long, value_parser = filter::parse_date_arg)]
     from_date: Option<String>,
 
     /// Skip messages after this date (format: YYYY-MM-DD)
-    #[arg(long)]
+    #[arg(long, value_parser = filter::parse_date_arg)]
     to_date: Option<String>,
 
-    /// List of stop words to exclude
+    /// Start of a date range to drop (format: YYYY-MM-DD)
+    #[arg(long, value_parser = filter::parse_date_arg)]
+    exclude_from: Option<String>,
+
+    /// End of a date range to drop (format: YYYY-MM-DD)
+    #[arg(long, value_parser = filter::parse_date_arg)]
+    exclude_to: Option<String>,
+
+    /// Fail on messages with unreadable dates instead of keeping them,
+    /// when a date window is set
     #[arg(long)]
+    strict_dates: bool,
+
+    /// Extra stop words to exclude, on top of the built-in list
+    #[arg(long, value_delimiter = ',')]
     stop_words: Option<Vec<String>>,
+
+    /// Fetch extra stop words from this URL, one per line (repeatable)
+    #[arg(long)]
+    stop_words_url: Vec<String>,
+
+    /// Never access the network; --stop-words-url is skipped
+    #[arg(long)]
+    offline: bool,
+
+    /// Unicode normalization applied to text before tokenizing
+    #[arg(long, value_enum, default_value_t = tokenizer::UnicodeForm::Nfc)]
+    unicode_normalize: tokenizer::UnicodeForm,
+
+    /// Keep the original case of words, counting "Rust" and "rust"
+    /// separately
+    #[arg(long)]
+    no_lowercase: bool,
+
+    /// Locale for lowercasing, e.g. tr for the Turkish dotted and dotless I
+    #[arg(long, default_value = "und", value_parser = tokenizer::parse_locale)]
+    locale: tokenizer::CaseLocale,
+
+    /// Count emoji as words, using their names (🔥 becomes "fire")
+    #[arg(long)]
+    emoji_as_words: bool,
+
+    /// Count runs of punctuation ("!!!", "...") as tokens of their own
+    #[arg(long)]
+    include_punctuation: bool,
+
+    /// Only count words made of letters, dropping any with digits,
+    /// hyphens or underscores
+    #[arg(long, conflicts_with = "include_punctuation")]
+    letters_only: bool,
+
+    /// Count each http(s) link as a single token, so often shared links
+    /// show up whole
+    #[arg(long)]
+    dedupe_urls_as_single_token: bool,
+
+    /// File of multi-word terms, one per line, counted as single words
+    /// (e.g. "machine learning")
+    #[arg(long)]
+    keep_phrases_file: Option<PathBuf>,
+
+    /// Split camelCase and snake_case identifiers into their words
+    /// ("readMessages" -> "read", "messages")
+    #[arg(long)]
+    split_identifiers: bool,
+
+    /// Split words on inner hyphens, underscores and apostrophes or remove
+    /// them
+    #[arg(long, value_enum)]
+    strip_punctuation_inside_words: Option<tokenizer::InnerPunctuation>,
+
+    /// Keep only words of this part of speech (suffix-based guess)
+    #[arg(long, value_enum, default_value_t = pos::PartOfSpeech::Any)]
+    pos: pos::PartOfSpeech,
+
+    /// How many times in a row the parser may resync to the next message
+    /// after a malformed one before skipping the whole object
+    #[arg(long, default_value_t = 16)]
+    parse_recovery_depth: usize,
+
+    /// File of words to leave unstemmed, one per line (brand names,
+    /// technical terms)
+    #[arg(long)]
+    stem_exceptions_file: Option<PathBuf>,
+
+    /// Stem the stop-word list and filter after stemming, so inflected
+    /// stop words are caught too
+    #[arg(long)]
+    stem_stopwords: bool,
+
+    /// Skip messages with fewer words than this
+    #[arg(long, default_value_t = 0)]
+    min_words_per_message: usize,
+
+    /// Collapse consecutive identical messages from the same user
+    #[arg(long)]
+    dedup_messages: bool,
+
+    /// Collapse floods: similar messages one user posts in quick
+    /// succession count once
+    #[arg(long)]
+    despam: bool,
+
+    /// Seconds between messages of a flood, with --despam
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 60,
+        requires = "despam"
+    )]
+    despam_window: u32,
+
+    /// How alike flood messages are (shared words, 0 to 1), with --despam
+    #[arg(
+        long,
+        default_value_t = 0.8,
+        value_parser = filter::parse_similarity,
+        requires = "despam"
+    )]
+    despam_similarity: f64,
+
+    /// Where message text is read from
+    #[arg(long, value_enum, default_value_t = parse::TextSource::Text)]
+    text_source: parse::TextSource,
+
+    /// Worker threads for reading several input files, 0 for one per CPU
+    #[arg(long, default_value_t = 0)]
+    threads: usize,
+
+    /// Write each malformed message's error and byte offset to this file
+    /// instead of printing them
+    #[arg(long)]
+    parse_log: Option<PathBuf>,
+
+    /// Input file encoding: auto, utf-8, cp1251, koi8-r, ...
+    #[arg(long, default_value = "auto", value_parser = parse::parse_encoding_arg)]
+    encoding: parse::InputEncoding,
+
+    /// Encoding of the word counts file for Python: utf-8, cp1251, ...
+    #[arg(long, default_value = "utf-8", value_parser = export::parse_output_encoding)]
+    dump_encoding: &'static encoding_rs::Encoding,
+
+    /// Start the UTF-8 word counts file with a byte order mark
+    #[arg(long)]
+    dump_bom: bool,
+
+    /// Count words while parsing instead of loading the whole export first,
+    /// to bound memory on huge exports. Broken JSON ends a file instead of
+    /// being skipped over.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "dedup_messages",
+            "despam",
+            "stem_map_out",
+            "warn_stem_collisions",
+            "corpus_out",
+            "graph_out",
+            "tfidf_by_user",
+            "by_hour",
+            "by_year",
+            "reaction_stats",
+            "reply_matrix",
+            "length_histogram",
+            "char_freq_out",
+            "min_df",
+            "min_senders",
+            "recency_halflife",
+            "weight_emphasized",
+            "topic",
+            "per_topic",
+            "caption_by_media_type",
+        ]
+    )]
+    streaming: bool,
+
+    /// Fail on unrecognized export formats and malformed messages instead
+    /// of parsing best-effort
+    #[arg(long)]
+    strict: bool,
+
+    /// Leave quoted text (blockquote entities) out of the counts
+    #[arg(long)]
+    strip_quotes: bool,
+
+    /// Leave bare URLs (link entities) out of the counts
+    #[arg(long)]
+    exclude_links_entities: bool,
+
+    /// With --exclude-links-entities, also leave out the display text of
+    /// text_link entities
+    #[arg(long, requires = "exclude_links_entities")]
+    drop_link_text: bool,
+
+    /// Turn tabs, newlines, non-breaking and zero-width spaces into plain
+    /// single spaces before tokenizing
+    #[arg(long)]
+    normalize_whitespace: bool,
+
+    /// Leave sticker pack emoji (custom_emoji entities) and sticker emoji
+    /// out of the text
+    #[arg(long)]
+    exclude_stickers_emoji_in_text: bool,
+
+    /// Add the names of chats and users that messages were forwarded from
+    /// to the stop words
+    #[arg(long)]
+    exclude_forward_source_names: bool,
+
+    /// Include poll questions and answer options in the word counts
+    #[arg(long)]
+    include_polls: bool,
+
+    /// Name used for messages without a sender
+    #[arg(long, default_value = "anonymous")]
+    unknown_user_label: String,
+
+    /// Drop messages without a sender
+    #[arg(long)]
+    exclude_unknown_users: bool,
+
+    /// Treat the export as a saved-messages chat: every message counts as
+    /// the account owner's, including saved forwards
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "users",
+            "exclude_unknown_users",
+            "min_senders",
+            "tfidf_by_user",
+            "reply_matrix",
+        ]
+    )]
+    self_chat: bool,
+
+    /// Write which original word forms were merged into each stem
+    #[arg(long)]
+    stem_map_out: Option<PathBuf>,
+
+    /// Warn about stems merged from N or more distinct word forms, a sign
+    /// that stemming is too aggressive for the chat
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(2..))]
+    warn_stem_collisions: Option<u64>,
+
+    /// How to color words (written to the Python data file)
+    #[arg(long, value_enum, default_value_t = colors::ColorBy::Random)]
+    color_by: colors::ColorBy,
+
+    /// Take word colors from this file of hex colors, one per line;
+    /// --color-by picks how words map onto it
+    #[arg(long)]
+    palette_file: Option<PathBuf>,
+
+    /// Write the filtered, stemmed tokens, one message per line
+    #[arg(long)]
+    corpus_out: Option<PathBuf>,
+
+    /// Write a word co-occurrence graph (GraphML for .graphml, else DOT)
+    #[arg(long)]
+    graph_out: Option<PathBuf>,
+
+    /// Minimum number of shared messages for a graph edge
+    #[arg(long, default_value_t = 2)]
+    graph_min_weight: usize,
+
+    /// Graph file format, guessed from the extension when omitted
+    #[arg(long, value_enum)]
+    graph_format: Option<graph::GraphFormat>,
+
+    /// Weight words by message age, halving every DAYS days before the
+    /// newest message
+    #[arg(long, value_name = "DAYS", value_parser = time::parse_days)]
+    recency_halflife: Option<f64>,
+
+    /// Multiply the weight of words in bold, italic or underlined text
+    #[arg(long, value_name = "FACTOR", value_parser = stats::parse_factor)]
+    weight_emphasized: Option<f64>,
+
+    /// Drop words found in fewer than N distinct messages
+    #[arg(long, default_value_t = 0)]
+    min_df: usize,
+
+    /// Drop words used by fewer than N distinct users
+    #[arg(long, default_value_t = 0)]
+    min_senders: usize,
+
+    /// Print which users react most and with which emoji
+    #[arg(long)]
+    reaction_stats: bool,
+
+    /// Write who replies to whom as tab separated from/to/replies lines
+    #[arg(long)]
+    reply_matrix: Option<PathBuf>,
+
+    /// Print the most distinctive words of each user (TF-IDF, one document
+    /// per user)
+    #[arg(long)]
+    tfidf_by_user: bool,
+
+    /// Number of words listed per user with --tfidf-by-user
+    #[arg(long, default_value_t = 10)]
+    tfidf_top: usize,
+
+    /// Merge words within this many edits (typos) into the most frequent
+    /// spelling
+    #[arg(long)]
+    merge_similar: Option<usize>,
+
+    /// Drop words counted less than this percentage of the top word's
+    /// count, a floor that scales with the export. Compares weights instead
+    /// with --recency-halflife or --weight-emphasized.
+    #[arg(long, value_name = "P", value_parser = stats::parse_percent)]
+    min_count_percent: Option<f64>,
+
+    /// Leave out messages with these ids (comma separated)
+    #[arg(long, value_delimiter = ',')]
+    exclude_ids: Vec<i64>,
+
+    /// Leave out messages with ids listed in this file, one per line
+    #[arg(long)]
+    exclude_ids_file: Option<PathBuf>,
+
+    /// Only count messages containing this term, ignoring case
+    /// (repeatable, any term matches)
+    #[arg(long, value_parser = filter::parse_search_term)]
+    contains: Vec<String>,
+
+    /// Only count messages from this forum topic
+    #[arg(long)]
+    topic: Option<String>,
+
+    /// Write the top caption words for each media type (photo, video, ...)
+    #[arg(long)]
+    caption_by_media_type: Option<PathBuf>,
+
+    /// Write the top words for each forum topic
+    #[arg(long)]
+    per_topic: Option<PathBuf>,
+
+    /// Write message and token counts at each pipeline stage as JSON
+    #[arg(long)]
+    funnel_json: Option<PathBuf>,
+
+    /// Compare word counts with another export, reported as changes from
+    /// the main input to this one. Both are counted with the same filters.
+    #[arg(long)]
+    diff: Option<PathBuf>,
+
+    /// Write every change found by --diff
+    #[arg(long, requires = "diff")]
+    diff_out: Option<PathBuf>,
+
+    /// Write a histogram of message lengths
+    #[arg(long)]
+    length_histogram: Option<PathBuf>,
+
+    /// Measure --length-histogram in words or characters
+    #[arg(long, value_enum, default_value_t = stats::LengthUnit::Words)]
+    length_unit: stats::LengthUnit,
+
+    /// Write how often each character occurs in the message text
+    #[arg(long)]
+    char_freq_out: Option<PathBuf>,
+
+    /// Only count letters and digits in --char-freq-out
+    #[arg(long, requires = "char_freq_out")]
+    char_freq_alphanumeric: bool,
+
+    /// Write the top words for each hour of the day
+    #[arg(long)]
+    by_hour: Option<PathBuf>,
+
+    /// Write the top words for each calendar year
+    #[arg(long)]
+    by_year: Option<PathBuf>,
+
+    /// UTC offset used for --by-hour, --by-year and reported dates (e.g.
+    /// +03:00), and for the date window of messages with only a unix time
+    #[arg(long, default_value = "UTC", value_parser = time::parse_utc_offset)]
+    tz: i64,
+
+    /// Words listed per bucket in --by-hour style reports
+    #[arg(long, default_value_t = 10)]
+    bucket_top: usize,
+
+    /// Multiply the canvas size and legend text by this factor, e.g. 2
+    /// for high-DPI print
+    #[arg(long, default_value_t = 1.0, value_parser = render::parse_scale_factor)]
+    scale_factor: f32,
+
+    /// Refuse to render images taking more than this many bytes in memory
+    /// (width x height x 4)
+    #[arg(long, default_value_t = 512 * 1024 * 1024)]
+    max_image_bytes: u64,
+
+    /// Also cut the cloud into ROWSxCOLS tile PNGs for printing, saved
+    /// next to the output; pair with --scale-factor for print resolution
+    #[arg(long, value_name = "ROWSxCOLS", value_parser = render::parse_tiles)]
+    tiles: Option<render::Tiles>,
+
+    /// Warn when words would render below this font size, in pixels
+    #[arg(long, default_value_t = 8.0)]
+    min_font_visible: f32,
+
+    /// Clamp word weights into MIN,MAX before layout (e.g. 5,200)
+    #[arg(long, value_parser = render::parse_weight_clamp)]
+    weight_clamp: Option<render::WeightClamp>,
+
+    /// Write a rank vs frequency (Zipf) report with the fitted slope
+    #[arg(long)]
+    zipf_out: Option<PathBuf>,
+
+    /// Write all word counts to a SQLite database (tables word_counts and
+    /// meta)
+    #[arg(long)]
+    sqlite_out: Option<PathBuf>,
+
+    /// Write words that occur exactly once, one per line
+    #[arg(long)]
+    hapax_out: Option<PathBuf>,
+
+    /// Trace what happens to WORD along the pipeline: too short, a stop
+    /// word, stemmed, filtered out or below the --max-words cutoff
+    #[arg(long, value_name = "WORD")]
+    explain: Option<String>,
+
+    /// Print the N longest words found, often jargon and compounds
+    #[arg(long, value_name = "N")]
+    longest_words: Option<usize>,
+
+    /// Also print a rough text version of the cloud to the terminal
+    #[arg(long)]
+    ascii: bool,
+
+    /// Write a markdown summary: chat, period, participants, top words and
+    /// emoji, and the command line used
+    #[arg(long)]
+    report_md: Option<PathBuf>,
+
+    /// Write the words and weights handed to wordcloud-rs as JSON, after
+    /// weighting and --weight-clamp
+    #[arg(long)]
+    tokens_out: Option<PathBuf>,
+
+    /// Also write the cloud as SVG, laid out in rows
+    #[arg(long)]
+    svg_out: Option<PathBuf>,
+
+    /// TrueType font measured for --svg-out and referenced by the SVG, the
+    /// bundled DejaVu Sans by default
+    #[arg(long, requires = "svg_out")]
+    svg_font: Option<PathBuf>,
+
+    /// Inline the --svg-font (or bundled) font in the SVG so it renders the
+    /// same everywhere
+    #[arg(long, requires = "svg_out")]
+    embed_font: bool,
+
+    /// Add a band below the cloud listing the top words with their counts
+    #[arg(long)]
+    legend: bool,
+
+    /// Number of words listed in the legend
+    #[arg(long, default_value_t = 5)]
+    legend_entries: usize,
+
+    /// Write a horizontal bar chart of the top words as a PNG, labeled in
+    /// --legend-font
+    #[arg(long)]
+    barchart_out: Option<PathBuf>,
+
+    /// Number of words in the --barchart-out chart
+    #[arg(long, default_value_t = 20, requires = "barchart_out")]
+    barchart_top: usize,
+
+    /// TrueType font used for the legend and bar chart text, the bundled
+    /// DejaVu Sans by default
+    #[arg(long)]
+    legend_font: Option<PathBuf>,
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
+    if let Some(shell) = args.generate_completions {
+        let mut command = Args::command();
+        let name = command.get_name().to_string();
+        clap_complete::generate(
+            shell,
+            &mut command,
+            name,
+            &mut std::io::stdout(),
+        );
+        return Ok(());
+    }
+    if args.dump_bom && args.dump_encoding != encoding_rs::UTF_8 {
+        Args::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--dump-bom only applies to --dump-encoding utf-8",
+            )
+            .exit();
+    }
 
-    println!("Reading messages from {:?}", args.input);
-    let messages = parse::read_messages(&args.input)?;
-    println!("Found {} messages", messages.len());
+    // Catch huge --scale-factor values before the pipeline runs
+    let (width, height) = render::cloud_dimensions(args.scale_factor);
+    let legend_entries = if args.legend { args.legend_entries } else { 0 };
+    render::check_image_size(
+        "word cloud",
+        width as u64,
+        height as u64
+            + render::legend_height(legend_entries, args.scale_factor) as u64,
+        args.max_image_bytes,
+    )?;
+    if args.barchart_out.is_some() {
+        render::check_image_size(
+            "bar chart",
+            render::bar_chart_width(args.scale_factor) as u64,
+            render::bar_chart_height(args.barchart_top, args.scale_factor) as u64,
+            args.max_image_bytes,
+        )?;
+    }
 
-    let simple_messages = parse::simplify_messages(&messages);
-    println!("Extracted {} messages with text", simple_messages.len());
-    let len = simple_messages.len();
-    // println!("Samples: {:?}", &simple_messages[len.saturating_sub(5)..]);
+    interrupt::install_handler()?;
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(args.threads)
+        .build_global()?;
 
-    println!("Extracting text tokens");
-    let tokens =
-        tokenizer::tokenize_messages(&simple_messages, args.min_length.max(4));
-    println!("Extracted {} tokens", tokens.len());
+    // Read before the messages so a bad palette or font fails fast
+    let palette = match &args.palette_file {
+        Some(path) => Some(colors::read_palette_file(path)?),
+        None => None,
+    };
+    let legend_font = if args.legend || args.barchart_out.is_some() {
+        Some(render::load_font(args.legend_font.as_deref())?)
+    } else {
+        None
+    };
+    let svg_font = match &args.svg_out {
+        Some(_) => Some(render::load_font(args.svg_font.as_deref())?),
+        None => None,
+    };
+    let embedded_font = if args.embed_font {
+        Some(render::font_data(args.svg_font.as_deref())?)
+    } else {
+        None
+    };
 
-    // Filter Russian stopwords
-    let stop_words = tokenizer::get_russian_stopwords();
-    // let stop_words = args.stop_words.unwrap_or_default();
-    let filtered_tokens = tokenizer::filter_stop_words(tokens, &stop_words);
-    println!(
-        "After filtering stop words: {} tokens",
-        filtered_tokens.len()
-    );
+    let mut inputs = args.input.clone();
+    if let Some(dir) = &args.input_dir {
+        inputs.extend(parse::find_export_parts(dir)?);
+    }
+
+    let counter = WordCounter::new(&args)?;
 
-    let stemmed_tokens = tokenizer::stem_tokens(filtered_tokens, &args.lang);
-    println!("After stemming: {} tokens", stemmed_tokens.len());
+    // Create a missing output directory now rather than fail after all the
+    // counting. The default output goes to the working directory.
+    if let Some(output) = &args.output {
+        create_output_dir(output)?;
+    }
+
+    println!("Reading messages from {:?}", inputs);
+    let Counted {
+        word_counts,
+        token_weights,
+        header,
+        mut funnel,
+        summary,
+        mut explain,
+    } = counter.count(&inputs, true)?;
+    // Chat name and type from the first file, for the default output name,
+    // the report and channel handling
+    let chat_name = header.name.clone();
+    let chat_type = header.chat_type();
+    let output = match &args.output {
+        Some(output) => output.clone(),
+        None => default_output(chat_name.as_deref()),
+    };
+    let time_range = &summary.time_range;
+    if let (Some(first), Some(last), Some(span)) =
+        (time_range.first, time_range.last, time_range.span_days())
+    {
+        println!(
+            "Messages span {} to {} ({:.1} days)",
+            time::format_datetime(first, args.tz),
+            time::format_datetime(last, args.tz),
+            span
+        );
+    }
+    if interrupt::is_interrupted() {
+        println!("Stopped early, results only cover part of the export");
+    }
 
-    let word_counts = tokenizer::count_words(&stemmed_tokens);
     println!("Found {} unique words", word_counts.len());
+    funnel.unique = word_counts.len();
     println!("{:?}", word_counts);
 
+    if let Some(zipf_path) = &args.zipf_out {
+        let ranked = stats::rank_words(&word_counts);
+        let slope = stats::zipf_slope(&ranked);
+        if let Some(slope) = slope {
+            println!("Zipf slope: {:.4}", slope);
+        }
+        println!("Saving Zipf report to {}", zipf_path.display());
+        export::save_zipf_report(&ranked, slope, zipf_path)?;
+    }
+
+    if let Some(other_path) = &args.diff {
+        println!("Counting words in {} for comparison", other_path.display());
+        let other = counter
+            .count(std::slice::from_ref(other_path), false)
+            .with_context(|| {
+                format!("Failed to count words in {}", other_path.display())
+            })?;
+
+        let deltas = stats::diff_counts(&word_counts, &other.word_counts);
+        println!("{} words changed in {}", deltas.len(), other_path.display());
+        println!("Biggest changes:");
+        for delta in deltas.iter().take(20) {
+            println!(
+                "{} {} ({} -> {})",
+                delta.change(),
+                delta.word,
+                delta.before,
+                delta.after
+            );
+        }
+        if let Some(diff_path) = &args.diff_out {
+            println!("Saving word count diff to {}", diff_path.display());
+            export::save_diff(&deltas, diff_path)?;
+        }
+    }
+
+    if let Some(hapax_path) = &args.hapax_out {
+        let hapaxes = stats::hapax_legomena(&word_counts);
+        println!(
+            "Saving {} words used once to {}",
+            hapaxes.len(),
+            hapax_path.display()
+        );
+        export::save_word_list(&hapaxes, hapax_path)?;
+    }
+
+    if let Some(top) = args.longest_words {
+        println!("Longest {} words:", top);
+        for (word, count) in stats::longest_words(&word_counts, top) {
+            println!("{} ({} chars, {})", word, word.chars().count(), count);
+        }
+    }
+
     // Convert to wordcloud-rs Token format
     let mut wc_tokens = Vec::new();
 
-    // Sort words by frequency and take top N words
-    let mut words: Vec<_> = word_counts.into_iter().collect();
-    words.sort_by(|a, b| b.1.cmp(&a.1));
-    words.truncate(args.max_words);
+    // Sort words by frequency and take top N words. Ranking the explained
+    // word and saving every word to SQLite need the full sorted list.
+    let weight_of = |word: &str, count: usize| match &token_weights {
+        Some(weights) => weights.get(word).copied().unwrap_or(0.0) as f32,
+        None => count as f32,
+    };
+    let mut words = if explain.is_none() && args.sqlite_out.is_none() {
+        rank::ranked_words(word_counts, args.max_words, weight_of)
+    } else {
+        rank::ranked_words(word_counts, 0, weight_of)
+    };
+    if let Some(trace) = &mut explain {
+        trace.check_rank(&words, args.max_words);
+        trace.print();
+    }
+
+    // Every counted word goes to the database, not just the cloud's top N
+    if let Some(sqlite_path) = &args.sqlite_out {
+        let meta = [
+            (
+                "command_line",
+                std::env::args().collect::<Vec<_>>().join(" "),
+            ),
+            ("chat_name", chat_name.clone().unwrap_or_default()),
+            ("lang", args.lang.join(",")),
+            ("min_length", args.min_length.to_string()),
+            ("messages", funnel.messages.to_string()),
+            ("words", words.len().to_string()),
+        ];
+        let rows: Vec<(&str, usize, f32)> = words
+            .iter()
+            .map(|(word, count)| (word.as_str(), *count, weight_of(word, *count)))
+            .collect();
+        println!("Saving word counts to SQLite {}", sqlite_path.display());
+        export::save_sqlite(&rows, &meta, sqlite_path)?;
+    }
+
+    if args.max_words > 0 {
+        words.truncate(args.max_words);
+    }
+    funnel.r#final = words.len();
+
+    // Every output lists the words with what the cloud sizes them by
+    let words: Vec<(String, f32)> = words
+        .into_iter()
+        .map(|(word, count)| {
+            let weight = weight_of(&word, count);
+            (word, weight)
+        })
+        .collect();
 
-    let python_data_path = args.output.with_extension("txt");
+    if let Some(funnel_path) = &args.funnel_json {
+        println!("Saving pipeline funnel to {}", funnel_path.display());
+        export::save_funnel(&funnel, funnel_path)?;
+    }
+
+    let python_data_path = output.with_extension("txt");
     println!(
         "Saving word data for Python to {}",
         python_data_path.display()
     );
-    save_word_counts_for_python(&words, &python_data_path)?;
+    let word_colors =
+        colors::assign_colors(&words, args.color_by, palette.as_deref());
+    save_word_counts_for_python(
+        &words,
+        word_colors.as_deref(),
+        &python_data_path,
+        args.dump_encoding,
+        args.dump_bom,
+    )?;
+
+    if let Some(report_path) = &args.report_md {
+        let report = export::Report {
+            chat_name: chat_name.as_deref(),
+            chat_type,
+            summary: &summary,
+            words: &words,
+            weighted: token_weights.is_some(),
+            utc_offset: args.tz,
+            command_line: std::env::args().collect::<Vec<_>>().join(" "),
+        };
+        println!("Saving markdown report to {}", report_path.display());
+        export::save_markdown_report(&report, report_path)?;
+    }
 
     // Print top words being used for the cloud
     println!("Top 40 words:");
-    for (i, (word, count)) in words.iter().take(40).enumerate() {
-        println!("{}. {} ({})", i + 1, word, count);
+    for (i, (word, weight)) in words.iter().take(40).enumerate() {
+        println!("{}. {} ({})", i + 1, word, stats::format_weight(*weight));
+    }
+
+    if args.ascii {
+        print!("{}", ascii::render_ascii(&words));
+    }
+
+    if let Some(barchart_path) = &args.barchart_out
+        && let Some(font) = &legend_font
+    {
+        let top = &words[..args.barchart_top.min(words.len())];
+        let chart = render::render_bar_chart(top, font, args.scale_factor);
+        println!("Saving bar chart to {}", barchart_path.display());
+        chart.save(barchart_path)?;
+    }
+
+    let legend_words = if args.legend {
+        words[..args.legend_entries.min(words.len())].to_vec()
+    } else {
+        Vec::new()
+    };
+
+    let weights: Vec<f32> = words
+        .iter()
+        .map(|(_, weight)| match &args.weight_clamp {
+            Some(clamp) => clamp.apply(*weight),
+            None => *weight,
+        })
+        .collect();
+
+    let sized: Vec<(&str, f32)> = words
+        .iter()
+        .zip(&weights)
+        .map(|((word, _), weight)| (word.as_str(), *weight))
+        .collect();
+    let font_sizes = render::estimate_font_sizes(&sized, width, height);
+    let readable = font_sizes
+        .iter()
+        .filter(|size| **size >= args.min_font_visible)
+        .count();
+    if readable < font_sizes.len() {
+        eprintln!(
+            "Warning: about {} of {} words will render below {}px; \
+             consider --max-words {}",
+            font_sizes.len() - readable,
+            font_sizes.len(),
+            args.min_font_visible,
+            readable.max(1)
+        );
+    }
+
+    if let Some(tokens_path) = &args.tokens_out {
+        println!("Saving cloud tokens to {}", tokens_path.display());
+        export::save_cloud_tokens(&sized, tokens_path)?;
+    }
+
+    if let Some(svg_path) = &args.svg_out
+        && let Some(font) = &svg_font
+    {
+        let document = svg::render_svg(
+            &sized,
+            word_colors.as_deref(),
+            width,
+            height,
+            font,
+            embedded_font.as_deref(),
+        );
+        println!("Saving SVG word cloud to {}", svg_path.display());
+        std::fs::write(svg_path, document)?;
     }
 
     // Convert to wordcloud tokens
-    for (word, count) in words {
-        wc_tokens.push((Token::Text(word), count as f32));
+    for ((word, _), weight) in words.into_iter().zip(weights) {
+        wc_tokens.push((Token::Text(word), weight));
     }
 
     println!("Generating word cloud with {} words", wc_tokens.len());
-    let wc = WordCloud::new().font("DejaVu Sans").generate(wc_tokens);
+    let mut wc = WordCloud::new()
+        .font("DejaVu Sans")
+        .dim(width, height)
+        .generate(wc_tokens);
+
+    if args.legend
+        && let Some(font) = &legend_font
+    {
+        wc = render::add_legend(&wc, &legend_words, font, args.scale_factor);
+    }
+
+    println!("Saving word cloud to {}", output.display());
+    wc.save(&output)?;
+
+    if let Some(tiles) = args.tiles {
+        let paths = render::save_tiles(&wc, tiles, &output)?;
+        println!("Saved {} tiles next to {}", paths.len(), output.display());
+    }
+
+    println!("Word cloud generated at: {}", output.display());
+    Ok(())
+}
+
+/// Everything needed to count the words of some inputs, shared by the main
+/// export and the --diff one so both go through the same filters
+struct WordCounter<'a> {
+    args: &'a Args,
+    read_options: parse::ReadOptions,
+    date_filter: filter::DateFilter,
+    excluded_ids: HashSet<i64>,
+    tokenize_options: tokenizer::TokenizeOptions,
+    /// Already stemmed with --stem-stopwords
+    stop_words: Vec<String>,
+    stem_exceptions: HashSet<String>,
+}
+
+/// Words counted in one set of inputs
+struct Counted {
+    word_counts: HashMap<String, usize>,
+    /// Word weights replacing counts in the cloud, with --recency-halflife
+    /// or --weight-emphasized
+    token_weights: Option<HashMap<String, f64>>,
+    /// Header of the first file
+    header: schema::ExportHeader,
+    funnel: stats::Funnel,
+    summary: stats::MessageSummary,
+    explain: Option<explain::Trace>,
+}
+
+impl<'a> WordCounter<'a> {
+    /// Read the word lists and id files the options point to
+    fn new(args: &'a Args) -> Result<Self> {
+        let read_options = parse::ReadOptions {
+            recovery_depth: args.parse_recovery_depth,
+            encoding: args.encoding,
+            strict: args.strict,
+        };
+        let date_filter = filter::DateFilter {
+            from: args.from_date.clone(),
+            to: args.to_date.clone(),
+            exclude_from: args.exclude_from.clone(),
+            exclude_to: args.exclude_to.clone(),
+            utc_offset: args.tz,
+        };
+
+        let tokenize_options = tokenizer::TokenizeOptions {
+            unicode_form: args.unicode_normalize,
+            lowercase: !args.no_lowercase,
+            locale: args.locale,
+            emoji_as_words: args.emoji_as_words,
+            include_punctuation: args.include_punctuation,
+            letters_only: args.letters_only,
+            url_tokens: args.dedupe_urls_as_single_token,
+            phrases: match &args.keep_phrases_file {
+                Some(path) => {
+                    tokenizer::phrase_regex(&filter::read_word_list(path)?)
+                }
+                None => None,
+            },
+            min_length: args.min_length,
+            max_length: args.max_length,
+            inner_punctuation: args.strip_punctuation_inside_words,
+            split_identifiers: args.split_identifiers,
+        };
+
+        // Filter Russian stopwords plus any given on the command line
+        let mut stop_words = tokenizer::get_russian_stopwords();
+        if args.merge_stopword_lists {
+            for lang in &args.lang {
+                match tokenizer::builtin_stopwords(lang) {
+                    Some(words) => stop_words.extend(words),
+                    None => {
+                        eprintln!(
+                            "Warning: No built-in stop words for '{}'",
+                            lang
+                        )
+                    }
+                }
+            }
+        }
+        stop_words.extend(args.stop_words.iter().flatten().cloned());
+        for url in &args.stop_words_url {
+            if args.offline {
+                eprintln!(
+                    "Warning: Skipping stop words from {} (--offline)",
+                    url
+                );
+                continue;
+            }
+            match filter::fetch_word_list(url) {
+                Ok(words) => {
+                    println!("Loaded {} stop words from {}", words.len(), url);
+                    stop_words.extend(words);
+                }
+                Err(e) if args.strict => return Err(e),
+                Err(e) => eprintln!("Warning: {:#}, continuing without it", e),
+            }
+        }
+        let stop_words = tokenizer::normalize_stop_words(stop_words, args.locale);
+        let stop_words = if args.stem_stopwords {
+            tokenizer::stem_stop_words(&stop_words, &args.lang)
+        } else {
+            stop_words
+        };
+
+        let stem_exceptions: HashSet<String> = match &args.stem_exceptions_file {
+            Some(path) => filter::read_word_list(path)?
+                .iter()
+                .map(|word| args.locale.to_lowercase(word))
+                .collect(),
+            None => HashSet::new(),
+        };
+
+        let mut excluded_ids: HashSet<i64> =
+            args.exclude_ids.iter().copied().collect();
+        if let Some(ids_path) = &args.exclude_ids_file {
+            excluded_ids.extend(filter::read_id_file(ids_path)?);
+        }
+
+        Ok(Self {
+            args,
+            read_options,
+            date_filter,
+            excluded_ids,
+            tokenize_options,
+            stop_words,
+            stem_exceptions,
+        })
+    }
+}
+
+impl WordCounter<'_> {
+    /// Count the words of `inputs`. The `primary` inputs also get the side
+    /// outputs, the --parse-log and the --explain trace.
+    fn count(&self, inputs: &[PathBuf], primary: bool) -> Result<Counted> {
+        let args = self.args;
+        let mut counted = if args.streaming {
+            self.count_streaming(inputs, primary)?
+        } else {
+            self.count_batch(inputs, primary)?
+        };
+
+        if let Some(distance) = args.merge_similar
+            && distance > 0
+        {
+            let (word_counts, merged_into) =
+                fuzzy::merge_similar(counted.word_counts, distance);
+            counted.word_counts = word_counts;
+            counted.token_weights = counted
+                .token_weights
+                .map(|weights| fuzzy::merge_weights(weights, &merged_into));
+            println!(
+                "After merging similar words: {} unique",
+                counted.word_counts.len()
+            );
+            if let Some(trace) = &mut counted.explain {
+                trace.check_counts(&counted.word_counts, "--merge-similar");
+            }
+        }
+        if let Some(percent) = args.min_count_percent {
+            // Weighted words are cut by the weight they are drawn with
+            match &mut counted.token_weights {
+                Some(weights) => {
+                    let floor = stats::weight_floor(weights, percent);
+                    weights.retain(|_, weight| *weight >= floor);
+                    counted
+                        .word_counts
+                        .retain(|word, _| weights.contains_key(word));
+                    println!(
+                        "Words weighing at least {:.2} ({}% of the top word): {}",
+                        floor,
+                        percent,
+                        counted.word_counts.len()
+                    );
+                }
+                None => {
+                    let floor = stats::count_floor(&counted.word_counts, percent);
+                    counted.word_counts.retain(|_, count| *count >= floor);
+                    println!(
+                        "Words counted at least {} times ({}% of the top word): {}",
+                        floor,
+                        percent,
+                        counted.word_counts.len()
+                    );
+                }
+            }
+            if let Some(trace) = &mut counted.explain {
+                trace.check_counts(&counted.word_counts, "--min-count-percent");
+            }
+        }
+        Ok(counted)
+    }
+
+    /// Tokenize each message as it is parsed, see `stream::count_words`
+    fn count_streaming(
+        &self,
+        inputs: &[PathBuf],
+        primary: bool,
+    ) -> Result<Counted> {
+        println!("Streaming: counting words as messages are parsed");
+        let args = self.args;
+        let mut explain = self.trace(primary, &self.stop_words);
+        let mut funnel = stats::Funnel::default();
+        let mut summary = stats::MessageSummary::default();
+        let simplify = OnceCell::new();
+        // First unreadable date seen under --strict-dates
+        let mut date_error = None;
+        let mut sources = HashSet::new();
+        let (mut word_counts, parse_stats) = stream::count_words(
+            inputs,
+            &self.read_options,
+            |message, header| {
+                if args.exclude_forward_source_names
+                    && let Some(source) = &message.forwarded_from
+                {
+                    sources.insert(source.clone());
+                }
+                let simplify_options =
+                    simplify.get_or_init(|| simplify_options(args, header));
+                self.tokenize_message(
+                    message,
+                    simplify_options,
+                    &mut funnel,
+                    &mut summary,
+                )
+                .unwrap_or_else(|e| {
+                    date_error.get_or_insert(e);
+                    Vec::new()
+                })
+            },
+        )?;
+        if let Some(e) = date_error {
+            return Err(e);
+        }
+        funnel.messages = parse_stats.parsed;
+        self.print_parse_stats(&parse_stats, primary)?;
+        if let Some(trace) = &mut explain {
+            trace.check_counts(&word_counts, "counting");
+        }
+        // The names are only all known once the messages are counted, so
+        // their words come out of the counts, which are keyed by stem
+        if args.exclude_forward_source_names {
+            let source_words = self.forward_source_words(sources);
+            let source_stems: HashSet<String> = if args.stem_stopwords {
+                source_words.into_iter().collect()
+            } else {
+                tokenizer::stem_stop_words(&source_words, &args.lang)
+                    .into_iter()
+                    .collect()
+            };
+            word_counts.retain(|word, _| !source_stems.contains(word));
+            if let Some(trace) = &mut explain {
+                trace
+                    .check_counts(&word_counts, "--exclude-forward-source-names");
+            }
+        }
+        Ok(Counted {
+            word_counts,
+            token_weights: None,
+            header: parse_stats.header.unwrap_or_default(),
+            funnel,
+            summary,
+            explain,
+        })
+    }
+
+    /// The whole pipeline for a single message, used when streaming. Fails
+    /// only on an unreadable date under --strict-dates.
+    fn tokenize_message(
+        &self,
+        message: &parse::Message,
+        simplify_options: &parse::SimplifyOptions,
+        funnel: &mut stats::Funnel,
+        summary: &mut stats::MessageSummary,
+    ) -> Result<Vec<tokenizer::Token>> {
+        let args = self.args;
+        if args.strict_dates && self.date_filter.is_active() {
+            filter::check_message_date(message)?;
+        }
+        if message.id.is_some_and(|id| self.excluded_ids.contains(&id))
+            || !self.date_filter.keeps(message)
+        {
+            return Ok(Vec::new());
+        }
+        let Some(simple_message) =
+            parse::simplify_message(message, simplify_options)
+        else {
+            return Ok(Vec::new());
+        };
+        if !args.contains.is_empty()
+            && !filter::contains_any_term(&simple_message.text, &args.contains)
+        {
+            return Ok(Vec::new());
+        }
+        if tokenizer::count_message_words(&simple_message.text)
+            < args.min_words_per_message
+        {
+            return Ok(Vec::new());
+        }
+        funnel.with_text += 1;
+        summary.add(&simple_message);
+        let tokens = tokenizer::tokenize_messages(
+            std::slice::from_ref(&simple_message),
+            &self.tokenize_options,
+        );
+        funnel.tokens += tokens.len();
+        Ok(refine_tokens(
+            tokens,
+            args,
+            &self.stop_words,
+            &self.stem_exceptions,
+            funnel,
+            false,
+        ))
+    }
+
+    /// Load every message first, for the filters that need to see more than
+    /// one message at a time
+    fn count_batch(&self, inputs: &[PathBuf], primary: bool) -> Result<Counted> {
+        let args = self.args;
+        let mut funnel = stats::Funnel::default();
+        let mut summary = stats::MessageSummary::default();
+
+        let (mut messages, parse_stats) =
+            parse::read_message_files(inputs, &self.read_options)?;
+        println!("Found {} messages", messages.len());
+        self.print_parse_stats(&parse_stats, primary)?;
+
+        let stop_words = if args.exclude_forward_source_names {
+            let sources = messages
+                .iter()
+                .filter_map(|message| message.forwarded_from.clone())
+                .collect();
+            let mut stop_words = self.stop_words.clone();
+            stop_words.extend(self.forward_source_words(sources));
+            Cow::Owned(stop_words)
+        } else {
+            Cow::Borrowed(&self.stop_words)
+        };
+        let mut explain = self.trace(primary, &stop_words);
+        funnel.messages = messages.len();
+        let header = parse_stats.header.unwrap_or_default();
+        let simplify_options = simplify_options(args, &header);
+
+        if !self.excluded_ids.is_empty() {
+            filter::filter_by_ids(&mut messages, &self.excluded_ids);
+            println!("After excluding message ids: {} messages", messages.len());
+        }
+
+        let topic_count = parse::resolve_topics(&mut messages);
+        if topic_count > 0 {
+            println!("Found {} forum topics", topic_count);
+        }
+        if let Some(topic) = &args.topic {
+            filter::filter_by_topic(&mut messages, topic);
+            println!("In topic '{}': {} messages", topic, messages.len());
+        }
+
+        if args.strict_dates && self.date_filter.is_active() {
+            for message in &messages {
+                filter::check_message_date(message)?;
+            }
+        }
+        if self.date_filter.is_active() {
+            filter::filter_by_date(&mut messages, &self.date_filter);
+            println!("After date filtering: {} messages", messages.len());
+        }
+
+        if primary {
+            self.save_message_stats(&messages)?;
+        }
+
+        let simple_messages =
+            parse::simplify_messages(&messages, &simplify_options);
+        println!("Extracted {} messages with text", simple_messages.len());
+
+        let simple_messages = if args.dedup_messages {
+            let deduped = filter::dedup_consecutive(simple_messages);
+            println!("After removing repeated messages: {}", deduped.len());
+            deduped
+        } else {
+            simple_messages
+        };
 
-    println!("Saving word cloud to {}", args.output.display());
-    wc.save(&args.output)?;
+        let simple_messages = if args.despam {
+            let despammed = filter::despam(
+                simple_messages,
+                args.despam_window.into(),
+                args.despam_similarity,
+            );
+            println!("After collapsing floods: {}", despammed.len());
+            despammed
+        } else {
+            simple_messages
+        };
 
-    println!("Word cloud generated at: {}", args.output.display());
+        let simple_messages = if !args.contains.is_empty() {
+            let matching =
+                filter::filter_by_terms(simple_messages, &args.contains);
+            println!(
+                "Kept {} messages containing {:?}",
+                matching.len(),
+                args.contains
+            );
+            matching
+        } else {
+            simple_messages
+        };
+
+        let simple_messages = if args.min_words_per_message > 0 {
+            let kept = tokenizer::filter_short_messages(
+                simple_messages,
+                args.min_words_per_message,
+            );
+            println!(
+                "Kept {} messages with at least {} words",
+                kept.len(),
+                args.min_words_per_message
+            );
+            kept
+        } else {
+            simple_messages
+        };
+
+        println!("Extracting text tokens");
+        let tokens = tokenizer::tokenize_messages(
+            &simple_messages,
+            &self.tokenize_options,
+        );
+        println!("Extracted {} tokens", tokens.len());
+        funnel.with_text = simple_messages.len();
+        funnel.tokens = tokens.len();
+        for message in &simple_messages {
+            summary.add(message);
+        }
+
+        let stemmed_tokens = refine_tokens(
+            tokens,
+            args,
+            &stop_words,
+            &self.stem_exceptions,
+            &mut funnel,
+            true,
+        );
+
+        if primary {
+            self.save_token_stats(&stemmed_tokens, &simple_messages)?;
+        }
+
+        let token_weights = (args.recency_halflife.is_some()
+            || args.weight_emphasized.is_some())
+        .then(|| {
+            stats::token_weights(
+                &stemmed_tokens,
+                &simple_messages,
+                args.recency_halflife,
+                args.weight_emphasized.unwrap_or(1.0),
+            )
+        });
+
+        let mut word_counts = tokenizer::count_words(&stemmed_tokens);
+        if let Some(trace) = &mut explain {
+            trace.check_counts(&word_counts, "counting");
+        }
+        if args.min_df > 1 {
+            let df = stats::document_frequency(&stemmed_tokens);
+            word_counts.retain(|word, _| df[word] >= args.min_df);
+            if let Some(trace) = &mut explain {
+                trace.check_counts(&word_counts, "--min-df");
+            }
+            println!(
+                "Words in at least {} messages: {}",
+                args.min_df,
+                word_counts.len()
+            );
+        }
+        if args.min_senders > 1 {
+            let senders = stats::sender_counts(&stemmed_tokens, &simple_messages);
+            word_counts.retain(|word, _| senders[word] >= args.min_senders);
+            if let Some(trace) = &mut explain {
+                trace.check_counts(&word_counts, "--min-senders");
+            }
+            println!(
+                "Words used by at least {} people: {}",
+                args.min_senders,
+                word_counts.len()
+            );
+        }
+        let token_weights = token_weights.map(|mut weights| {
+            weights.retain(|word, _| word_counts.contains_key(word));
+            weights
+        });
+        Ok(Counted {
+            word_counts,
+            token_weights,
+            header,
+            funnel,
+            summary,
+            explain,
+        })
+    }
+
+    /// --explain trace, for the primary inputs only
+    fn trace(
+        &self,
+        primary: bool,
+        stop_words: &[String],
+    ) -> Option<explain::Trace> {
+        let word = self.args.explain.as_deref().filter(|_| primary)?;
+        let pipeline = explain::Pipeline {
+            tokenize_options: &self.tokenize_options,
+            stop_words,
+            stem_stopwords: self.args.stem_stopwords,
+            langs: &self.args.lang,
+            stem_exceptions: &self.stem_exceptions,
+            pos: self.args.pos,
+        };
+        Some(explain::Trace::new(word, &pipeline))
+    }
+
+    /// Words of the forward source names in `sources`, normalized like
+    /// `stop_words`
+    fn forward_source_words(&self, sources: HashSet<String>) -> Vec<String> {
+        let source_messages: Vec<parse::SimpleMessage> = sources
+            .into_iter()
+            .map(|source| parse::SimpleMessage {
+                text: source,
+                ..Default::default()
+            })
+            .collect();
+        let source_words = tokenizer::tokenize_messages(
+            &source_messages,
+            &self.tokenize_options,
+        );
+        println!(
+            "Excluding {} words from {} forward source names",
+            source_words.len(),
+            source_messages.len()
+        );
+        let words = tokenizer::normalize_stop_words(
+            source_words.into_iter().map(|token| token.word).collect(),
+            self.args.locale,
+        );
+        if self.args.stem_stopwords {
+            tokenizer::stem_stop_words(&words, &self.args.lang)
+        } else {
+            words
+        }
+    }
+
+    /// Parse issues of the primary inputs go to the --parse-log
+    fn print_parse_stats(
+        &self,
+        parse_stats: &parse::ParseStats,
+        primary: bool,
+    ) -> Result<()> {
+        let parse_log = self.args.parse_log.as_deref().filter(|_| primary);
+        print_parse_stats(parse_stats, parse_log)
+    }
+
+    /// Side outputs computed from whole messages
+    fn save_message_stats(&self, messages: &[parse::Message]) -> Result<()> {
+        let args = self.args;
+        if args.reaction_stats {
+            println!("Reactions by user:");
+            for stats in stats::reactions_by_user(messages) {
+                let emoji = stats
+                    .emoji
+                    .iter()
+                    .map(|(emoji, count)| format!("{} {}", emoji, count))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                println!("{}: {} ({})", stats.user, stats.total, emoji);
+            }
+        }
+
+        if let Some(reply_path) = &args.reply_matrix {
+            let counts = stats::reply_counts(messages);
+            println!("Saving reply matrix to {}", reply_path.display());
+            export::save_reply_matrix(&counts, reply_path)?;
+        }
+        Ok(())
+    }
+
+    /// Side outputs computed from the message texts and their tokens
+    fn save_token_stats(
+        &self,
+        stemmed_tokens: &[tokenizer::Token],
+        simple_messages: &[parse::SimpleMessage],
+    ) -> Result<()> {
+        let args = self.args;
+        if let Some(histogram_path) = &args.length_histogram {
+            let buckets =
+                stats::length_histogram(simple_messages, args.length_unit);
+            println!(
+                "Saving message length histogram to {}",
+                histogram_path.display()
+            );
+            export::save_length_histogram(&buckets, histogram_path)?;
+        }
+
+        if let Some(char_freq_path) = &args.char_freq_out {
+            let counts = stats::char_frequencies(
+                simple_messages,
+                args.char_freq_alphanumeric,
+            );
+            println!(
+                "Saving character frequencies to {}",
+                char_freq_path.display()
+            );
+            export::save_char_frequencies(&counts, char_freq_path)?;
+        }
+
+        if args.stem_map_out.is_some() || args.warn_stem_collisions.is_some() {
+            let stem_forms = tokenizer::count_stem_forms(stemmed_tokens);
+            if let Some(min_forms) = args.warn_stem_collisions {
+                for (stem, forms) in
+                    tokenizer::stem_collisions(&stem_forms, min_forms as usize)
+                {
+                    eprintln!(
+                        "Warning: Stem '{}' merges {} forms: {}",
+                        stem,
+                        forms.len(),
+                        forms.join(", ")
+                    );
+                }
+            }
+            if let Some(stem_map_path) = &args.stem_map_out {
+                println!("Saving stem map to {}", stem_map_path.display());
+                export::save_stem_map(&stem_forms, stem_map_path)?;
+            }
+        }
+
+        if let Some(corpus_path) = &args.corpus_out {
+            println!("Saving token corpus to {}", corpus_path.display());
+            export::save_corpus(stemmed_tokens, corpus_path)?;
+        }
+
+        if let Some(graph_path) = &args.graph_out {
+            let pairs = graph::count_cooccurrences(stemmed_tokens);
+            let format = args
+                .graph_format
+                .unwrap_or_else(|| graph::GraphFormat::from_path(graph_path));
+            println!("Saving co-occurrence graph to {}", graph_path.display());
+            graph::save_graph(&pairs, args.graph_min_weight, format, graph_path)?;
+        }
+
+        if args.tfidf_by_user {
+            println!("Distinctive words per user (TF-IDF):");
+            let per_user = stats::tfidf_by_user(
+                stemmed_tokens,
+                simple_messages,
+                args.tfidf_top,
+            );
+            for (user, scores) in per_user {
+                let words = scores
+                    .iter()
+                    .map(|(word, score)| format!("{} ({:.3})", word, score))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                println!("{}: {}", user, words);
+            }
+        }
+
+        if let Some(per_topic_path) = &args.per_topic {
+            let buckets = stats::count_by_topic(stemmed_tokens, simple_messages);
+            println!("Saving words by topic to {}", per_topic_path.display());
+            export::save_bucketed_counts(
+                &buckets,
+                args.bucket_top,
+                per_topic_path,
+            )?;
+        }
+
+        if let Some(media_path) = &args.caption_by_media_type {
+            let buckets =
+                stats::count_by_media_type(stemmed_tokens, simple_messages);
+            println!(
+                "Saving caption words by media type to {}",
+                media_path.display()
+            );
+            export::save_bucketed_counts(&buckets, args.bucket_top, media_path)?;
+        }
+
+        if let Some(by_year_path) = &args.by_year {
+            let buckets =
+                stats::count_by_year(stemmed_tokens, simple_messages, args.tz);
+            println!("Saving words by year to {}", by_year_path.display());
+            export::save_bucketed_counts(
+                &buckets,
+                args.bucket_top,
+                by_year_path,
+            )?;
+        }
+
+        if let Some(by_hour_path) = &args.by_hour {
+            let buckets: Vec<_> =
+                stats::count_by_hour(stemmed_tokens, simple_messages, args.tz)
+                    .into_iter()
+                    .enumerate()
+                    .map(|(hour, counts)| (format!("{:02}", hour), counts))
+                    .collect();
+            println!("Saving words by hour to {}", by_hour_path.display());
+            export::save_bucketed_counts(
+                &buckets,
+                args.bucket_top,
+                by_hour_path,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// How messages are simplified for the chat described by `header`
+fn simplify_options(
+    args: &Args,
+    header: &schema::ExportHeader,
+) -> parse::SimplifyOptions {
+    parse::SimplifyOptions {
+        include_polls: args.include_polls,
+        unknown_user_label: args.unknown_user_label.clone(),
+        exclude_unknown_users: args.exclude_unknown_users,
+        text_source: args.text_source,
+        strip_quotes: args.strip_quotes,
+        exclude_links: args.exclude_links_entities,
+        drop_link_text: args.drop_link_text,
+        exclude_sticker_emoji: args.exclude_stickers_emoji_in_text,
+        normalize_whitespace: args.normalize_whitespace,
+        prefer_author: header
+            .chat_type()
+            .is_some_and(schema::ChatType::is_channel),
+        user_names: header.user_names(),
+        // Saved-messages exports usually have no chat name
+        single_sender: args.self_chat.then(|| {
+            header
+                .name
+                .clone()
+                .unwrap_or_else(|| "Saved Messages".to_string())
+        }),
+    }
+}
+
+/// Create the directories `output` is to be written in
+fn create_output_dir(output: &Path) -> Result<()> {
+    if let Some(dir) = output.parent()
+        && !dir.as_os_str().is_empty()
+    {
+        std::fs::create_dir_all(dir).with_context(|| {
+            format!("Failed to create output directory {}", dir.display())
+        })?;
+    }
+    Ok(())
+}
+
+/// `<chat name>.png` when the export names its chat, else `wordcloud.png`
+fn default_output(chat_name: Option<&str>) -> PathBuf {
+    let name = chat_name
+        .map(sanitize_file_name)
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "wordcloud".to_string());
+    PathBuf::from(name).with_extension("png")
+}
+
+/// Keep letters and digits, turning every other run of characters into a
+/// single underscore
+fn sanitize_file_name(name: &str) -> String {
+    let mut sanitized = String::new();
+    for c in name.chars() {
+        if c.is_alphanumeric() {
+            sanitized.push(c);
+        } else if !sanitized.is_empty() && !sanitized.ends_with('_') {
+            sanitized.push('_');
+        }
+    }
+    sanitized.trim_end_matches('_').chars().take(100).collect()
+}
+
+/// Report skipped messages, each one to stderr or to the --parse-log file
+fn print_parse_stats(
+    parse_stats: &parse::ParseStats,
+    parse_log: Option<&std::path::Path>,
+) -> Result<()> {
+    match parse_log {
+        Some(log_path) => {
+            let mut writer = BufWriter::new(File::create(log_path)?);
+            for issue in &parse_stats.issues {
+                writeln!(writer, "{}", issue)?;
+            }
+            writer.flush()?;
+        }
+        None => {
+            for issue in &parse_stats.issues {
+                eprintln!("Warning: {}", issue);
+            }
+        }
+    }
+
+    if parse_stats.failed > 0 || parse_stats.skipped_bytes > 0 {
+        println!(
+            "Skipped {} malformed objects ({} bytes)",
+            parse_stats.failed, parse_stats.skipped_bytes
+        );
+        if let Some(log_path) = parse_log {
+            println!("Parse errors written to {}", log_path.display());
+        }
+    }
     Ok(())
 }
 
+/// Stop-word, part of speech and stemming stages shared by the batch and
+/// streaming pipelines. `stop_words` are already stemmed with
+/// --stem-stopwords.
+fn refine_tokens(
+    tokens: Vec<tokenizer::Token>,
+    args: &Args,
+    stop_words: &[String],
+    stem_exceptions: &HashSet<String>,
+    funnel: &mut stats::Funnel,
+    verbose: bool,
+) -> Vec<tokenizer::Token> {
+    // Stop words are matched on surface forms unless --stem-stopwords moves
+    // filtering after stemming
+    let tokens = if args.stem_stopwords {
+        tokens
+    } else {
+        let filtered =
+            tokenizer::filter_stop_words(tokens, stop_words, args.locale);
+        if verbose {
+            println!("After filtering stop words: {} tokens", filtered.len());
+        }
+        funnel.after_stopwords += filtered.len();
+        filtered
+    };
+
+    // Tagging relies on word endings, so it has to run before stemming
+    let tokens = if args.pos != pos::PartOfSpeech::Any {
+        let tagged = pos::filter_pos(tokens, &args.lang, args.pos);
+        if verbose {
+            println!("After part of speech filtering: {} tokens", tagged.len());
+        }
+        tagged
+    } else {
+        tokens
+    };
+
+    let tokens = tokenizer::stem_tokens(tokens, &args.lang, stem_exceptions);
+    if verbose {
+        println!("After stemming: {} tokens", tokens.len());
+    }
+    funnel.after_stem += tokens.len();
+
+    if args.stem_stopwords {
+        let filtered =
+            tokenizer::filter_stop_words(tokens, stop_words, args.locale);
+        if verbose {
+            println!(
+                "After filtering stemmed stop words: {} tokens",
+                filtered.len()
+            );
+        }
+        funnel.after_stopwords += filtered.len();
+        filtered
+    } else {
+        tokens
+    }
+}
+
 fn save_word_counts_for_python(
-    words: &[(String, usize)],
+    words: &[(String, f32)],
+    word_colors: Option<&[colors::Rgb]>,
     output_path: &std::path::Path,
+    encoding: &'static encoding_rs::Encoding,
+    bom: bool,
 ) -> Result<()> {
+    let mut text = String::new();
+    for (i, (word, weight)) in words.iter().enumerate() {
+        let weight = stats::format_weight(*weight);
+        let line = match word_colors {
+            Some(word_colors) => {
+                format!(
+                    "{} {} {}\n",
+                    word,
+                    weight,
+                    colors::to_hex(word_colors[i])
+                )
+            }
+            None => format!("{} {}\n", word, weight),
+        };
+        text.push_str(&line);
+    }
+
+    let (bytes, _, unmappable) = encoding.encode(&text);
+    if unmappable {
+        eprintln!(
+            "Warning: Some words can't be written as {} and were saved as \
+             HTML character references",
+            encoding.name()
+        );
+    }
+
     let output_file = File::create(output_path)?;
     let mut writer = BufWriter::new(output_file);
-
-    for (word, count) in words {
-        writeln!(writer, "{} {}", word, count)?;
+    if bom {
+        writer.write_all(b"\xEF\xBB\xBF")?;
     }
+    writer.write_all(&bytes)?;
+    writer.flush()?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    /// Write a single chat export holding `messages` to a temporary file
+    fn write_export(name: &str, messages: serde_json::Value) -> PathBuf {
+        let path = temp_path(&format!("{}.json", name));
+        let export = serde_json::json!({
+            "name": "Test chat",
+            "type": "private_group",
+            "id": 1,
+            "messages": messages,
+        });
+        std::fs::write(&path, export.to_string()).unwrap();
+        path
+    }
+
+    /// A text message from `from`, a minute after the previous `id`
+    fn text_message(
+        id: i64,
+        from: &str,
+        text: impl Into<serde_json::Value>,
+    ) -> serde_json::Value {
+        serde_json::json!({
+            "id": id,
+            "type": "message",
+            "date_unixtime": (1709283600 + id * 60).to_string(),
+            "from": from,
+            "text": text.into(),
+        })
+    }
+
+    /// A path in the temporary directory unique to this test run, shared by
+    /// the tests of every module
+    pub(crate) fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "tg-dump-word-cloud-{}-{}",
+            std::process::id(),
+            name
+        ))
+    }
+
+    /// Count the words of `input` as the command line `options` would
+    fn count_with(input: &Path, options: &[&str]) -> Counted {
+        let mut command_line =
+            vec!["tg-dump-word-cloud", "-i", input.to_str().unwrap()];
+        command_line.extend(options);
+        let args = Args::try_parse_from(command_line).unwrap();
+        WordCounter::new(&args)
+            .unwrap()
+            .count(&args.input, true)
+            .unwrap()
+    }
+
+    #[test]
+    fn export_fixture_end_to_end() {
+        let (messages, stats) =
+            parse::read_messages(fixture("export.json"), &Default::default())
+                .unwrap();
+        // The chat header wrapping the messages is skipped, not a failure
+        assert_eq!(messages.len(), 8);
+        assert_eq!(stats.failed, 0);
+
+        // Service message and sticker without caption carry no text
+        let simple_messages = parse::simplify_messages(
+            &messages,
+            &parse::SimplifyOptions::default(),
+        );
+        assert_eq!(simple_messages.len(), 6);
+        assert_eq!(simple_messages[0].username, "Alice");
+        assert_eq!(
+            simple_messages[1].text,
+            "Read the https://doc.rust-lang.org about the borrow checker"
+        );
+        assert_eq!(simple_messages[2].text, "Rust crab mascot");
+        assert_eq!(simple_messages[4].text, "Cargo builds fast");
+
+        let tokenize_options = tokenizer::TokenizeOptions::default();
+        let tokens =
+            tokenizer::tokenize_messages(&simple_messages, &tokenize_options);
+        let tokens = tokenizer::filter_stop_words(
+            tokens,
+            &tokenizer::get_russian_stopwords(),
+            tokenizer::CaseLocale::Default,
+        );
+        let tokens =
+            tokenizer::stem_tokens(tokens, &["en".to_string()], &HashSet::new());
+        let word_counts = tokenizer::count_words(&tokens);
+
+        assert_eq!(word_counts["checker"], 3);
+        assert_eq!(word_counts["borrow"], 2);
+        assert_eq!(word_counts["rust"], 2);
+        assert_eq!(word_counts["strict"], 2);
+        assert_eq!(word_counts["build"], 1);
+        assert_eq!(word_counts["раст"], 1);
+        assert!(!word_counts.contains_key("https"));
+        assert!(!word_counts.contains_key("это"));
+        assert!(!word_counts.contains_key("is"));
+        assert_eq!(word_counts.values().sum::<usize>(), 28);
+    }
+
+    #[test]
+    fn streaming_counts_match_batch() {
+        let simplify_options = parse::SimplifyOptions::default();
+        let tokenize_options = tokenizer::TokenizeOptions {
+            min_length: 1,
+            ..Default::default()
+        };
+
+        let (messages, batch_stats) =
+            parse::read_messages(fixture("export.json"), &Default::default())
+                .unwrap();
+        let simple_messages =
+            parse::simplify_messages(&messages, &simplify_options);
+        let tokens =
+            tokenizer::tokenize_messages(&simple_messages, &tokenize_options);
+        let batch_counts = tokenizer::count_words(&tokens);
+
+        let (stream_counts, stream_stats) = stream::count_words(
+            &[fixture("export.json")],
+            &Default::default(),
+            |message, _| match parse::simplify_message(message, &simplify_options)
+            {
+                Some(message) => tokenizer::tokenize_messages(
+                    std::slice::from_ref(&message),
+                    &tokenize_options,
+                ),
+                None => Vec::new(),
+            },
+        )
+        .unwrap();
+
+        assert!(!batch_counts.is_empty());
+        assert_eq!(stream_counts, batch_counts);
+        assert_eq!(stream_stats.parsed, batch_stats.parsed);
+        assert_eq!(stream_stats.failed, 0);
+    }
+
+    #[test]
+    fn chat_type_from_fixture_header() {
+        let (_, stats) =
+            parse::read_messages(fixture("export.json"), &Default::default())
+                .unwrap();
+        let header = stats.header.unwrap();
+        assert_eq!(header.name.as_deref(), Some("Rustaceans"));
+        assert_eq!(header.id, Some(1000000001));
+        assert_eq!(
+            header.chat_type(),
+            Some(schema::ChatType::PrivateSupergroup)
+        );
+
+        // Streaming reads the same header in its single pass
+        let (_, stats) = stream::count_words(
+            &[fixture("export.json")],
+            &Default::default(),
+            |_, _| Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            stats.header.unwrap().chat_type(),
+            Some(schema::ChatType::PrivateSupergroup)
+        );
+    }
+
+    #[test]
+    fn mixed_text_array_keeps_spacing() {
+        let message: parse::Message = serde_json::from_value(serde_json::json!({
+            "id": 1,
+            "type": "message",
+            "from": "Alice",
+            "text": ["hello ", {"type": "bold", "text": "world"}, "!"],
+        }))
+        .unwrap();
+        let options = parse::SimplifyOptions::default();
+        assert_eq!(
+            parse::extract_message_text(&message, &options).0,
+            "hello world!"
+        );
+
+        // Parts are joined verbatim, the spacing is in the text itself: a
+        // bold run typed without a space around it is part of the word
+        let glued: parse::Message = serde_json::from_value(serde_json::json!({
+            "id": 2,
+            "type": "message",
+            "from": "Alice",
+            "text": ["hello", {"type": "bold", "text": "world"}, "!"],
+        }))
+        .unwrap();
+        assert_eq!(
+            parse::extract_message_text(&glued, &options).0,
+            "helloworld!"
+        );
+
+        // Formatting can start or end mid-word
+        let split: parse::Message = serde_json::from_value(serde_json::json!({
+            "id": 3,
+            "type": "message",
+            "from": "Alice",
+            "text": [{"type": "bold", "text": "Hel"}, "lo ", "world"],
+        }))
+        .unwrap();
+        assert_eq!(
+            parse::extract_message_text(&split, &options).0,
+            "Hello world"
+        );
+
+        let simple_messages =
+            parse::simplify_messages(&[message, split], &options);
+        let tokenize_options = tokenizer::TokenizeOptions {
+            min_length: 1,
+            ..Default::default()
+        };
+        let words: Vec<String> =
+            tokenizer::tokenize_messages(&simple_messages, &tokenize_options)
+                .into_iter()
+                .map(|token| token.word)
+                .collect();
+        assert_eq!(words, ["hello", "world", "hello", "world"]);
+    }
+
+    #[test]
+    fn forward_source_names_are_excluded() {
+        let input = write_export(
+            "forwarded",
+            serde_json::json!([
+                {
+                    "id": 1,
+                    "type": "message",
+                    "date": "2024-03-01T09:00:00",
+                    "date_unixtime": "1709283600",
+                    "from": "Alice",
+                    "forwarded_from": "Ferris Digest",
+                    "text": "Ferris Digest weekly news",
+                },
+                {
+                    "id": 2,
+                    "type": "message",
+                    "date": "2024-03-01T09:01:00",
+                    "date_unixtime": "1709283660",
+                    "from": "Bob",
+                    "text": "Digest of the news",
+                },
+            ]),
+        );
+
+        let counted = count_with(&input, &[]);
+        assert_eq!(counted.word_counts["digest"], 2);
+
+        for mode in [&[][..], &["--streaming"]] {
+            let options = [&["--exclude-forward-source-names"], mode].concat();
+            let counted = count_with(&input, &options);
+            let words = &counted.word_counts;
+            assert!(!words.contains_key("digest"), "{:?}: {:?}", mode, words);
+            assert!(!words.keys().any(|word| word.starts_with("ferri")));
+            assert_eq!(words["news"], 2);
+            assert_eq!(words["week"], 1);
+        }
+    }
+
+    #[test]
+    fn parse_log_records_offsets() {
+        let content = concat!(
+            r#"{"messages": ["#,
+            r#"{"id": 1, "type": "message", "date_unixtime": "1704103200", "text": "fine"}, "#,
+            r#"{"id": "two", "type": "message", "text": "bad id"}, "#,
+            r#"{"id": 3, "type": "message", "date_unixtime": "1704103300", "text": "fine"}"#,
+            "]}"
+        );
+        let input = temp_path("malformed.json");
+        std::fs::write(&input, content).unwrap();
+
+        let (messages, stats) =
+            parse::read_messages(&input, &Default::default()).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(stats.failed, 1);
+
+        let log = input.with_extension("log");
+        print_parse_stats(&stats, Some(&log)).unwrap();
+        let logged = std::fs::read_to_string(&log).unwrap();
+        let offset = content.find(r#"{"id": "two""#).unwrap();
+        let expected = format!("{}: byte {}: ", input.display(), offset);
+        assert_eq!(logged.lines().count(), 1);
+        assert!(logged.starts_with(&expected), "{}", logged);
+
+        let strict = parse::ReadOptions {
+            strict: true,
+            ..Default::default()
+        };
+        assert!(parse::read_messages(&input, &strict).is_err());
+    }
+
+    #[test]
+    fn emphasized_words_weigh_more() {
+        let input = write_export(
+            "emphasized",
+            serde_json::json!([
+                {
+                    "id": 1,
+                    "type": "message",
+                    "date_unixtime": "1709283600",
+                    "from": "Alice",
+                    "text": ["rust is fast, ", {"type": "bold", "text": "rust"}],
+                },
+                {
+                    "id": 2,
+                    "type": "message",
+                    "date_unixtime": "1709283660",
+                    "from": "Bob",
+                    "text": [{"type": "italic", "text": "Hel"}, "lo world"],
+                },
+            ]),
+        );
+
+        // The bold occurrence is the marked one, not the first
+        let (messages, _) =
+            parse::read_messages(&input, &Default::default()).unwrap();
+        let options = parse::SimplifyOptions::default();
+        let simple_messages = parse::simplify_messages(&messages, &options);
+        let args =
+            Args::try_parse_from(["tg-dump-word-cloud", "-i", "x"]).unwrap();
+        let counter = WordCounter::new(&args).unwrap();
+        let tokens = tokenizer::tokenize_messages(
+            &simple_messages,
+            &counter.tokenize_options,
+        );
+        let marked: Vec<(&str, bool)> = tokens
+            .iter()
+            .map(|token| (token.word.as_str(), token.emphasized))
+            .collect();
+        assert_eq!(
+            marked,
+            [
+                ("rust", false),
+                ("fast", false),
+                ("rust", true),
+                ("hello", true),
+                ("world", false)
+            ]
+        );
+
+        let counted = count_with(&input, &["--weight-emphasized", "3"]);
+        let weights = counted.token_weights.unwrap();
+        assert_eq!(weights["rust"], 4.0);
+        assert_eq!(weights["fast"], 1.0);
+        assert_eq!(weights["hello"], 3.0);
+        assert_eq!(weights["world"], 1.0);
+    }
+
+    #[test]
+    fn merged_words_keep_their_weight() {
+        let message = |id: i64, text: serde_json::Value| {
+            serde_json::json!({
+                "id": id,
+                "type": "message",
+                "date_unixtime": "1709283600",
+                "from": "Alice",
+                "text": text,
+            })
+        };
+        let bold =
+            |text: &str| serde_json::json!([{"type": "bold", "text": text}]);
+        let input = write_export(
+            "merged-weights",
+            serde_json::json!([
+                message(1, "borrowchecker".into()),
+                message(2, "borrowchecker".into()),
+                message(3, bold("borowchecker")),
+                message(4, bold("loud")),
+            ]),
+        );
+
+        let counted = count_with(
+            &input,
+            &[
+                "--merge-similar",
+                "1",
+                "--weight-emphasized",
+                "3",
+                "--min-count-percent",
+                "50",
+            ],
+        );
+        // The typo's weight moves to the word it is merged into
+        let weights = counted.token_weights.unwrap();
+        assert_eq!(weights.len(), 2);
+        assert_eq!(weights["borrowcheck"], 5.0);
+        // By count "loud" is under half of the top word, by weight it isn't
+        assert_eq!(weights["loud"], 3.0);
+        assert_eq!(counted.word_counts["borrowcheck"], 3);
+        assert_eq!(counted.word_counts["loud"], 1);
+    }
+
+    #[test]
+    fn stem_map_lists_most_frequent_form_first() {
+        let input = write_export(
+            "stem-map",
+            serde_json::json!([
+                text_message(1, "Alice", "running late"),
+                text_message(2, "Bob", "running again, she runs"),
+                text_message(3, "Alice", "runs"),
+                text_message(4, "Bob", "running"),
+            ]),
+        );
+        let stem_map = temp_path("stem-map.txt");
+
+        count_with(&input, &["--stem-map-out", stem_map.to_str().unwrap()]);
+        let lines = std::fs::read_to_string(&stem_map).unwrap();
+        assert_eq!(lines.lines().next(), Some("run -> running:3, runs:2"));
+    }
+
+    #[test]
+    fn polls_counted_with_include_polls() {
+        let (messages, stats) =
+            parse::read_messages(fixture("poll.json"), &Default::default())
+                .unwrap();
+        assert_eq!(stats.failed, 0);
+        let poll = messages[1].poll.as_ref().unwrap();
+        assert_eq!(poll.total_voters, 3);
+        assert_eq!(poll.answers.len(), 2);
+        assert!(poll.answers[0].chosen);
+
+        let counted = count_with(&fixture("poll.json"), &[]);
+        assert!(!counted.word_counts.contains_key("helix"));
+
+        let counted = count_with(&fixture("poll.json"), &["--include-polls"]);
+        let mut words: Vec<&str> =
+            counted.word_counts.keys().map(String::as_str).collect();
+        words.sort_unstable();
+        assert_eq!(
+            words,
+            ["editor", "emac", "favourit", "helix", "pleas", "vote"]
+        );
+    }
+
+    #[test]
+    fn input_dir_reads_parts_in_order() {
+        let dir = temp_path("split-export");
+        std::fs::create_dir_all(&dir).unwrap();
+        let part = |name: &str, messages: serde_json::Value| {
+            let export = serde_json::json!({
+                "name": "Test chat",
+                "type": "private_group",
+                "id": 1,
+                "messages": messages,
+            });
+            std::fs::write(dir.join(name), export.to_string()).unwrap();
+        };
+        part(
+            "messages.json",
+            serde_json::json!([text_message(1, "Alice", "first part")]),
+        );
+        part(
+            "messages2.json",
+            serde_json::json!([text_message(2, "Bob", "second part")]),
+        );
+        std::fs::write(dir.join("photos.json"), "not an export").unwrap();
+
+        let parts = parse::find_export_parts(&dir).unwrap();
+        let names: Vec<_> =
+            parts.iter().map(|path| path.file_name().unwrap()).collect();
+        assert_eq!(names, ["messages.json", "messages2.json"]);
+
+        let args = Args::try_parse_from([
+            "tg-dump-word-cloud",
+            "--input-dir",
+            dir.to_str().unwrap(),
+        ])
+        .unwrap();
+        let counted = WordCounter::new(&args)
+            .unwrap()
+            .count(&parts, true)
+            .unwrap();
+        assert_eq!(counted.word_counts["part"], 2);
+        assert_eq!(counted.word_counts["first"], 1);
+        assert_eq!(counted.word_counts["second"], 1);
+        assert_eq!(counted.header.name.as_deref(), Some("Test chat"));
+    }
+
+    #[test]
+    fn case_variants_merge_unless_no_lowercase() {
+        let input = write_export(
+            "case",
+            serde_json::json!([
+                text_message(1, "Alice", "Rust"),
+                text_message(2, "Bob", "rust RUST"),
+            ]),
+        );
+
+        let counted = count_with(&input, &[]);
+        assert_eq!(counted.word_counts.len(), 1);
+        assert_eq!(counted.word_counts["rust"], 3);
+
+        let counted = count_with(&input, &["--no-lowercase"]);
+        assert_eq!(counted.word_counts.len(), 3);
+        assert_eq!(counted.word_counts["Rust"], 1);
+    }
+
+    #[test]
+    fn min_df_drops_words_from_a_single_message() {
+        let input = write_export(
+            "min-df",
+            serde_json::json!([
+                text_message(1, "Alice", "cargo cargo cargo build"),
+                text_message(2, "Bob", "cargo test"),
+            ]),
+        );
+
+        let counted = count_with(&input, &["--min-df", "2"]);
+        // Repeats within one message don't count as more messages
+        assert_eq!(counted.word_counts.len(), 1);
+        assert_eq!(counted.word_counts["cargo"], 4);
+    }
+
+    #[test]
+    fn bash_completions_list_flags() {
+        // No input needed just to print completions
+        let args = Args::try_parse_from([
+            "tg-dump-word-cloud",
+            "--generate-completions",
+            "bash",
+        ])
+        .unwrap();
+        let shell = args.generate_completions.unwrap();
+        assert_eq!(shell, clap_complete::Shell::Bash);
+
+        let mut script = Vec::new();
+        clap_complete::generate(
+            shell,
+            &mut Args::command(),
+            "tg-dump-word-cloud",
+            &mut script,
+        );
+        let script = String::from_utf8(script).unwrap();
+        assert!(script.contains("tg-dump-word-cloud"));
+        assert!(script.contains("--input-dir"));
+        assert!(script.contains("--max-words"));
+    }
+
+    #[test]
+    fn excluded_ids_are_not_counted() {
+        let input = write_export(
+            "exclude-ids",
+            serde_json::json!([
+                text_message(1, "Alice", "alpha"),
+                text_message(2, "Bob", "bravo"),
+                text_message(3, "Carol", "charlie"),
+            ]),
+        );
+        let ids_file = temp_path("exclude-ids.txt");
+        std::fs::write(&ids_file, "# spam\n3\n").unwrap();
+
+        for mode in [&[][..], &["--streaming"]] {
+            let options = [
+                &[
+                    "--exclude-ids",
+                    "1",
+                    "--exclude-ids-file",
+                    ids_file.to_str().unwrap(),
+                ],
+                mode,
+            ]
+            .concat();
+            let counted = count_with(&input, &options);
+            let words: Vec<&String> = counted.word_counts.keys().collect();
+            assert_eq!(words, ["bravo"], "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn chat_name_becomes_file_name() {
+        assert_eq!(
+            default_output(Some("Rust / Chat: 2024!")),
+            Path::new("Rust_Chat_2024.png")
+        );
+        assert_eq!(
+            default_output(Some("Растаманы")),
+            Path::new("Растаманы.png")
+        );
+        assert_eq!(default_output(Some("../..")), Path::new("wordcloud.png"));
+        assert_eq!(default_output(None), Path::new("wordcloud.png"));
+    }
+
+    #[test]
+    fn date_range_limits_counted_messages() {
+        // One message a day, March 1st to 4th 2024 at noon UTC
+        let messages: Vec<serde_json::Value> = (1..=4)
+            .map(|day| {
+                serde_json::json!({
+                    "id": day,
+                    "type": "message",
+                    "date": format!("2024-03-0{}T12:00:00", day),
+                    "date_unixtime": (1709294400 + (day - 1) * 86400).to_string(),
+                    "from": "Alice",
+                    "text": format!("day{}", day),
+                })
+            })
+            .collect();
+        let input = write_export("date-range", messages.into());
+
+        let counted = count_with(
+            &input,
+            &["--from-date", "2024-03-02", "--to-date", "2024-03-03"],
+        );
+        let mut words: Vec<&String> = counted.word_counts.keys().collect();
+        words.sort();
+        assert_eq!(words, ["day2", "day3"]);
+
+        let time_range = &counted.summary.time_range;
+        assert_eq!(time_range.first, Some(1709294400 + 86400));
+        assert_eq!(time_range.last, Some(1709294400 + 2 * 86400));
+        assert_eq!(time_range.span_days(), Some(1.0));
+        assert_eq!(
+            time::format_datetime(time_range.first.unwrap(), 0),
+            "2024-03-02 12:00"
+        );
+    }
+
+    #[test]
+    fn min_senders_keeps_words_shared_by_users() {
+        let input = write_export(
+            "min-senders",
+            serde_json::json!([
+                text_message(1, "Alice", "tokio tokio async"),
+                text_message(2, "Alice", "tokio again"),
+                text_message(3, "Bob", "async rust"),
+                text_message(4, "Carol", "async"),
+            ]),
+        );
+
+        let counted = count_with(&input, &["--min-senders", "2"]);
+        let words: Vec<&String> = counted.word_counts.keys().collect();
+        assert_eq!(words, ["async"]);
+        assert_eq!(counted.word_counts["async"], 3);
+
+        let counted = count_with(&input, &["--min-senders", "3"]);
+        assert_eq!(counted.word_counts.len(), 1);
+        let counted = count_with(&input, &["--min-senders", "4"]);
+        assert!(counted.word_counts.is_empty());
+    }
+
+    #[test]
+    fn strict_dates_fails_on_unreadable_date() {
+        let input = write_export(
+            "bad-date",
+            serde_json::json!([
+                {
+                    "id": 7,
+                    "type": "message",
+                    "date": "yesterday",
+                    "date_unixtime": "1709283600",
+                    "from": "Alice",
+                    "text": "undated",
+                },
+            ]),
+        );
+        let window = ["--from-date", "2024-01-01"];
+
+        // Kept by the date window unless strict
+        let counted = count_with(&input, &window);
+        assert_eq!(counted.word_counts["undat"], 1);
+
+        for mode in [&[][..], &["--streaming"]] {
+            let mut command_line = vec![
+                "tg-dump-word-cloud",
+                "-i",
+                input.to_str().unwrap(),
+                "--strict-dates",
+            ];
+            command_line.extend(window);
+            command_line.extend(mode);
+            let args = Args::try_parse_from(command_line).unwrap();
+            let error = WordCounter::new(&args)
+                .unwrap()
+                .count(&args.input, true)
+                .err()
+                .unwrap();
+            assert!(
+                format!("{:#}", error)
+                    .contains("message 7 has an unreadable date 'yesterday'"),
+                "{:?}: {:#}",
+                mode,
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn stop_lists_of_both_languages() {
+        let input = write_export(
+            "bilingual",
+            serde_json::json!([text_message(1, "Alice", "the cats и кошки")]),
+        );
+        let sorted = |counted: Counted| {
+            let mut words: Vec<String> =
+                counted.word_counts.into_keys().collect();
+            words.sort();
+            words
+        };
+
+        // The Russian list always applies, the English one on request
+        let counted = count_with(&input, &["--lang", "en,ru"]);
+        assert_eq!(sorted(counted), ["cat", "the", "кошк"]);
+
+        let counted =
+            count_with(&input, &["--lang", "en,ru", "--merge-stopword-lists"]);
+        assert_eq!(sorted(counted), ["cat", "кошк"]);
+    }
+
+    #[test]
+    fn cyrillic_words_and_characters_counted() {
+        let input = write_export(
+            "cyrillic",
+            serde_json::json!([
+                text_message(1, "Алиса", "Привет, мир! Ёжик"),
+                text_message(2, "Боб", "привет ёжик"),
+            ]),
+        );
+
+        let counted = count_with(&input, &["--lang", "ru"]);
+        let mut words: Vec<(&str, usize)> = counted
+            .word_counts
+            .iter()
+            .map(|(word, count)| (word.as_str(), *count))
+            .collect();
+        words.sort();
+        assert_eq!(words, [("мир", 1), ("привет", 2), ("ёжик", 2)]);
+
+        let (messages, _) =
+            parse::read_messages(&input, &Default::default()).unwrap();
+        let simple = parse::simplify_messages(&messages, &Default::default());
+        let chars = stats::char_frequencies(&simple, true);
+        // Counted per character, not per byte, and case-sensitively
+        assert_eq!(chars[0], ('и', 5));
+        assert!(chars.contains(&('Ё', 1)) && chars.contains(&('ё', 1)));
+        assert!(!chars.iter().any(|(c, _)| !c.is_alphanumeric()));
+        assert_eq!(chars.iter().map(|(_, count)| count).sum::<usize>(), 23);
+    }
+
+    #[test]
+    fn cp1251_roundtrip() {
+        // An export saved as Windows-1251 is detected and decoded
+        let export = serde_json::json!({
+            "name": "Чат",
+            "messages": [text_message(1, "Алиса", "привет привет мир")],
+        });
+        let export = export.to_string();
+        let (bytes, _, unmappable) = encoding_rs::WINDOWS_1251.encode(&export);
+        assert!(!unmappable);
+        let input = temp_path("cp1251.json");
+        std::fs::write(&input, &bytes).unwrap();
+        let counted = count_with(&input, &["--lang", "ru"]);
+        assert_eq!(counted.word_counts["привет"], 2);
+
+        // And the counts are written back in it
+        let dump = temp_path("cp1251.txt");
+        let words = [("привет".to_string(), 2.0), ("мир".to_string(), 1.5)];
+        save_word_counts_for_python(
+            &words,
+            None,
+            &dump,
+            encoding_rs::WINDOWS_1251,
+            false,
+        )
+        .unwrap();
+        let written = std::fs::read(&dump).unwrap();
+        assert!(std::str::from_utf8(&written).is_err());
+        let (text, had_errors) =
+            encoding_rs::WINDOWS_1251.decode_without_bom_handling(&written);
+        assert!(!had_errors);
+        assert_eq!(text, "привет 2\nмир 1.50\n");
+    }
+
+    #[test]
+    fn one_thread_counts_the_same() {
+        let inputs: Vec<PathBuf> = (1..=4)
+            .map(|part| {
+                write_export(
+                    &format!("threads-{}", part),
+                    serde_json::json!([
+                        text_message(part, "Alice", "rust borrow checker"),
+                        text_message(
+                            part + 10,
+                            "Bob",
+                            format!("rust part{}", part)
+                        ),
+                    ]),
+                )
+            })
+            .collect();
+        let mut command_line = vec!["tg-dump-word-cloud", "--threads", "1"];
+        for input in &inputs {
+            command_line.extend(["-i", input.to_str().unwrap()]);
+        }
+        let args = Args::try_parse_from(command_line).unwrap();
+        assert_eq!(args.threads, 1);
+
+        // The files are read on the pool main sets up from --threads
+        let count = || {
+            WordCounter::new(&args)
+                .unwrap()
+                .count(&args.input, true)
+                .unwrap()
+                .word_counts
+        };
+        let single = rayon::ThreadPoolBuilder::new()
+            .num_threads(args.threads)
+            .build()
+            .unwrap()
+            .install(count);
+        assert_eq!(single, count());
+        assert_eq!(single["rust"], 8);
+        assert_eq!(single["part3"], 1);
+    }
+
+    #[test]
+    fn from_id_resolved_to_participant_name() {
+        let id_only = |id: i64, from_id: &str| {
+            let mut message = text_message(id, "", "hello");
+            let fields = message.as_object_mut().unwrap();
+            fields.remove("from");
+            fields.insert("from_id".to_string(), from_id.into());
+            message
+        };
+        let export = serde_json::json!({
+            "name": "Test chat",
+            "type": "private_group",
+            "id": 1,
+            "participants": [{"id": 12345, "name": "Alice"}],
+            "contacts": {"list": [
+                {"user_id": 678, "first_name": "Bob", "last_name": "Smith"},
+            ]},
+            "messages": [
+                id_only(1, "user12345"),
+                id_only(2, "user678"),
+                id_only(3, "user999"),
+            ],
+        });
+        let input = temp_path("participants.json");
+        std::fs::write(&input, export.to_string()).unwrap();
+
+        // Ids missing from the lists are kept as they are
+        let counted = count_with(&input, &[]);
+        let mut participants: Vec<_> =
+            counted.summary.participants.into_iter().collect();
+        participants.sort();
+        assert_eq!(participants, ["Alice", "Bob Smith", "user999"]);
+    }
+
+    #[test]
+    fn repeated_url_counted_as_one_token() {
+        let link = "https://github.com/rust-lang/rust/issues?q=label%3AE-easy";
+        let input = write_export(
+            "urls",
+            serde_json::json!([
+                text_message(1, "Alice", format!("look {}", link)),
+                text_message(2, "Bob", format!("({})", link)),
+                text_message(3, "Carol", format!("{}, again", link)),
+            ]),
+        );
+
+        let counted = count_with(&input, &["--dedupe-urls-as-single-token"]);
+        assert_eq!(counted.word_counts[link], 3);
+        assert!(!counted.word_counts.contains_key("github"));
+
+        // Otherwise the link is split into words
+        let counted = count_with(&input, &[]);
+        assert!(!counted.word_counts.contains_key(link));
+        assert_eq!(counted.word_counts["github"], 3);
+    }
+
+    #[test]
+    fn stem_exceptions_keep_their_form() {
+        let input = write_export(
+            "stem-exceptions",
+            serde_json::json!([text_message(
+                1,
+                "Alice",
+                "Kubernetes clusters running kubernetes"
+            )]),
+        );
+        let exceptions = temp_path("stem-exceptions.txt");
+        std::fs::write(&exceptions, "Kubernetes\n").unwrap();
+        let sorted = |counted: Counted| {
+            let mut words: Vec<(String, usize)> =
+                counted.word_counts.into_iter().collect();
+            words.sort();
+            words
+        };
+
+        let counted = count_with(&input, &["--lang", "en"]);
+        assert_eq!(
+            sorted(counted),
+            [
+                ("cluster".into(), 1),
+                ("kubernet".into(), 2),
+                ("run".into(), 1)
+            ]
+        );
+
+        let counted = count_with(
+            &input,
+            &[
+                "--lang",
+                "en",
+                "--stem-exceptions-file",
+                exceptions.to_str().unwrap(),
+            ],
+        );
+        assert_eq!(
+            sorted(counted),
+            [
+                ("cluster".into(), 1),
+                ("kubernetes".into(), 2),
+                ("run".into(), 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn saved_messages_fixture_counted_as_owner() {
+        let input = fixture("saved_messages.json");
+        let participants = |counted: &Counted| {
+            let mut names: Vec<_> =
+                counted.summary.participants.iter().cloned().collect();
+            names.sort();
+            names
+        };
+
+        let counted = count_with(&input, &[]);
+        assert_eq!(
+            counted.header.chat_type(),
+            Some(schema::ChatType::SavedMessages)
+        );
+        assert_eq!(counted.header.name, None);
+        assert_eq!(participants(&counted), ["Bob", "Me"]);
+        assert_eq!(counted.word_counts["todo"], 2);
+        assert_eq!(counted.word_counts["async"], 2);
+
+        // Saved forwards count as the owner's too, and the nameless export
+        // falls back to "Saved Messages"
+        let counted = count_with(&input, &["--self-chat"]);
+        assert_eq!(participants(&counted), ["Saved Messages"]);
+        assert_eq!(counted.word_counts["async"], 2);
+    }
+
+    #[test]
+    fn nested_output_dir_created() {
+        let root = temp_path("nested-output");
+        let _ = std::fs::remove_dir_all(&root);
+        let output = root.join("clouds/2024/march.png");
+        create_output_dir(&output).unwrap();
+        assert!(root.join("clouds/2024").is_dir());
+        // Existing directories and bare file names are fine
+        create_output_dir(&output).unwrap();
+        create_output_dir(Path::new("cloud.png")).unwrap();
+
+        // A file in the way names the directory that can't be made
+        std::fs::write(root.join("taken"), "").unwrap();
+        let blocked = root.join("taken/cloud.png");
+        let error = create_output_dir(&blocked).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Failed to create output directory {}",
+                root.join("taken").display()
+            )
+        );
+    }
+
+    #[test]
+    fn words_below_one_percent_of_top_dropped() {
+        let input = write_export(
+            "count-percent",
+            serde_json::json!([
+                text_message(1, "Alice", "rust ".repeat(250)),
+                text_message(
+                    2,
+                    "Bob",
+                    "borrow borrow checker checker checker crab"
+                ),
+            ]),
+        );
+        let counted = count_with(&input, &["--min-count-percent", "1"]);
+        // 1% of 250 rounds up to a floor of 3
+        let mut words: Vec<(String, usize)> =
+            counted.word_counts.into_iter().collect();
+        words.sort();
+        assert_eq!(words, [("checker".into(), 3), ("rust".into(), 250)]);
+
+        let counted = count_with(&input, &[]);
+        assert_eq!(counted.word_counts.len(), 4);
+    }
+
+    #[test]
+    fn flood_collapsed_on_both_diff_sides() {
+        let spam = "buy cheap coins now";
+        // Messages are a minute apart, inside the default 60 s window
+        let input = write_export(
+            "flood",
+            serde_json::json!([
+                text_message(1, "Bob", spam),
+                text_message(2, "Bob", spam),
+                text_message(3, "Bob", "BUY cheap coins now today"),
+                text_message(4, "Bob", spam),
+                text_message(5, "Alice", spam),
+                text_message(7, "Bob", spam),
+            ]),
+        );
+
+        let counted = count_with(&input, &[]);
+        assert_eq!(counted.word_counts["coin"], 6);
+
+        // Bob's flood keeps its first message, Alice and Bob's later
+        // message are outside it
+        let args = Args::try_parse_from([
+            "tg-dump-word-cloud",
+            "-i",
+            input.to_str().unwrap(),
+            "--despam",
+            "--diff",
+            input.to_str().unwrap(),
+        ])
+        .unwrap();
+        let counter = WordCounter::new(&args).unwrap();
+        let primary = counter.count(&args.input, true).unwrap();
+        assert_eq!(primary.word_counts["coin"], 3);
+        assert!(!primary.word_counts.contains_key("today"));
+        let other = counter
+            .count(std::slice::from_ref(args.diff.as_ref().unwrap()), false)
+            .unwrap();
+        assert_eq!(other.word_counts, primary.word_counts);
+    }
+
+    #[test]
+    fn unknown_users_relabeled_or_excluded() {
+        let input = fixture("unknown_users.json");
+        let participants = |counted: Counted| {
+            let mut names: Vec<String> =
+                counted.summary.participants.into_iter().collect();
+            names.sort();
+            names
+        };
+
+        let counted = count_with(&input, &[]);
+        assert_eq!(counted.word_counts["ghost"], 2);
+        assert_eq!(participants(counted), ["Alice", "anonymous"]);
+
+        let counted = count_with(&input, &["--unknown-user-label", "Ghost"]);
+        assert_eq!(participants(counted), ["Alice", "Ghost"]);
+
+        let counted = count_with(&input, &["--exclude-unknown-users"]);
+        assert!(!counted.word_counts.contains_key("ghost"));
+        assert_eq!(counted.word_counts["hello"], 1);
+        assert_eq!(participants(counted), ["Alice"]);
+    }
+
+    #[test]
+    fn several_inputs_count_the_sum_of_each() {
+        let first = fixture("export.json");
+        let second = write_export(
+            "second-input",
+            serde_json::json!([
+                text_message(1, "Carol", "rust borrow checker again"),
+                text_message(2, "Dan", "cargo clippy"),
+            ]),
+        );
+
+        let mut expected = count_with(&first, &[]).word_counts;
+        for (word, count) in count_with(&second, &[]).word_counts {
+            *expected.entry(word).or_insert(0) += count;
+        }
+        let merged =
+            count_with(&first, &["-i", second.to_str().unwrap()]).word_counts;
+        assert_eq!(merged, expected);
+        assert_eq!(merged["checker"], 4);
+        assert_eq!(merged["clippi"], 1);
+    }
+
+    #[test]
+    fn only_nouns_kept_with_pos_noun() {
+        let input = fixture("pos.json");
+        let sorted = |counted: Counted| {
+            let mut words: Vec<String> =
+                counted.word_counts.into_keys().collect();
+            words.sort();
+            words
+        };
+
+        let counted = count_with(&input, &["--lang", "en,ru"]);
+        assert_eq!(
+            sorted(counted),
+            [
+                "file",
+                "pars",
+                "parser",
+                "use",
+                "книг",
+                "компилятор",
+                "лежа",
+                "нов",
+                "собира",
+                "стар"
+            ]
+        );
+
+        // Tagged before stemming: "parser" and "files" are nouns, "parsing"
+        // a verb and "useful" an adjective
+        let counted = count_with(&input, &["--lang", "en,ru", "--pos", "noun"]);
+        assert_eq!(sorted(counted), ["file", "parser", "книг", "компилятор"]);
+    }
+
+    #[test]
+    fn min_length_two_keeps_short_words() {
+        let input = write_export(
+            "min-length",
+            serde_json::json!([text_message(1, "Alice", "go vim rust x")]),
+        );
+        let sorted = |counted: Counted| {
+            let mut words: Vec<String> =
+                counted.word_counts.into_keys().collect();
+            words.sort();
+            words
+        };
+
+        // Not clamped to 4 in either counting path
+        for mode in [&[][..], &["--streaming"]] {
+            let mut options = vec!["--min-length", "2"];
+            options.extend(mode);
+            assert_eq!(
+                sorted(count_with(&input, &options)),
+                ["go", "rust", "vim"]
+            );
+        }
+        assert_eq!(sorted(count_with(&input, &[])), ["rust", "vim"]);
+    }
+
+    #[test]
+    fn inflected_stop_word_removed_with_stem_stopwords() {
+        let input = write_export(
+            "stem-stopwords",
+            serde_json::json!([text_message(
+                1,
+                "Alice",
+                "clusters cluster crab"
+            )]),
+        );
+        let options = ["--lang", "en", "--stop-words", "cluster"];
+
+        // The plural slips past the surface list and stems into the word
+        let counted = count_with(&input, &options);
+        assert_eq!(counted.word_counts["cluster"], 1);
+
+        let mut stemmed = options.to_vec();
+        stemmed.push("--stem-stopwords");
+        let counted = count_with(&input, &stemmed);
+        assert!(!counted.word_counts.contains_key("cluster"));
+        assert_eq!(counted.word_counts["crab"], 1);
+    }
+
+    #[test]
+    fn one_word_message_excluded_with_min_words_per_message() {
+        let input = write_export(
+            "min-words",
+            serde_json::json!([
+                text_message(1, "Alice", "thanks"),
+                text_message(2, "Bob", "rust compiles slowly"),
+            ]),
+        );
+
+        for mode in [&[][..], &["--streaming"]] {
+            let mut options = vec!["--min-words-per-message", "2"];
+            options.extend(mode);
+            let counted = count_with(&input, &options);
+            assert!(!counted.word_counts.contains_key("thank"));
+            assert_eq!(counted.word_counts["rust"], 1);
+            assert_eq!(counted.funnel.with_text, 1);
+        }
+        let counted = count_with(&input, &[]);
+        assert_eq!(counted.word_counts["thank"], 1);
+    }
+
+    #[test]
+    fn corpus_out_matches_counted_tokens() {
+        let input = write_export(
+            "corpus",
+            serde_json::json!([
+                text_message(1, "Alice", "Rust compilers compile Rust"),
+                text_message(2, "Bob", "the borrow checker"),
+            ]),
+        );
+        let corpus_path = temp_path("corpus.txt");
+
+        let counted =
+            count_with(&input, &["--corpus-out", corpus_path.to_str().unwrap()]);
+        let corpus = std::fs::read_to_string(&corpus_path).unwrap();
+        std::fs::remove_file(&corpus_path).unwrap();
+
+        assert_eq!(corpus.lines().count(), 2);
+        let mut corpus_counts = HashMap::new();
+        for word in corpus.split_whitespace() {
+            *corpus_counts.entry(word.to_string()).or_insert(0) += 1;
+        }
+        assert_eq!(corpus_counts, counted.word_counts);
+    }
+
+    #[test]
+    fn identical_consecutive_messages_counted_once_with_dedup() {
+        let input = write_export(
+            "dedup",
+            serde_json::json!([
+                text_message(1, "Bot", "Free crypto giveaway"),
+                text_message(2, "Bot", "free  crypto GIVEAWAY"),
+                text_message(3, "Bot", "Free crypto giveaway "),
+                text_message(4, "Alice", "crypto again"),
+                text_message(5, "Bot", "Free crypto giveaway"),
+            ]),
+        );
+
+        // The run of three counts once, the repeat after Alice again
+        let counted = count_with(&input, &["--dedup-messages"]);
+        assert_eq!(counted.word_counts["giveaway"], 2);
+        assert_eq!(counted.word_counts["crypto"], 3);
+
+        let counted = count_with(&input, &[]);
+        assert_eq!(counted.word_counts["giveaway"], 4);
+    }
+
+    #[test]
+    fn quoted_text_left_out_with_strip_quotes() {
+        let input = write_export(
+            "strip-quotes",
+            serde_json::json!([
+                text_message(1, "Alice", "borrow checker complaints"),
+                text_message(
+                    2,
+                    "Bob",
+                    serde_json::json!([
+                        {"type": "blockquote", "text": "borrow checker complaints"},
+                        "\nlifetimes help",
+                    ])
+                ),
+            ]),
+        );
+
+        let counted = count_with(&input, &["--strip-quotes"]);
+        assert_eq!(counted.word_counts["borrow"], 1);
+        assert_eq!(counted.word_counts["lifetim"], 1);
+
+        let counted = count_with(&input, &[]);
+        assert_eq!(counted.word_counts["borrow"], 2);
+    }
+
+    #[test]
+    fn mixed_case_stop_word_filters_lowercased_tokens() {
+        let input = write_export(
+            "stop-word-case",
+            serde_json::json!([text_message(
+                1,
+                "Alice",
+                "привет ПРИВЕТ Rust мир"
+            )]),
+        );
+
+        let counted = count_with(&input, &["--stop-words", "Привет, RUST "]);
+        let words: Vec<&String> = counted.word_counts.keys().collect();
+        assert_eq!(words, ["мир"]);
+
+        // Tokens keep their case with --no-lowercase and still match
+        let counted = count_with(
+            &input,
+            &["--no-lowercase", "--stop-words", "Привет,RUST"],
+        );
+        let words: Vec<&String> = counted.word_counts.keys().collect();
+        assert_eq!(words, ["мир"]);
+    }
+
+    #[test]
+    fn topic_vocabulary_isolated() {
+        let input = fixture("forum.json");
+        let sorted = |counted: Counted| {
+            let mut words: Vec<String> =
+                counted.word_counts.into_keys().collect();
+            words.sort();
+            words
+        };
+
+        // Bob's reply to Alice stays in her topic
+        assert_eq!(
+            sorted(count_with(&input, &["--topic", "rust"])),
+            ["borrow", "checker", "everywher", "lifetim"]
+        );
+        assert_eq!(
+            sorted(count_with(&input, &["--topic", "General"])),
+            ["everyon", "welcom"]
+        );
+
+        let per_topic = temp_path("per-topic.txt");
+        count_with(&input, &["--per-topic", per_topic.to_str().unwrap()]);
+        let written = std::fs::read_to_string(&per_topic).unwrap();
+        std::fs::remove_file(&per_topic).unwrap();
+        assert_eq!(
+            written,
+            "Cooking: pasta:1, recip:1\n\
+             General: everyon:1, welcom:1\n\
+             Rust: borrow:1, checker:1, everywher:1, lifetim:1\n"
+        );
+    }
+
+    #[test]
+    fn funnel_counts_of_the_fixture() {
+        // The service message and the empty one have no text
+        let input = fixture("export.json");
+        for mode in [&[][..], &["--streaming"]] {
+            let counted = count_with(&input, mode);
+            let mut funnel = counted.funnel;
+            funnel.unique = counted.word_counts.len();
+            // Set after counting, as with --max-words 2
+            funnel.r#final = funnel.unique.min(2);
+
+            let funnel_path = temp_path("funnel.json");
+            export::save_funnel(&funnel, &funnel_path).unwrap();
+            let written: serde_json::Value = serde_json::from_str(
+                &std::fs::read_to_string(&funnel_path).unwrap(),
+            )
+            .unwrap();
+            std::fs::remove_file(&funnel_path).unwrap();
+            assert_eq!(
+                written,
+                serde_json::json!({
+                    "messages": 8,
+                    "with_text": 6,
+                    "tokens": 30,
+                    "after_stopwords": 28,
+                    "after_stem": 28,
+                    "unique": 21,
+                    "final": 2,
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn only_messages_with_a_term_kept_with_contains() {
+        let input = write_export(
+            "contains",
+            serde_json::json!([
+                text_message(1, "Alice", "Tokio runtime panics"),
+                text_message(2, "Bob", "lunch plans today"),
+                text_message(3, "Carol", "async runtimes compared"),
+                text_message(4, "Dan", "Borrowck errors again"),
+            ]),
+        );
+        let sorted = |counted: Counted| {
+            let mut words: Vec<String> =
+                counted.word_counts.into_keys().collect();
+            words.sort();
+            words
+        };
+
+        // Any term matches, in any case and inside longer words
+        for mode in [&[][..], &["--streaming"]] {
+            let mut options =
+                vec!["--contains", "RUNTIME", "--contains", "borrowck"];
+            options.extend(mode);
+            assert_eq!(
+                sorted(count_with(&input, &options)),
+                [
+                    "again", "async", "borrowck", "compar", "error", "panic",
+                    "runtim", "tokio"
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn channel_posts_counted_under_their_author() {
+        let post = |id: i64, author: Option<&str>| {
+            let mut message = text_message(id, "Rust News", "release notes");
+            if let Some(author) = author {
+                message["author"] = author.into();
+            }
+            message
+        };
+        let messages = serde_json::json!([
+            post(1, Some("Alice")),
+            post(2, Some("Bob")),
+            post(3, None),
+        ]);
+        let participants_in = |chat_type: &str| {
+            let export = serde_json::json!({
+                "name": "Rust News",
+                "type": chat_type,
+                "id": 1,
+                "messages": messages,
+            });
+            let input = temp_path(&format!("{}.json", chat_type));
+            std::fs::write(&input, export.to_string()).unwrap();
+            let counted = count_with(&input, &[]);
+            std::fs::remove_file(&input).unwrap();
+            let mut participants: Vec<_> =
+                counted.summary.participants.into_iter().collect();
+            participants.sort();
+            participants
+        };
+
+        // Unsigned posts stay under the channel
+        assert_eq!(
+            participants_in("public_channel"),
+            ["Alice", "Bob", "Rust News"]
+        );
+        // Outside channels `from` is the sender
+        assert_eq!(participants_in("private_group"), ["Rust News"]);
+
+        // Without `from` the signature comes before the id
+        let without_from: parse::Message = serde_json::from_value(
+            serde_json::json!({"id": 4, "author": "Carol", "from_id": "channel1"}),
+        )
+        .unwrap();
+        assert_eq!(without_from.sender().map(String::as_str), Some("Carol"));
+    }
+
+    #[test]
+    fn diff_of_two_exports() {
+        let before = write_export(
+            "diff-before",
+            serde_json::json!([
+                text_message(1, "Alice", "rust rust crab"),
+                text_message(2, "Bob", "rust borrow ferris"),
+            ]),
+        );
+        let after = write_export(
+            "diff-after",
+            serde_json::json!([
+                text_message(1, "Alice", "crab crab rust"),
+                text_message(2, "Bob", "crab cargo ferris"),
+            ]),
+        );
+        let args = Args::try_parse_from([
+            "tg-dump-word-cloud",
+            "-i",
+            before.to_str().unwrap(),
+            "--diff",
+            after.to_str().unwrap(),
+        ])
+        .unwrap();
+        let counter = WordCounter::new(&args).unwrap();
+        let primary = counter.count(&args.input, true).unwrap();
+        let other = counter
+            .count(std::slice::from_ref(args.diff.as_ref().unwrap()), false)
+            .unwrap();
+
+        let deltas = stats::diff_counts(&primary.word_counts, &other.word_counts);
+        let rows: Vec<(&str, usize, usize, i64, &str)> = deltas
+            .iter()
+            .map(|delta| {
+                (
+                    delta.word.as_str(),
+                    delta.before,
+                    delta.after,
+                    delta.delta(),
+                    delta.change(),
+                )
+            })
+            .collect();
+        // Biggest changes first, ties alphabetically, unchanged left out
+        assert_eq!(
+            rows,
+            [
+                ("crab", 1, 3, 2, "increased"),
+                ("rust", 3, 1, -2, "decreased"),
+                ("borrow", 1, 0, -1, "disappeared"),
+                ("cargo", 0, 1, 1, "appeared"),
+            ]
+        );
+
+        let diff_path = temp_path("diff.txt");
+        export::save_diff(&deltas, &diff_path).unwrap();
+        let written = std::fs::read_to_string(&diff_path).unwrap();
+        std::fs::remove_file(&diff_path).unwrap();
+        assert_eq!(
+            written,
+            "# change delta before after word\n\
+             increased +2 1 3 crab\n\
+             decreased -2 3 1 rust\n\
+             disappeared -1 1 0 borrow\n\
+             appeared +1 0 1 cargo\n"
+        );
+    }
+}
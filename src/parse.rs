@@ -1,11 +1,27 @@
+use crate::interrupt;
+use crate::schema::{ExportFormat, ExportHeader};
 use anyhow::{Context, Result};
+use clap::ValueEnum;
+use encoding_rs::{Encoding, UTF_8, WINDOWS_1251};
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct SimpleMessage {
     pub username: String,
     pub text: String,
+    /// Unix time from `date_unixtime`, if it could be read
+    pub timestamp: Option<i64>,
+    /// Forum topic title, for forum groups
+    pub topic: Option<String>,
+    /// Media type when the text is a caption
+    pub media_type: Option<String>,
+    /// Byte ranges of `text` formatted as bold, italic or underlined
+    pub emphasized: Vec<Range<usize>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -30,11 +46,37 @@ pub struct ReactionUser {
     pub date: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Poll {
+    pub question: String,
+    #[serde(default)]
+    pub closed: bool,
+    #[serde(default)]
+    pub total_voters: i32,
+    #[serde(default)]
+    pub answers: Vec<PollAnswer>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PollAnswer {
+    pub text: String,
+    #[serde(default)]
+    pub voters: i32,
+    #[serde(default)]
+    pub chosen: bool,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Message {
-    pub id: i64,
+    // Missing id, type or dates shouldn't cost a message its text. Without
+    // an id the message can't be replied to or excluded by id.
+    #[serde(default)]
+    pub id: Option<i64>,
+    #[serde(default)]
     pub r#type: String,
+    #[serde(default)]
     pub date: String,
+    #[serde(default)]
     pub date_unixtime: String,
     #[serde(default)]
     pub edited: Option<String>,
@@ -42,6 +84,9 @@ pub struct Message {
     pub edited_unixtime: Option<String>,
     pub from: Option<String>,
     pub from_id: Option<String>,
+    /// Author signature of a channel post
+    #[serde(default)]
+    pub author: Option<String>,
     #[serde(default)]
     pub reply_to_message_id: Option<i64>,
     // Handle text which can be a plain string or an array of text entities
@@ -51,130 +96,1415 @@ pub struct Message {
     pub text_entities: Vec<TextEntity>,
     #[serde(default)]
     pub reactions: Vec<Reaction>,
+    #[serde(default)]
+    pub poll: Option<Poll>,
+    /// Kind of attached media (`video_file`, `voice_message`, `sticker`, ...)
+    #[serde(default)]
+    pub media_type: Option<String>,
+    /// Path of an attached photo, which comes without a `media_type`
+    #[serde(default)]
+    pub photo: Option<String>,
+    /// Emoji a sticker stands for
+    #[serde(default)]
+    pub sticker_emoji: Option<String>,
+    /// Name of the chat or user a forwarded message comes from
+    #[serde(default)]
+    pub forwarded_from: Option<String>,
+    /// Service action such as `topic_created`
+    #[serde(default)]
+    pub action: Option<String>,
+    /// Title set by the service action, e.g. the name of a new topic
+    #[serde(default)]
+    pub title: Option<String>,
+    /// Forum topic, filled in by `resolve_topics`
+    #[serde(skip)]
+    pub topic: Option<String>,
+}
+
+impl Message {
+    /// How errors refer to the message: `message 12`
+    pub fn label(&self) -> String {
+        match self.id {
+            Some(id) => format!("message {}", id),
+            None => "message without id".to_string(),
+        }
+    }
+
+    /// Type of attached media, photos reported as `photo`
+    pub fn media_kind(&self) -> Option<&str> {
+        self.media_type
+            .as_deref()
+            .or(self.photo.as_ref().map(|_| "photo"))
+    }
+
+    /// Display name of the sender, falling back to the channel post
+    /// signature and then the user id
+    pub fn sender(&self) -> Option<&String> {
+        self.from
+            .as_ref()
+            .or(self.author.as_ref())
+            .or(self.from_id.as_ref())
+    }
+}
+
+/// Which message field the text is taken from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TextSource {
+    /// The `text` field, falling back to `text_entities` if it's missing
+    Text,
+    /// Only `text_entities`
+    Entities,
+    /// Both, without repeating what they have in common
+    Both,
+}
+
+/// Controls how a `Message` is turned into a `SimpleMessage`
+#[derive(Debug)]
+pub struct SimplifyOptions {
+    /// Append poll question and answer options to the message text
+    pub include_polls: bool,
+    /// Username used for messages with neither `from` nor `from_id`
+    pub unknown_user_label: String,
+    /// Drop messages with neither `from` nor `from_id`
+    pub exclude_unknown_users: bool,
+    pub text_source: TextSource,
+    /// Leave out `blockquote` entities (quoted text)
+    pub strip_quotes: bool,
+    /// Leave out `link` entities (bare URLs)
+    pub exclude_links: bool,
+    /// Also leave out the display text of `text_link` entities
+    pub drop_link_text: bool,
+    /// Leave out `custom_emoji` entities (emoji from sticker packs) and the
+    /// emoji of stickers
+    pub exclude_sticker_emoji: bool,
+    /// Collapse runs of Unicode whitespace and zero-width spaces into one
+    /// space, see `whitespace_edits`
+    pub normalize_whitespace: bool,
+    /// Count posts under their signature (`author`) rather than `from`,
+    /// for channels
+    pub prefer_author: bool,
+    /// Display names for senders known only by `from_id`
+    pub user_names: HashMap<String, String>,
+    /// Count every message under this name, for saved messages where
+    /// forwarded notes keep the `from` of their original author
+    pub single_sender: Option<String>,
+}
+
+impl SimplifyOptions {
+    /// Whether text of an entity with this `type` goes into the message text
+    fn keeps_entity(&self, entity_type: &str) -> bool {
+        match entity_type {
+            "blockquote" => !self.strip_quotes,
+            "link" => !self.exclude_links,
+            "text_link" => !self.drop_link_text,
+            "custom_emoji" => !self.exclude_sticker_emoji,
+            _ => true,
+        }
+    }
+}
+
+impl Default for SimplifyOptions {
+    fn default() -> Self {
+        Self {
+            include_polls: false,
+            unknown_user_label: "anonymous".to_string(),
+            exclude_unknown_users: false,
+            text_source: TextSource::Text,
+            strip_quotes: false,
+            exclude_links: false,
+            drop_link_text: false,
+            exclude_sticker_emoji: false,
+            normalize_whitespace: false,
+            prefer_author: false,
+            user_names: HashMap::new(),
+            single_sender: None,
+        }
+    }
+}
+
+/// Counters describing how well an export was parsed, plus the header
+/// read along the way
+#[derive(Debug, Default, Clone)]
+pub struct ParseStats {
+    pub parsed: usize,
+    pub failed: usize,
+    /// Bytes passed over while resyncing after malformed objects
+    pub skipped_bytes: usize,
+    /// What went wrong with each failed message
+    pub issues: Vec<ParseIssue>,
+    /// Chat name, type and people lists, from the first file that has a
+    /// header
+    pub header: Option<ExportHeader>,
+}
+
+impl ParseStats {
+    pub fn merge(&mut self, other: &ParseStats) {
+        self.parsed += other.parsed;
+        self.failed += other.failed;
+        self.skipped_bytes += other.skipped_bytes;
+        self.issues.extend(other.issues.iter().cloned());
+        if self.header.is_none() {
+            self.header.clone_from(&other.header);
+        }
+    }
+
+    /// Count a parsed message found at `offset`, noting a missing
+    /// `date_unixtime`
+    pub fn add_message(&mut self, message: &Message, offset: usize) {
+        if message.date_unixtime.is_empty() {
+            self.issues.push(ParseIssue {
+                file: None,
+                offset,
+                error: "No date_unixtime, counted without a timestamp"
+                    .to_string(),
+            });
+        }
+        self.parsed += 1;
+    }
+
+    /// Count a message at `offset` that couldn't be parsed
+    pub fn add_failure(&mut self, offset: usize, error: String) {
+        self.issues.push(ParseIssue {
+            file: None,
+            offset,
+            error,
+        });
+        self.failed += 1;
+    }
+
+    /// Attribute the issues found so far to `file_path`
+    pub fn set_file(&mut self, file_path: &Path) {
+        for issue in &mut self.issues {
+            issue.file.get_or_insert_with(|| file_path.to_path_buf());
+        }
+    }
+}
+
+/// A message that couldn't be parsed
+#[derive(Debug, Clone)]
+pub struct ParseIssue {
+    pub file: Option<PathBuf>,
+    /// Byte offset of the message in the decoded file
+    pub offset: usize,
+    pub error: String,
+}
+
+impl std::fmt::Display for ParseIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}: ", file.display())?;
+        }
+        write!(f, "byte {}: {}", self.offset, self.error)
+    }
+}
+
+/// Character encoding of an export file
+#[derive(Debug, Clone, Copy)]
+pub enum InputEncoding {
+    /// BOM if present, else UTF-8, falling back to Windows-1251
+    Auto,
+    Fixed(&'static Encoding),
+}
+
+/// Parse an `--encoding` value: `auto` or any WHATWG label (`cp1251`, ...)
+pub fn parse_encoding_arg(value: &str) -> Result<InputEncoding, String> {
+    if value.eq_ignore_ascii_case("auto") {
+        return Ok(InputEncoding::Auto);
+    }
+    Encoding::for_label(value.as_bytes())
+        .map(InputEncoding::Fixed)
+        .ok_or_else(|| format!("unknown encoding '{}'", value))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ReadOptions {
+    /// See `parse_messages`
+    pub recovery_depth: usize,
+    pub encoding: InputEncoding,
+    /// Fail on unrecognized export formats and malformed messages
+    pub strict: bool,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        Self {
+            recovery_depth: 16,
+            encoding: InputEncoding::Auto,
+            strict: false,
+        }
+    }
+}
+
+pub fn read_messages<P: AsRef<Path>>(
+    file_path: P,
+    options: &ReadOptions,
+) -> Result<(Vec<Message>, ParseStats)> {
+    let file_path = file_path.as_ref();
+    let content = read_content(file_path, options.encoding)?;
+    let (messages, format, mut stats) =
+        parse_messages(&content, options.recovery_depth);
+    check_format(file_path, format, stats.header.as_ref(), options.strict)?;
+    stats.set_file(file_path);
+    check_stats(file_path, &stats, options.strict)?;
+
+    if messages.is_empty() {
+        anyhow::bail!("No valid messages found in the file");
+    }
+
+    Ok((messages, stats))
+}
+
+/// Report the detected export format, erroring on unknown ones if strict
+pub fn check_format(
+    file_path: &Path,
+    format: ExportFormat,
+    header: Option<&ExportHeader>,
+    strict: bool,
+) -> Result<()> {
+    if format != ExportFormat::Unknown {
+        println!("Detected {} in {}", format, file_path.display());
+        if let Some(header) = header
+            && let Some(name) = &header.name
+        {
+            println!(
+                "Chat: {} ({}, id {})",
+                name,
+                header.r#type.as_deref().unwrap_or("unknown type"),
+                header.id.map_or("unknown".to_string(), |id| id.to_string())
+            );
+        }
+    } else if strict {
+        anyhow::bail!(
+            "Unrecognized export format in {} (--strict)",
+            file_path.display()
+        );
+    } else {
+        eprintln!(
+            "Warning: Unrecognized export format in {}, parsing best-effort",
+            file_path.display()
+        );
+    }
+    Ok(())
+}
+
+/// In strict mode, any malformed message is an error. The issues are
+/// printed first, the error ends the run before they would be reported.
+pub fn check_stats(
+    file_path: &Path,
+    stats: &ParseStats,
+    strict: bool,
+) -> Result<()> {
+    if strict && stats.failed > 0 {
+        for issue in &stats.issues {
+            eprintln!("Error: {}", issue);
+        }
+        anyhow::bail!(
+            "{} malformed messages in {} (--strict)",
+            stats.failed,
+            file_path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Read and decode a whole export file
+pub fn read_content<P: AsRef<Path>>(
+    file_path: P,
+    encoding: InputEncoding,
+) -> Result<String> {
+    let file_path = file_path.as_ref();
+    let bytes = std::fs::read(file_path).map_err(|e| read_error(file_path, e))?;
+
+    Ok(decode_content(&bytes, encoding).into_owned())
+}
+
+/// Error for an export file that can't be opened or read
+pub fn read_error(file_path: &Path, e: std::io::Error) -> anyhow::Error {
+    let reason = match e.kind() {
+        std::io::ErrorKind::NotFound => "file not found",
+        std::io::ErrorKind::PermissionDenied => "permission denied",
+        _ => "I/O error",
+    };
+    anyhow::Error::new(e).context(format!(
+        "Failed to read {}: {}",
+        file_path.display(),
+        reason
+    ))
 }
 
-pub fn read_messages<P: AsRef<Path>>(file_path: P) -> Result<Vec<Message>> {
-    let content = std::fs::read_to_string(file_path)
-        .with_context(|| "Failed to read file content")?;
+/// The encoding `bytes` are in: the BOM's if there is one, else UTF-8 if
+/// they are valid UTF-8, else Windows-1251. With `partial`, `bytes` are the
+/// start of a file and may end inside a UTF-8 sequence.
+pub fn sniff_encoding(
+    bytes: &[u8],
+    encoding: InputEncoding,
+    partial: bool,
+) -> &'static Encoding {
+    match encoding {
+        InputEncoding::Fixed(encoding) => encoding,
+        InputEncoding::Auto => {
+            let is_utf8 = match std::str::from_utf8(bytes) {
+                Ok(_) => true,
+                Err(e) => partial && e.error_len().is_none(),
+            };
+            match Encoding::for_bom(bytes) {
+                Some((encoding, _)) => encoding,
+                None if is_utf8 => UTF_8,
+                None => {
+                    eprintln!(
+                        "Warning: Input is not valid UTF-8, decoding as \
+                         Windows-1251"
+                    );
+                    WINDOWS_1251
+                }
+            }
+        }
+    }
+}
 
+pub fn decode_content(bytes: &[u8], encoding: InputEncoding) -> Cow<'_, str> {
+    let encoding = sniff_encoding(bytes, encoding, false);
+    let (content, _, had_errors) = encoding.decode(bytes);
+    if had_errors {
+        eprintln!(
+            "Warning: Some bytes are not valid {}, replaced them",
+            encoding.name()
+        );
+    }
+    content
+}
+
+pub fn parse_messages(
+    content: &str,
+    recovery_depth: usize,
+) -> (Vec<Message>, ExportFormat, ParseStats) {
     let mut messages = Vec::new();
+    let (format, stats) =
+        for_each_message(content, recovery_depth, |message, _| {
+            messages.push(message)
+        });
+    (messages, format, stats)
+}
+
+/// Scan `content` for messages, handing each one to `visit` as soon as it
+/// is parsed. Messages are the objects directly inside a `messages` array,
+/// or inside a top-level array of messages. The entities nested in messages
+/// and the account sections are passed over, the header fields are read
+/// into the returned stats. `visit` also gets the header fields read so
+/// far, which Telegram writes before the messages.
+///
+/// A well-formed message that doesn't fit `Message` is skipped whole. When
+/// a message is broken JSON (cut off, or its braces never close), its
+/// extent can't be trusted: scanning resyncs to the next `{"id"` message
+/// boundary after its start, up to `recovery_depth` times in a row. Past
+/// that, the span its braces enclose is skipped.
+pub fn for_each_message(
+    content: &str,
+    recovery_depth: usize,
+    mut visit: impl FnMut(Message, &ExportHeader),
+) -> (ExportFormat, ParseStats) {
+    // Braces and quotes are ASCII, so scanning bytes is safe for UTF-8 text
+    let bytes = content.as_bytes();
+    let mut stats = ParseStats::default();
+    let mut structure = Structure::default();
+    let mut resyncs = 0;
     let mut start_idx = 0;
 
-    // Iterate through the content by characters, not bytes
-    let chars: Vec<char> = content.chars().collect();
+    while let Some(start) = structure.next_message(bytes, start_idx) {
+        if interrupt::is_interrupted() {
+            break;
+        }
 
-    while start_idx < chars.len() {
-        // Find the next opening brace
-        let mut obj_start = None;
-        for i in start_idx..chars.len() {
-            if chars[i] == '{' {
-                obj_start = Some(i);
-                break;
+        let obj_end = find_object_end(bytes, start);
+        if let Some(end) = obj_end {
+            // We found a complete JSON object
+            match serde_json::from_str::<Message>(&content[start..=end]) {
+                Ok(message) => {
+                    stats.add_message(&message, start);
+                    visit(message, &structure.header);
+                    resyncs = 0;
+                    start_idx = end + 1;
+                    continue;
+                }
+                // Valid JSON of the wrong shape ends where its braces do,
+                // objects inside it are not messages
+                Err(e) if e.is_data() => {
+                    stats.add_failure(
+                        start,
+                        format!("Failed to parse message: {}", e),
+                    );
+                    start_idx = end + 1;
+                    stats.skipped_bytes += start_idx - start;
+                    continue;
+                }
+                // A message cut off mid-file has its braces balanced by the
+                // messages after it
+                Err(e) => stats.add_failure(
+                    start,
+                    format!("Failed to parse message: {}", e),
+                ),
             }
+        } else {
+            stats.add_failure(start, "Unterminated message".to_string());
         }
 
-        if let Some(start) = obj_start {
-            // Find the matching closing brace
-            let mut brace_count = 1;
-            let mut obj_end = None;
+        // Broken message: try the next message boundary
+        let next = if resyncs < recovery_depth {
+            find_message_boundary(bytes, start + 1)
+        } else {
+            None
+        };
+        start_idx = match (next, obj_end) {
+            (Some(next), _) => {
+                resyncs += 1;
+                next
+            }
+            (None, Some(end)) => end + 1,
+            // Nothing after it can be told apart from its contents
+            (None, None) => bytes.len(),
+        };
+        stats.skipped_bytes += start_idx - start;
+    }
 
-            for i in (start + 1)..chars.len() {
-                match chars[i] {
-                    '{' => brace_count += 1,
-                    '}' => {
-                        brace_count -= 1;
-                        if brace_count == 0 {
-                            obj_end = Some(i);
-                            break;
-                        }
+    if matches!(
+        structure.format,
+        ExportFormat::SingleChat | ExportFormat::Account
+    ) {
+        stats.header = Some(structure.header);
+    }
+    (structure.format, stats)
+}
+
+/// A JSON container open around the scan position
+enum Container {
+    /// The last string read in the object, key or value, was "messages"
+    Object { after_messages_key: bool },
+    /// Arrays of messages hold one message per element
+    Array { of_messages: bool },
+}
+
+/// Nesting of the JSON read so far, just enough to tell messages apart from
+/// the objects around and inside them, and the top-level fields
+#[derive(Default)]
+struct Structure {
+    open: Vec<Container>,
+    format: ExportFormat,
+    header: ExportHeader,
+}
+
+impl Structure {
+    /// Position of the `{` opening the next message at or after `from`.
+    /// `from` must not be inside a string. Messages themselves are not
+    /// entered, the caller skips past them.
+    fn next_message(&mut self, bytes: &[u8], from: usize) -> Option<usize> {
+        let mut i = from;
+        while i < bytes.len() {
+            match bytes[i] {
+                b'"' => {
+                    let end = find_string_end(bytes, i)?;
+                    if let Some(Container::Object { after_messages_key }) =
+                        self.open.last_mut()
+                    {
+                        *after_messages_key = &bytes[i + 1..end] == b"messages";
+                    }
+                    if self.open.len() == 1
+                        && let Some(value_start) = key_value_start(bytes, end)
+                        && let Ok(key) = std::str::from_utf8(&bytes[i + 1..end])
+                        && let Some(value_end) =
+                            self.read_top_level_key(key, bytes, value_start)
+                    {
+                        i = value_end;
+                        continue;
                     }
-                    _ => {}
+                    i = end;
                 }
+                b'{' => {
+                    if let Some(Container::Array { of_messages: true }) =
+                        self.open.last()
+                    {
+                        return Some(i);
+                    }
+                    self.open.push(Container::Object {
+                        after_messages_key: false,
+                    });
+                }
+                b'[' => {
+                    // A bare array at the top is a list of messages
+                    let of_messages = match self.open.last() {
+                        None => {
+                            self.format = ExportFormat::MessageArray;
+                            true
+                        }
+                        Some(Container::Object { after_messages_key }) => {
+                            *after_messages_key
+                        }
+                        Some(Container::Array { .. }) => false,
+                    };
+                    self.open.push(Container::Array { of_messages });
+                }
+                b'}' | b']' => {
+                    self.open.pop();
+                }
+                _ => {}
             }
+            i += 1;
+        }
+        None
+    }
+
+    /// Note what a top-level key says about the export. Header fields are
+    /// read whole, returning the index just past their value.
+    fn read_top_level_key(
+        &mut self,
+        key: &str,
+        bytes: &[u8],
+        value_start: usize,
+    ) -> Option<usize> {
+        if let Some(format) = ExportFormat::from_key(key)
+            && self.format != ExportFormat::Account
+        {
+            self.format = format;
+        }
+        if !ExportHeader::is_field(key) {
+            return None;
+        }
+        let mut values =
+            serde_json::Deserializer::from_slice(&bytes[value_start..])
+                .into_iter::<serde_json::Value>();
+        let value = values.next()?.ok()?;
+        self.header.set_field(key, value);
+        Some(value_start + values.byte_offset())
+    }
+}
 
-            if let Some(end) = obj_end {
-                // We found a complete JSON object
-                let json_str: String = chars[start..=end].iter().collect();
+/// Index where the value starts if the string ending at `string_end` is an
+/// object key, i.e. followed by a colon
+fn key_value_start(bytes: &[u8], string_end: usize) -> Option<usize> {
+    let colon = string_end
+        + 1
+        + bytes[string_end + 1..]
+            .iter()
+            .position(|b| !b.is_ascii_whitespace())?;
+    (bytes[colon] == b':').then_some(colon + 1)
+}
 
-                match serde_json::from_str::<Message>(&json_str) {
-                    Ok(message) => messages.push(message),
-                    Err(e) => {
-                        eprintln!("Warning: Failed to parse message: {}", e);
-                        // Continue with next message
-                    }
+/// Index of the quote closing the string opened at `start`
+fn find_string_end(bytes: &[u8], start: usize) -> Option<usize> {
+    let mut escaped = false;
+    for (i, &b) in bytes.iter().enumerate().skip(start + 1) {
+        match b {
+            _ if escaped => escaped = false,
+            b'\\' => escaped = true,
+            b'"' => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn find_byte(bytes: &[u8], needle: u8, from: usize) -> Option<usize> {
+    bytes
+        .get(from..)?
+        .iter()
+        .position(|&b| b == needle)
+        .map(|i| from + i)
+}
+
+/// Index of the brace closing the object opened at `start`, ignoring braces
+/// inside JSON strings
+fn find_object_end(bytes: &[u8], start: usize) -> Option<usize> {
+    let mut brace_count = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+
+    for (i, &b) in bytes.iter().enumerate().skip(start) {
+        if in_string {
+            match b {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+
+        match b {
+            b'"' => in_string = true,
+            b'{' => brace_count += 1,
+            b'}' => {
+                brace_count -= 1;
+                if brace_count == 0 {
+                    return Some(i);
                 }
-                start_idx = end + 1;
-            } else {
-                // Unmatched braces, move past this opening brace
-                start_idx = start + 1;
             }
-        } else {
-            // No more opening braces
-            break;
+            _ => {}
         }
     }
 
-    if messages.is_empty() {
-        anyhow::bail!("No valid messages found in the file");
+    None
+}
+
+/// Next `{` that opens a message
+fn find_message_boundary(bytes: &[u8], from: usize) -> Option<usize> {
+    let mut idx = from;
+    while let Some(brace) = find_byte(bytes, b'{', idx) {
+        if is_message_start(bytes, brace) {
+            return Some(brace);
+        }
+        idx = brace + 1;
+    }
+    None
+}
+
+/// Whether the `{` at `brace` is followed by an `"id"` key, which Telegram
+/// always writes first in a message
+fn is_message_start(bytes: &[u8], brace: usize) -> bool {
+    let rest = &bytes[brace + 1..];
+    let key_start = rest
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(rest.len());
+    rest[key_start..].starts_with(b"\"id\"")
+}
+
+/// Find the part files of a split export (`messages.json`, `messages2.json`,
+/// ...) in `dir`, ordered by part number
+pub fn find_export_parts(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory {}", dir.display()))?;
+
+    let mut parts = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if let Some(number) = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(export_part_number)
+        {
+            parts.push((number, path));
+        }
+    }
+    if parts.is_empty() {
+        anyhow::bail!("No messages*.json files found in {}", dir.display());
     }
 
-    Ok(messages)
+    parts.sort();
+    Ok(parts.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Part number of `messages.json` (1), `messages2.json` (2), ...
+fn export_part_number(file_name: &str) -> Option<u32> {
+    let number = file_name.strip_prefix("messages")?.strip_suffix(".json")?;
+    if number.is_empty() {
+        Some(1)
+    } else {
+        number.parse().ok()
+    }
 }
 
-pub fn simplify_messages(messages: &[Message]) -> Vec<SimpleMessage> {
+/// Read several exports in parallel and merge them in the given order.
+/// A file that fails to read is reported and skipped, unless strict.
+pub fn read_message_files<P: AsRef<Path> + Sync>(
+    file_paths: &[P],
+    options: &ReadOptions,
+) -> Result<(Vec<Message>, ParseStats)> {
+    let results: Vec<_> = file_paths
+        .par_iter()
+        .map(|file_path| read_messages(file_path, options))
+        .collect();
+
+    let mut messages = Vec::new();
+    let mut stats = ParseStats::default();
+    let mut failed = 0;
+    for (file_path, result) in file_paths.iter().zip(results) {
+        match result {
+            Ok((file_messages, file_stats)) => {
+                messages.extend(file_messages);
+                stats.merge(&file_stats);
+            }
+            Err(e) if options.strict => return Err(e),
+            Err(e) => {
+                eprintln!(
+                    "Warning: Skipping {}: {:#}",
+                    file_path.as_ref().display(),
+                    e
+                );
+                failed += 1;
+            }
+        }
+    }
+
+    if failed == file_paths.len() {
+        anyhow::bail!("None of the {} input files could be read", failed);
+    }
+
+    Ok((messages, stats))
+}
+
+pub fn simplify_messages(
+    messages: &[Message],
+    options: &SimplifyOptions,
+) -> Vec<SimpleMessage> {
     messages
         .iter()
-        .filter_map(|msg| {
-            // Skip messages without text
-            let text = extract_message_text(msg);
-            if text.is_empty() {
-                return None;
-            }
+        .filter_map(|msg| simplify_message(msg, options))
+        .collect()
+}
+
+pub fn simplify_message(
+    msg: &Message,
+    options: &SimplifyOptions,
+) -> Option<SimpleMessage> {
+    let (mut text, mut emphasized) = extract_message_text(msg, options);
+    if options.exclude_sticker_emoji
+        && let Some(emoji) = &msg.sticker_emoji
+        && !emoji.is_empty()
+        && text.contains(emoji.as_str())
+    {
+        let edits: Vec<_> = text
+            .match_indices(emoji.as_str())
+            .map(|(at, emoji)| (at..at + emoji.len(), " "))
+            .collect();
+        text = edit_text(&text, &edits, &mut emphasized);
+        text = edit_text(&text, &trim_edits(&text), &mut emphasized);
+    }
+    if options.include_polls
+        && let Some(poll) = &msg.poll
+    {
+        append_line(&mut text, &extract_poll_text(poll));
+    }
+
+    if options.normalize_whitespace {
+        text = edit_text(&text, &whitespace_edits(&text), &mut emphasized);
+    }
+
+    // Skip messages without text
+    if text.is_empty() {
+        return None;
+    }
+
+    // Get username, falling back to the channel post signature, the user id
+    // or a placeholder
+    let sender = match &msg.author {
+        Some(author) if options.prefer_author => Some(author),
+        _ => msg.sender(),
+    };
+    let username = match (sender, &options.single_sender) {
+        (_, Some(owner)) => owner.clone(),
+        (Some(name), None) => {
+            options.user_names.get(name).unwrap_or(name).clone()
+        }
+        (None, None) if options.exclude_unknown_users => return None,
+        (None, None) => options.unknown_user_label.clone(),
+    };
 
-            // Get username or a placeholder if it's missing
-            let username = match &msg.from {
-                Some(name) => name.clone(),
-                None => match &msg.from_id {
-                    Some(id) => id.clone(),
-                    None => "anonymous".to_string(),
-                },
+    Some(SimpleMessage {
+        username,
+        text,
+        timestamp: msg.date_unixtime.parse().ok(),
+        topic: msg.topic.clone(),
+        media_type: msg.media_kind().map(String::from),
+        emphasized,
+    })
+}
+
+// Forum topic the General topic's messages are assigned to
+const GENERAL_TOPIC: &str = "General";
+// Longest reply chain followed when looking for a topic
+const MAX_TOPIC_DEPTH: usize = 64;
+
+/// Fill in `topic` for messages of a forum group.
+///
+/// Exports don't tag messages with their topic. Each topic starts with a
+/// `topic_created` service message and every message in it is a reply to
+/// that message or to another message in the topic, so the topic is found
+/// by following the reply chain. Messages that don't lead to a topic belong
+/// to General. Returns the number of topics found; chats without topics
+/// are left untouched.
+pub fn resolve_topics(messages: &mut [Message]) -> usize {
+    let titles: HashMap<i64, String> = messages
+        .iter()
+        .filter(|msg| msg.action.as_deref() == Some("topic_created"))
+        .filter_map(|msg| Some((msg.id?, msg.title.clone().unwrap_or_default())))
+        .collect();
+    if titles.is_empty() {
+        return 0;
+    }
+
+    let replies: HashMap<i64, i64> = messages
+        .iter()
+        .filter_map(|msg| Some((msg.id?, msg.reply_to_message_id?)))
+        .collect();
+
+    for msg in messages.iter_mut() {
+        // A message without an id can still follow its own reply
+        let mut id = msg.id.or(msg.reply_to_message_id);
+        let mut topic = None;
+        for _ in 0..MAX_TOPIC_DEPTH {
+            let Some(current) = id else {
+                break;
             };
+            if let Some(title) = titles.get(&current) {
+                topic = Some(title.clone());
+                break;
+            }
+            id = replies.get(&current).copied();
+        }
+        msg.topic = Some(topic.unwrap_or_else(|| GENERAL_TOPIC.to_string()));
+    }
 
-            Some(SimpleMessage { username, text })
-        })
-        .collect()
+    titles.len()
+}
+
+/// The message text and the byte ranges of it formatted as bold, italic or
+/// underlined
+pub fn extract_message_text(
+    message: &Message,
+    options: &SimplifyOptions,
+) -> (String, Vec<Range<usize>>) {
+    match options.text_source {
+        TextSource::Text => {
+            // If there's no text field or it's in an unexpected format,
+            // try to use text_entities
+            extract_text_field(message, options)
+                .unwrap_or_else(|| extract_entities_text(message, options))
+        }
+        TextSource::Entities => extract_entities_text(message, options),
+        TextSource::Both => {
+            let (text, text_emphasized) =
+                extract_text_field(message, options).unwrap_or_default();
+            let (entities, entities_emphasized) =
+                extract_entities_text(message, options);
+            // Usually one is a copy of the other, so only keep the extra part
+            if text.contains(&entities) {
+                (text, text_emphasized)
+            } else if entities.contains(&text) {
+                (entities, entities_emphasized)
+            } else {
+                let mut result = text;
+                let mut emphasized = text_emphasized;
+                // Both are non-empty here, a newline goes between them
+                let offset = result.len() + 1;
+                append_line(&mut result, &entities);
+                emphasized.extend(
+                    entities_emphasized
+                        .into_iter()
+                        .map(|span| span.start + offset..span.end + offset),
+                );
+                (result, emphasized)
+            }
+        }
+    }
 }
 
-pub fn extract_message_text(message: &Message) -> String {
+/// Text from the `text` field, `None` if it is neither a string nor an array.
+/// Parts are joined as they are: Telegram keeps the spaces around a
+/// formatted run in the plain parts next to it, and formatting can start or
+/// end mid-word ("**Hel**lo"), so parts without a space between them are one
+/// word as sent.
+fn extract_text_field(
+    message: &Message,
+    options: &SimplifyOptions,
+) -> Option<(String, Vec<Range<usize>>)> {
     match &message.text {
-        serde_json::Value::String(text) => text.clone(),
+        serde_json::Value::String(text) => Some((text.clone(), Vec::new())),
         serde_json::Value::Array(parts) => {
             let mut result = String::new();
+            let mut emphasized = Vec::new();
             for part in parts {
                 if let serde_json::Value::Object(obj) = part {
+                    let entity_type =
+                        obj.get("type").and_then(|t| t.as_str()).unwrap_or("");
+                    if !options.keeps_entity(entity_type) {
+                        continue;
+                    }
                     if let Some(serde_json::Value::String(text)) = obj.get("text")
                     {
-                        result.push_str(text);
+                        push_part(
+                            &mut result,
+                            &mut emphasized,
+                            text,
+                            entity_type,
+                        );
                     }
                 } else if let serde_json::Value::String(text) = part {
                     result.push_str(text);
                 }
             }
-            result
+            Some((result, emphasized))
         }
-        _ => {
-            // If there's no text field or it's in an unexpected format,
-            // try to use text_entities
-            if !message.text_entities.is_empty() {
-                message
-                    .text_entities
-                    .iter()
-                    .map(|entity| entity.text.clone())
-                    .collect::<Vec<_>>()
-                    .join("")
-            } else {
-                String::new()
+        _ => None,
+    }
+}
+
+fn extract_entities_text(
+    message: &Message,
+    options: &SimplifyOptions,
+) -> (String, Vec<Range<usize>>) {
+    let mut result = String::new();
+    let mut emphasized = Vec::new();
+    for entity in &message.text_entities {
+        if options.keeps_entity(&entity.r#type) {
+            push_part(&mut result, &mut emphasized, &entity.text, &entity.r#type);
+        }
+    }
+    (result, emphasized)
+}
+
+/// Append the text of an entity, noting where it is if it's formatted as
+/// bold, italic or underlined
+fn push_part(
+    text: &mut String,
+    emphasized: &mut Vec<Range<usize>>,
+    part: &str,
+    entity_type: &str,
+) {
+    let start = text.len();
+    text.push_str(part);
+    if matches!(entity_type, "bold" | "italic" | "underline") && !part.is_empty()
+    {
+        emphasized.push(start..text.len());
+    }
+}
+
+/// `text` with each of the sorted, non-overlapping `edits` ranges replaced
+/// by its string. `spans` move along with the text, spans left empty are
+/// dropped.
+fn edit_text(
+    text: &str,
+    edits: &[(Range<usize>, &str)],
+    spans: &mut Vec<Range<usize>>,
+) -> String {
+    let mut result = String::with_capacity(text.len());
+    // New offset of every old one, offsets inside an edit go to its start.
+    // Only needed when there are spans to move.
+    let mut moved = vec![0; if spans.is_empty() { 0 } else { text.len() + 1 }];
+    let mut rest = 0;
+    for (range, replacement) in edits {
+        if !moved.is_empty() {
+            for (slot, new) in
+                moved[rest..range.start].iter_mut().zip(result.len()..)
+            {
+                *slot = new;
             }
+            let start = result.len() + range.start - rest;
+            moved[range.start..range.end].fill(start);
+        }
+        result.push_str(&text[rest..range.start]);
+        result.push_str(replacement);
+        rest = range.end;
+    }
+    if !moved.is_empty() {
+        for (slot, new) in moved[rest..].iter_mut().zip(result.len()..) {
+            *slot = new;
+        }
+        for span in spans.iter_mut() {
+            *span = moved[span.start]..moved[span.end];
+        }
+        spans.retain(|span| !span.is_empty());
+    }
+    result.push_str(&text[rest..]);
+    result
+}
+
+/// Edits for `edit_text` dropping the whitespace at both ends, like `trim`
+fn trim_edits(text: &str) -> Vec<(Range<usize>, &'static str)> {
+    let start = text.len() - text.trim_start().len();
+    let end = text.trim_end().len().max(start);
+    vec![(0..start, ""), (end..text.len(), "")]
+}
+
+/// Edits for `edit_text` replacing every run of whitespace (tabs, newlines,
+/// non-breaking spaces, ...) and zero-width spaces with a single space and
+/// trimming the ends. Zero-width joiners are kept, emoji sequences are built
+/// with them.
+fn whitespace_edits(text: &str) -> Vec<(Range<usize>, &'static str)> {
+    let is_space = |c: char| {
+        c.is_whitespace() || matches!(c, '\u{200B}' | '\u{2060}' | '\u{FEFF}')
+    };
+    let mut edits = Vec::new();
+    let mut run = None;
+    for (at, c) in text.char_indices() {
+        if is_space(c) {
+            run.get_or_insert(at);
+        } else if let Some(start) = run.take() {
+            edits.push((start..at, if start == 0 { "" } else { " " }));
         }
     }
+    if let Some(start) = run {
+        edits.push((start..text.len(), ""));
+    }
+    edits
+}
+
+pub fn extract_poll_text(poll: &Poll) -> String {
+    let mut result = poll.question.clone();
+    for answer in &poll.answers {
+        append_line(&mut result, &answer.text);
+    }
+    result
+}
+
+fn append_line(text: &mut String, line: &str) {
+    if line.is_empty() {
+        return;
+    }
+    if !text.is_empty() {
+        text.push('\n');
+    }
+    text.push_str(line);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(content: &str) -> (Vec<Message>, ParseStats) {
+        let (messages, _, stats) =
+            parse_messages(content, ReadOptions::default().recovery_depth);
+        (messages, stats)
+    }
+
+    #[test]
+    fn only_message_array_elements_are_messages() {
+        let content = r#"{
+            "name": "Rustaceans",
+            "pinned": {"id": 99, "type": "message", "text": "not a message"},
+            "messages": [
+                {"id": 1, "type": "message", "date_unixtime": "1704103200",
+                 "from": "Alice", "text": ["plain ", {"type": "bold", "text": "bold"}],
+                 "text_entities": [{"type": "bold", "text": "bold"}]},
+                {"id": 2, "type": "message", "date_unixtime": "1704103300",
+                 "from": "Bob",
+                 "text_entities": [{"type": "plain", "text": "entities only"}]}
+            ]
+        }"#;
+        let (messages, stats) = parse(content);
+        let ids: Vec<i64> = messages.iter().filter_map(|msg| msg.id).collect();
+        assert_eq!(ids, [1, 2]);
+        assert_eq!(stats.failed, 0);
+        assert!(stats.issues.is_empty());
+
+        // A message without `text` falls back to its entities
+        let (text, _) = extract_message_text(&messages[1], &Default::default());
+        assert_eq!(text, "entities only");
+    }
+
+    #[test]
+    fn message_without_date_unixtime_is_counted_with_an_issue() {
+        let content = r#"{"messages": [
+            {"id": 1, "type": "message", "date": "2024-01-01T10:00:00",
+             "from": "Alice", "text": "no unix time"},
+            {"id": 2, "type": "message", "date_unixtime": "1704103300",
+             "from": "Bob", "text": "fine"}
+        ]}"#;
+        let (messages, stats) = parse(content);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].text, "no unix time");
+        assert_eq!(stats.parsed, 2);
+        assert_eq!(stats.failed, 0);
+        assert_eq!(stats.issues.len(), 1);
+        assert_eq!(stats.issues[0].offset, content.find("{\"id\": 1").unwrap());
+        assert!(stats.issues[0].error.contains("date_unixtime"));
+
+        let simple = simplify_messages(&messages, &Default::default());
+        assert_eq!(simple[0].timestamp, None);
+        assert_eq!(simple[1].timestamp, Some(1704103300));
+    }
+
+    #[test]
+    fn malformed_message_is_skipped() {
+        let content = r#"[
+            {"id": 1, "from": "Alice", "date_unixtime": "1", "text": "first"},
+            {"id": "two", "from": "Bob", "date_unixtime": "2", "text": "bad id",
+             "forwarded": {"id": 9, "from": "Dan", "text": "inside"}},
+            {"id": 3, "from": "Carol", "date_unixtime": "3", "text": "third"}
+        ]"#;
+        let (messages, stats) = parse(content);
+        let ids: Vec<i64> = messages.iter().filter_map(|msg| msg.id).collect();
+        assert_eq!(ids, [1, 3]);
+        assert_eq!(stats.failed, 1);
+        let start = content.find("{\"id\": \"two\"").unwrap();
+        assert_eq!(stats.issues[0].offset, start);
+        // The whole bad message is skipped, up to its closing brace
+        let end = content.find("\"inside\"}}").unwrap() + "\"inside\"}}".len();
+        assert_eq!(stats.skipped_bytes, end - start);
+    }
+
+    #[test]
+    fn messages_after_a_truncated_one_are_recovered() {
+        let content = r#"{"name": "Rustaceans", "messages": [
+            {"id": 1, "from": "Alice", "date_unixtime": "1", "text": "first"},
+            {"id": 2, "from": "Bob", "date_unixtime": "2", "text": "cut off
+            {"id": 3, "from": "Carol", "date_unixtime": "3", "text": "third"},
+            {"id": 4, "from": "Dan", "date_unixtime": "4", "reply_to":
+            {"id": 5, "from": "Erin", "date_unixtime": "5", "text": "fifth"}
+        ]}"#;
+        let (messages, stats) = parse(content);
+        let ids: Vec<i64> = messages.iter().filter_map(|msg| msg.id).collect();
+        assert_eq!(ids, [1, 3, 5]);
+        assert_eq!(stats.failed, 2);
+        let offset =
+            |id: &str| content.find(&format!("{{\"id\": {}", id)).unwrap();
+        let offsets: Vec<usize> =
+            stats.issues.iter().map(|issue| issue.offset).collect();
+        assert_eq!(offsets, [offset("2"), offset("4")]);
+        assert_eq!(
+            stats.skipped_bytes,
+            offset("3") - offset("2") + offset("5") - offset("4")
+        );
+    }
+
+    #[test]
+    fn interrupt_keeps_the_messages_read_so_far() {
+        let content = r#"[
+            {"id": 1, "from": "Alice", "date_unixtime": "1", "text": "first"},
+            {"id": 2, "from": "Bob", "date_unixtime": "2", "text": "second"},
+            {"id": 3, "from": "Carol", "date_unixtime": "3", "text": "third"}
+        ]"#;
+        let mut messages = Vec::new();
+        let (_, stats) = for_each_message(content, 0, |message, _| {
+            messages.push(message);
+            // Ctrl-C while the second message is counted
+            if messages.len() == 2 {
+                interrupt::interrupt();
+            }
+        });
+        interrupt::reset();
+
+        let ids: Vec<i64> = messages.iter().filter_map(|msg| msg.id).collect();
+        assert_eq!(ids, [1, 2]);
+        assert_eq!(stats.parsed, 2);
+        assert_eq!(stats.failed, 0);
+        assert_eq!(stats.skipped_bytes, 0);
+    }
+
+    #[test]
+    fn invalid_utf8_detected_as_cp1251() {
+        let (cp1251, _, _) = WINDOWS_1251.encode("привет, мир");
+        assert!(std::str::from_utf8(&cp1251).is_err());
+        assert_eq!(
+            sniff_encoding(&cp1251, InputEncoding::Auto, false),
+            WINDOWS_1251
+        );
+        assert_eq!(decode_content(&cp1251, InputEncoding::Auto), "привет, мир");
+
+        // UTF-8 is kept, also when a read chunk ends inside a character
+        let utf8 = "привет".as_bytes();
+        assert_eq!(sniff_encoding(utf8, InputEncoding::Auto, false), UTF_8);
+        assert_eq!(sniff_encoding(&utf8[..3], InputEncoding::Auto, true), UTF_8);
+        assert_eq!(
+            sniff_encoding(&utf8[..3], InputEncoding::Auto, false),
+            WINDOWS_1251
+        );
+
+        // A BOM or --encoding wins over the guess
+        let bom = [&[0xEF, 0xBB, 0xBF][..], &cp1251].concat();
+        assert_eq!(sniff_encoding(&bom, InputEncoding::Auto, false), UTF_8);
+        let InputEncoding::Fixed(koi8) = parse_encoding_arg("koi8-r").unwrap()
+        else {
+            panic!("koi8-r is a fixed encoding");
+        };
+        assert_eq!(
+            sniff_encoding(&cp1251, InputEncoding::Fixed(koi8), false),
+            koi8
+        );
+        assert!(matches!(
+            parse_encoding_arg("AUTO"),
+            Ok(InputEncoding::Auto)
+        ));
+        assert!(parse_encoding_arg("cp9999").is_err());
+    }
+
+    #[test]
+    fn export_shape_detected_and_unknown_rejected_when_strict() {
+        let message =
+            r#"{"id": 1, "from": "Alice", "date_unixtime": "1", "text": "hi"}"#;
+        let format_of = |content: &str| parse_messages(content, 0).1;
+        assert_eq!(
+            format_of(&format!(
+                r#"{{"name": "Chat", "messages": [{}]}}"#,
+                message
+            )),
+            ExportFormat::SingleChat
+        );
+        assert_eq!(
+            format_of(&format!(
+                r#"{{"personal_information": {{}}, "chats": {{"list": [{{"messages": [{}]}}]}}}}"#,
+                message
+            )),
+            ExportFormat::Account
+        );
+        assert_eq!(
+            format_of(&format!("[{}]", message)),
+            ExportFormat::MessageArray
+        );
+        let unknown = format!(r#"{{"items": [{}]}}"#, message);
+        assert_eq!(format_of(&unknown), ExportFormat::Unknown);
+
+        let path = crate::tests::temp_path("unknown-shape.json");
+        std::fs::write(&path, &unknown).unwrap();
+        let strict = ReadOptions {
+            strict: true,
+            ..Default::default()
+        };
+        let error = read_messages(&path, &strict).unwrap_err();
+        assert!(error.to_string().starts_with("Unrecognized export format"));
+        let best_effort = read_messages(&path, &Default::default());
+        std::fs::remove_file(&path).unwrap();
+        // Without --strict it gets past the format check and finds nothing
+        assert_eq!(
+            best_effort.unwrap_err().to_string(),
+            "No valid messages found in the file"
+        );
+    }
+
+    #[test]
+    fn emphasized_ranges_follow_whitespace_normalization() {
+        let message: Message = serde_json::from_value(serde_json::json!({
+            "id": 1,
+            "type": "message",
+            "from": "Alice",
+            "text": ["  plain\n\n", {"type": "bold", "text": "bold  word"}, "  end "],
+        }))
+        .unwrap();
+        let options = SimplifyOptions {
+            normalize_whitespace: true,
+            ..Default::default()
+        };
+        let simple_message = simplify_message(&message, &options).unwrap();
+        assert_eq!(simple_message.text, "plain bold word end");
+        let bold = &simple_message.text[simple_message.emphasized[0].clone()];
+        assert_eq!(bold, "bold word");
+        assert_eq!(simple_message.emphasized.len(), 1);
+    }
+
+    #[test]
+    fn missing_input_names_path_and_reason() {
+        let missing = Path::new("/nonexistent/result.json");
+        let error = read_messages(missing, &Default::default()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Failed to read /nonexistent/result.json: file not found"
+        );
+    }
+
+    #[test]
+    fn link_entities_drop_url_keep_text_link_text() {
+        let message: Message = serde_json::from_value(serde_json::json!({
+            "id": 1,
+            "type": "message",
+            "from": "Alice",
+            "text": [
+                "read ",
+                {"type": "text_link", "text": "the book", "href": "https://doc.rust-lang.org/book"},
+                " at ",
+                {"type": "link", "text": "https://rust-lang.org"},
+            ],
+        }))
+        .unwrap();
+
+        let options = SimplifyOptions {
+            exclude_links: true,
+            ..Default::default()
+        };
+        let (text, _) = extract_message_text(&message, &options);
+        assert_eq!(text, "read the book at ");
+        assert!(!text.contains("doc.rust-lang.org"));
+
+        let options = SimplifyOptions {
+            drop_link_text: true,
+            ..options
+        };
+        assert_eq!(extract_message_text(&message, &options).0, "read  at ");
+    }
+
+    #[test]
+    fn sticker_emoji_read_and_dropped_from_text() {
+        let message: Message = serde_json::from_value(serde_json::json!({
+            "id": 1,
+            "type": "message",
+            "from": "Alice",
+            "file": "stickers/sticker.webp",
+            "media_type": "sticker",
+            "sticker_emoji": "🔥",
+            "text": ["so 🔥 fast ", {"type": "custom_emoji", "text": "🦀", "document_id": "1"}],
+        }))
+        .unwrap();
+        assert_eq!(message.sticker_emoji.as_deref(), Some("🔥"));
+        assert_eq!(message.media_kind(), Some("sticker"));
+
+        let kept = simplify_message(&message, &Default::default()).unwrap();
+        assert_eq!(kept.text, "so 🔥 fast 🦀");
+
+        let options = SimplifyOptions {
+            exclude_sticker_emoji: true,
+            ..Default::default()
+        };
+        let dropped = simplify_message(&message, &options).unwrap();
+        assert_eq!(dropped.text, "so   fast");
+    }
+
+    #[test]
+    fn zero_width_space_becomes_a_space() {
+        let message: Message = serde_json::from_value(serde_json::json!({
+            "id": 1,
+            "type": "message",
+            "from": "Alice",
+            "text": "\u{FEFF}borrow\u{200B}checker\u{a0}\u{2060}rules \u{1F468}\u{200D}\u{1F4BB}\u{200B}",
+        }))
+        .unwrap();
+        let options = SimplifyOptions {
+            normalize_whitespace: true,
+            ..Default::default()
+        };
+        let simple_message = simplify_message(&message, &options).unwrap();
+        // The zero-width joiner inside the emoji sequence stays
+        assert_eq!(
+            simple_message.text,
+            "borrow checker rules \u{1F468}\u{200D}\u{1F4BB}"
+        );
+
+        let untouched = simplify_message(&message, &Default::default()).unwrap();
+        assert!(untouched.text.contains('\u{200B}'));
+    }
+
+    #[test]
+    fn text_source_picks_the_field() {
+        let message: Message = serde_json::from_value(serde_json::json!({
+            "id": 1,
+            "type": "message",
+            "from": "Alice",
+            "text": "edited later",
+            "text_entities": [
+                {"type": "plain", "text": "first "},
+                {"type": "bold", "text": "draft"},
+            ],
+        }))
+        .unwrap();
+        let text_of = |text_source| {
+            let options = SimplifyOptions {
+                text_source,
+                ..Default::default()
+            };
+            extract_message_text(&message, &options).0
+        };
+
+        assert_eq!(text_of(TextSource::Text), "edited later");
+        assert_eq!(text_of(TextSource::Entities), "first draft");
+        assert_eq!(text_of(TextSource::Both), "edited later\nfirst draft");
+
+        // Nothing is repeated when one contains the other
+        let message: Message = serde_json::from_value(serde_json::json!({
+            "id": 2,
+            "text": "first draft",
+            "text_entities": [{"type": "plain", "text": "first draft"}],
+        }))
+        .unwrap();
+        let options = SimplifyOptions {
+            text_source: TextSource::Both,
+            ..Default::default()
+        };
+        assert_eq!(extract_message_text(&message, &options).0, "first draft");
+    }
 }
@@ -0,0 +1,112 @@
+use clap::ValueEnum;
+
+use crate::tokenizer::{self, Token};
+
+/// Part of speech to keep in the cloud
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum PartOfSpeech {
+    Noun,
+    Verb,
+    Adj,
+    Any,
+}
+
+// Suffix heuristics, longest first within each list. They work on surface
+// forms, so tagging must happen before stemming.
+#[rustfmt::skip]
+const RU_VERB_SUFFIXES: &[&str] = &[
+    "ться", "тся", "ешь", "ишь", "ете", "ите", "ала", "ало", "али", "ила",
+    "ило", "или", "ыла", "ыли", "ть", "ти", "чь", "ет", "ит", "ют", "ут",
+    "ат", "ят", "ем", "им", "ал", "ил", "ыл",
+];
+#[rustfmt::skip]
+const RU_ADJ_SUFFIXES: &[&str] = &[
+    "ого", "его", "ому", "ему", "ыми", "ими", "ый", "ий", "ой", "ая", "яя",
+    "ое", "ее", "ые", "ие", "ую", "юю", "ых", "их",
+];
+#[rustfmt::skip]
+const EN_VERB_SUFFIXES: &[&str] = &["ing", "ize", "ise", "ate", "ify", "ed"];
+#[rustfmt::skip]
+const EN_ADJ_SUFFIXES: &[&str] = &[
+    "ous", "ful", "ive", "able", "ible", "less", "ish", "ical", "ic", "al",
+];
+
+/// Guess the part of speech of a lowercase word from its ending
+pub fn tag_word(word: &str, lang: &str) -> PartOfSpeech {
+    let (verb_suffixes, adj_suffixes) = match lang.to_lowercase().as_str() {
+        "ru" => (RU_VERB_SUFFIXES, RU_ADJ_SUFFIXES),
+        _ => (EN_VERB_SUFFIXES, EN_ADJ_SUFFIXES),
+    };
+
+    // Leave at least a short root before the suffix
+    let has_suffix = |suffixes: &[&str]| {
+        suffixes.iter().any(|suffix| {
+            word.ends_with(suffix)
+                && word.chars().count() >= suffix.chars().count() + 2
+        })
+    };
+
+    if has_suffix(adj_suffixes) {
+        PartOfSpeech::Adj
+    } else if has_suffix(verb_suffixes) {
+        PartOfSpeech::Verb
+    } else {
+        PartOfSpeech::Noun
+    }
+}
+
+pub fn filter_pos(
+    tokens: Vec<Token>,
+    langs: &[String],
+    keep: PartOfSpeech,
+) -> Vec<Token> {
+    if keep == PartOfSpeech::Any {
+        return tokens;
+    }
+
+    tokens
+        .into_iter()
+        .filter(|token| {
+            let lang = tokenizer::lang_for_word(&token.word, langs);
+            tag_word(&token.word, lang) == keep
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suffix_classes_tagged() {
+        let tags = |words: &[&str], lang: &str| -> Vec<PartOfSpeech> {
+            words.iter().map(|word| tag_word(word, lang)).collect()
+        };
+        use PartOfSpeech::{Adj, Noun, Verb};
+
+        assert_eq!(
+            tags(&["учиться", "читает", "писала", "нести"], "ru"),
+            [Verb, Verb, Verb, Verb]
+        );
+        assert_eq!(
+            tags(&["новый", "синего", "старая", "добрыми"], "ru"),
+            [Adj, Adj, Adj, Adj]
+        );
+        assert_eq!(tags(&["книга", "дом", "кошка"], "ru"), [Noun, Noun, Noun]);
+
+        assert_eq!(
+            tags(&["parsing", "optimize", "verify", "merged"], "en"),
+            [Verb, Verb, Verb, Verb]
+        );
+        assert_eq!(
+            tags(&["famous", "useful", "portable", "magical"], "en"),
+            [Adj, Adj, Adj, Adj]
+        );
+        assert_eq!(tags(&["parser", "files", "crab"], "en"), [Noun, Noun, Noun]);
+
+        // A suffix needs a root of two letters before it
+        assert_eq!(tags(&["red", "ting"], "en"), [Noun, Noun]);
+        assert_eq!(tag_word("мыть", "ru"), Verb);
+        assert_eq!(tag_word("ять", "ru"), Noun);
+    }
+}
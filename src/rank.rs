@@ -0,0 +1,146 @@
+// Ranking of counted words. Uses nothing else from the crate, so
+// benches/top_words.rs can include it.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+
+/// Sort words by descending weight, ties broken alphabetically
+pub fn sort_by_weight(
+    words: &mut [(String, usize)],
+    weight_of: impl Fn(&str, usize) -> f32,
+) {
+    words.sort_by(|a, b| {
+        weight_of(&b.0, b.1)
+            .total_cmp(&weight_of(&a.0, a.1))
+            .then_with(|| a.0.cmp(&b.0))
+    });
+}
+
+/// The `top` heaviest words in the order of `sort_by_weight`. Keeps a heap
+/// of `top` entries instead of sorting every word, which matters once an
+/// export has millions of distinct words.
+pub fn top_words(
+    word_counts: HashMap<String, usize>,
+    top: usize,
+    weight_of: impl Fn(&str, usize) -> f32,
+) -> Vec<(String, usize)> {
+    // Min-heap on rank, so the weakest of the kept words is popped first
+    let mut heap = BinaryHeap::with_capacity(top + 1);
+    for (word, count) in word_counts {
+        heap.push(Reverse(Ranked {
+            weight: weight_of(&word, count),
+            word,
+            count,
+        }));
+        if heap.len() > top {
+            heap.pop();
+        }
+    }
+    heap.into_sorted_vec()
+        .into_iter()
+        .map(|Reverse(ranked)| (ranked.word, ranked.count))
+        .collect()
+}
+
+/// Words in the order of `sort_by_weight`: the `max_words` heaviest, or
+/// every word when `max_words` is 0
+pub fn ranked_words(
+    word_counts: HashMap<String, usize>,
+    max_words: usize,
+    weight_of: impl Fn(&str, usize) -> f32,
+) -> Vec<(String, usize)> {
+    if max_words > 0 {
+        return top_words(word_counts, max_words, weight_of);
+    }
+    let mut words: Vec<_> = word_counts.into_iter().collect();
+    sort_by_weight(&mut words, weight_of);
+    words
+}
+
+/// Orders by rank: heavier is greater, then alphabetically earlier
+struct Ranked {
+    weight: f32,
+    word: String,
+    count: usize,
+}
+
+impl Ord for Ranked {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.weight
+            .total_cmp(&other.weight)
+            .then_with(|| other.word.cmp(&self.word))
+    }
+}
+
+impl PartialOrd for Ranked {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Ranked {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Ranked {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn top_words_matches_full_sort() {
+        // Few distinct counts, so most words tie with others
+        let mut seed: u64 = 42;
+        let word_counts: HashMap<String, usize> = (0..500)
+            .map(|i| {
+                seed = seed
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (format!("w{}", i), (seed >> 33) as usize % 7 + 1)
+            })
+            .collect();
+        let by_count = |_: &str, count: usize| count as f32;
+        // Collapses counts pairwise, ties across different counts
+        let by_half = |_: &str, count: usize| (count / 2) as f32;
+
+        for top in [0, 1, 5, 100, 500, 600] {
+            for weight_of in [&by_count as &dyn Fn(&str, usize) -> f32, &by_half]
+            {
+                let mut sorted: Vec<_> =
+                    word_counts.clone().into_iter().collect();
+                sort_by_weight(&mut sorted, weight_of);
+                sorted.truncate(top);
+                assert_eq!(
+                    top_words(word_counts.clone(), top, weight_of),
+                    sorted
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn max_words_zero_keeps_every_word() {
+        let word_counts: HashMap<String, usize> =
+            [("rust", 5), ("crab", 2), ("zig", 1), ("cargo", 2)]
+                .into_iter()
+                .map(|(word, count)| (word.to_string(), count))
+                .collect();
+        let by_count = |_: &str, count: usize| count as f32;
+
+        let words = ranked_words(word_counts.clone(), 0, by_count);
+        assert_eq!(
+            words,
+            [
+                ("rust".to_string(), 5),
+                ("cargo".to_string(), 2),
+                ("crab".to_string(), 2),
+                ("zig".to_string(), 1)
+            ]
+        );
+        assert_eq!(ranked_words(word_counts.clone(), 2, by_count), words[..2]);
+        assert_eq!(ranked_words(word_counts, 10, by_count), words);
+    }
+}
@@ -0,0 +1,577 @@
+use crate::stats;
+use ab_glyph::{FontVec, PxScale};
+use anyhow::{Context, Result};
+use image::{Rgba, RgbaImage, imageops};
+use imageproc::drawing::{draw_filled_rect_mut, draw_text_mut, text_size};
+use imageproc::rect::Rect;
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+
+// Cloud canvas size at --scale-factor 1, in pixels
+const CLOUD_WIDTH: usize = 800;
+const CLOUD_HEIGHT: usize = 400;
+
+// Legend band layout at --scale-factor 1, in pixels
+const LEGEND_FONT_SIZE: f32 = 20.0;
+const LEGEND_LINE_HEIGHT: u32 = 26;
+const LEGEND_PADDING: u32 = 12;
+
+const LEGEND_BACKGROUND: Rgba<u8> = Rgba([255, 255, 255, 255]);
+const LEGEND_TEXT: Rgba<u8> = Rgba([0, 0, 0, 255]);
+
+// Bar chart layout at --scale-factor 1, in pixels
+const BAR_CHART_WIDTH: u32 = 800;
+const BAR_HEIGHT: u32 = 22;
+const BAR_GAP: u32 = 6;
+const BAR_FONT_SIZE: f32 = 16.0;
+const BAR_COLOR: Rgba<u8> = Rgba([70, 130, 180, 255]);
+
+// DejaVu Sans, used when no font file is given. License in assets/.
+const BUNDLED_FONT: &[u8] = include_bytes!("../assets/DejaVuSans.ttf");
+
+/// Bounds applied to each word's weight before layout
+#[derive(Debug, Clone, Copy)]
+pub struct WeightClamp {
+    pub min: f32,
+    pub max: f32,
+}
+
+impl WeightClamp {
+    pub fn apply(&self, weight: f32) -> f32 {
+        weight.clamp(self.min, self.max)
+    }
+}
+
+/// Parse `--weight-clamp MIN,MAX`
+pub fn parse_weight_clamp(value: &str) -> Result<WeightClamp, String> {
+    let (min, max) = value
+        .split_once(',')
+        .ok_or_else(|| format!("expected MIN,MAX, got '{}'", value))?;
+    let min: f32 = min
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid minimum weight '{}'", min))?;
+    let max: f32 = max
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid maximum weight '{}'", max))?;
+    if !(min.is_finite() && max.is_finite()) || min <= 0.0 || min > max {
+        return Err(format!(
+            "weight range must satisfy 0 < MIN <= MAX, got '{}'",
+            value
+        ));
+    }
+    Ok(WeightClamp { min, max })
+}
+
+/// Grid the final image is cut into with `--tiles`
+#[derive(Debug, Clone, Copy)]
+pub struct Tiles {
+    pub rows: u32,
+    pub cols: u32,
+}
+
+/// Parse `--tiles ROWSxCOLS`, e.g. `2x3`
+pub fn parse_tiles(value: &str) -> Result<Tiles, String> {
+    let (rows, cols) = value
+        .split_once(['x', 'X'])
+        .ok_or_else(|| format!("expected ROWSxCOLS, got '{}'", value))?;
+    let rows: u32 = rows
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid number of rows '{}'", rows))?;
+    let cols: u32 = cols
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid number of columns '{}'", cols))?;
+    if rows == 0 || cols == 0 {
+        return Err(format!("tile grid must be at least 1x1, got '{}'", value));
+    }
+    Ok(Tiles { rows, cols })
+}
+
+/// Cut `image` into a grid of tiles and save each as
+/// `<output stem>_r<row>_c<col>.png` next to `output`, counting from 1.
+/// The last row and column take any leftover pixels.
+pub fn save_tiles(
+    image: &RgbaImage,
+    tiles: Tiles,
+    output: &Path,
+) -> Result<Vec<PathBuf>> {
+    let stem = output
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "wordcloud".to_string());
+    let tile_width = image.width() / tiles.cols;
+    let tile_height = image.height() / tiles.rows;
+    if tile_width == 0 || tile_height == 0 {
+        anyhow::bail!(
+            "a {}x{} image can't be cut into {}x{} tiles",
+            image.width(),
+            image.height(),
+            tiles.rows,
+            tiles.cols
+        );
+    }
+
+    let mut paths = Vec::new();
+    for row in 0..tiles.rows {
+        for col in 0..tiles.cols {
+            let x = col * tile_width;
+            let y = row * tile_height;
+            let width = if col + 1 == tiles.cols {
+                image.width() - x
+            } else {
+                tile_width
+            };
+            let height = if row + 1 == tiles.rows {
+                image.height() - y
+            } else {
+                tile_height
+            };
+            let tile = imageops::crop_imm(image, x, y, width, height).to_image();
+            let path = output.with_file_name(format!(
+                "{}_r{}_c{}.png",
+                stem,
+                row + 1,
+                col + 1
+            ));
+            tile.save(&path).with_context(|| {
+                format!("Failed to save tile {}", path.display())
+            })?;
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
+
+/// Parse `--scale-factor`, which must be a positive number
+pub fn parse_scale_factor(value: &str) -> Result<f32, String> {
+    let scale: f32 = value
+        .parse()
+        .map_err(|_| format!("invalid scale factor '{}'", value))?;
+    if !scale.is_finite() || scale <= 0.0 {
+        return Err(format!("scale factor must be positive, got '{}'", value));
+    }
+    Ok(scale)
+}
+
+/// Cloud canvas width and height for the given scale factor
+pub fn cloud_dimensions(scale: f32) -> (usize, usize) {
+    let scaled = |size: usize| ((size as f32 * scale).round() as usize).max(1);
+    (scaled(CLOUD_WIDTH), scaled(CLOUD_HEIGHT))
+}
+
+/// Fail if an RGBA image of this size would take more than `max_bytes`
+/// in memory, before any time is spent drawing it
+pub fn check_image_size(
+    what: &str,
+    width: u64,
+    height: u64,
+    max_bytes: u64,
+) -> Result<()> {
+    let bytes = width * height * 4;
+    if bytes > max_bytes {
+        anyhow::bail!(
+            "The {} would be {}x{} pixels ({} bytes), over --max-image-bytes {}",
+            what,
+            width,
+            height,
+            bytes,
+            max_bytes
+        );
+    }
+    Ok(())
+}
+
+// Share of the canvas covered by words, and glyph width relative to
+// font size, used to estimate rendered font sizes
+const FILL_RATIO: f32 = 0.5;
+const GLYPH_ASPECT: f32 = 0.6;
+
+/// Rough font size of each word if the cloud's area is shared out in
+/// proportion to weight. Each word covers about `len * 0.6 * size^2`.
+pub fn estimate_font_sizes(
+    words: &[(&str, f32)],
+    width: usize,
+    height: usize,
+) -> Vec<f32> {
+    let total_weight: f32 = words.iter().map(|(_, weight)| weight).sum();
+    if total_weight <= 0.0 {
+        return vec![0.0; words.len()];
+    }
+    let area = (width * height) as f32 * FILL_RATIO;
+    words
+        .iter()
+        .map(|(word, weight)| {
+            let word_area = area * weight / total_weight;
+            let chars = word.chars().count().max(1) as f32;
+            (word_area / (chars * GLYPH_ASPECT))
+                .sqrt()
+                .min(height as f32)
+        })
+        .collect()
+}
+
+fn scale_px(size: u32, scale: f32) -> u32 {
+    (size as f32 * scale).round() as u32
+}
+
+/// Contents of the TrueType font at `font_path`, the bundled DejaVu Sans
+/// without one
+pub fn font_data(font_path: Option<&Path>) -> Result<Cow<'static, [u8]>> {
+    match font_path {
+        Some(font_path) => {
+            std::fs::read(font_path).map(Cow::Owned).with_context(|| {
+                format!("Failed to read font file {}", font_path.display())
+            })
+        }
+        None => Ok(Cow::Borrowed(BUNDLED_FONT)),
+    }
+}
+
+pub fn load_font(font_path: Option<&Path>) -> Result<FontVec> {
+    let data = font_data(font_path)?.into_owned();
+    FontVec::try_from_vec(data).with_context(|| match font_path {
+        Some(font_path) => {
+            format!("Failed to load font from {}", font_path.display())
+        }
+        None => "Failed to load the bundled font".to_string(),
+    })
+}
+
+/// Height of a legend band holding `entries` lines
+pub fn legend_height(entries: usize, scale: f32) -> u32 {
+    if entries == 0 {
+        return 0;
+    }
+    scale_px(
+        LEGEND_PADDING * 2 + LEGEND_LINE_HEIGHT * entries as u32,
+        scale,
+    )
+}
+
+/// Return a copy of the cloud with a band below it listing words and their
+/// counts or weights
+pub fn add_legend(
+    cloud: &RgbaImage,
+    words: &[(String, f32)],
+    font: &FontVec,
+    scale: f32,
+) -> RgbaImage {
+    let band_height = legend_height(words.len(), scale);
+    let padding = scale_px(LEGEND_PADDING, scale);
+    let line_height = scale_px(LEGEND_LINE_HEIGHT, scale);
+    let mut image = RgbaImage::from_pixel(
+        cloud.width(),
+        cloud.height() + band_height,
+        LEGEND_BACKGROUND,
+    );
+    imageops::replace(&mut image, cloud, 0, 0);
+
+    let font_scale = PxScale::from(LEGEND_FONT_SIZE * scale);
+    for (i, (word, weight)) in words.iter().enumerate() {
+        let y = cloud.height() + padding + line_height * i as u32;
+        let line =
+            format!("{}. {} ({})", i + 1, word, stats::format_weight(*weight));
+        draw_text_mut(
+            &mut image,
+            LEGEND_TEXT,
+            padding as i32,
+            y as i32,
+            font_scale,
+            font,
+            &line,
+        );
+    }
+
+    image
+}
+
+/// Width of a bar chart
+pub fn bar_chart_width(scale: f32) -> u32 {
+    scale_px(BAR_CHART_WIDTH, scale)
+}
+
+/// Height of a bar chart with `rows` bars, at least one row tall
+pub fn bar_chart_height(rows: usize, scale: f32) -> u32 {
+    let rows = rows.max(1) as u32;
+    scale_px(
+        LEGEND_PADDING * 2 + (BAR_HEIGHT + BAR_GAP) * rows - BAR_GAP,
+        scale,
+    )
+}
+
+/// Horizontal bar chart of `words`, one bar per word in the given order,
+/// labels on the left and counts or weights after the bars
+pub fn render_bar_chart(
+    words: &[(String, f32)],
+    font: &FontVec,
+    scale: f32,
+) -> RgbaImage {
+    let width = bar_chart_width(scale);
+    let height = bar_chart_height(words.len(), scale);
+    let padding = scale_px(LEGEND_PADDING, scale);
+    let bar_height = scale_px(BAR_HEIGHT, scale);
+    let row_height = scale_px(BAR_HEIGHT + BAR_GAP, scale);
+    let font_scale = PxScale::from(BAR_FONT_SIZE * scale);
+    let mut image = RgbaImage::from_pixel(width, height, LEGEND_BACKGROUND);
+
+    // Bars start after the widest label and leave room for the widest count
+    let text_width = |text: &str| text_size(font_scale, font, text).0;
+    let label_width = words
+        .iter()
+        .map(|(word, _)| text_width(word))
+        .max()
+        .unwrap_or(0);
+    let count_width = words
+        .iter()
+        .map(|(_, weight)| text_width(&stats::format_weight(*weight)))
+        .max()
+        .unwrap_or(0);
+    let bar_start = padding * 2 + label_width;
+    let bar_space = width.saturating_sub(bar_start + count_width + padding * 2);
+    let max_weight = words
+        .iter()
+        .map(|(_, weight)| *weight)
+        .reduce(f32::max)
+        .unwrap_or(1.0);
+
+    let text_offset = bar_height.saturating_sub(font_scale.y as u32) / 2;
+    for (i, (word, weight)) in words.iter().enumerate() {
+        let y = padding + row_height * i as u32;
+        let text_y = (y + text_offset) as i32;
+        draw_text_mut(
+            &mut image,
+            LEGEND_TEXT,
+            padding as i32,
+            text_y,
+            font_scale,
+            font,
+            word,
+        );
+
+        let bar_width = ((*weight / max_weight) as f64 * bar_space as f64)
+            .round()
+            .max(1.0) as u32;
+        draw_filled_rect_mut(
+            &mut image,
+            Rect::at(bar_start as i32, y as i32).of_size(bar_width, bar_height),
+            BAR_COLOR,
+        );
+        draw_text_mut(
+            &mut image,
+            LEGEND_TEXT,
+            (bar_start + bar_width + padding / 2) as i32,
+            text_y,
+            font_scale,
+            font,
+            &stats::format_weight(*weight),
+        );
+    }
+
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn font_falls_back_to_bundled() {
+        assert!(load_font(None).is_ok());
+
+        let missing = Path::new("/nonexistent/font.ttf");
+        let error = load_font(Some(missing)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Failed to read font file /nonexistent/font.ttf"
+        );
+    }
+
+    #[test]
+    fn legend_band_grows_the_image() {
+        let font = load_font(None).unwrap();
+        let (width, height) = cloud_dimensions(1.0);
+        let cloud = RgbaImage::from_pixel(width as u32, height as u32, BAR_COLOR);
+        let words: Vec<(String, f32)> = [("rust", 10.0), ("borrow", 4.5)]
+            .into_iter()
+            .map(|(word, weight)| (word.to_string(), weight))
+            .collect();
+
+        // Without --legend there is no band
+        assert_eq!(legend_height(0, 1.0), 0);
+        assert_eq!(add_legend(&cloud, &[], &font, 1.0).dimensions(), (800, 400));
+
+        let image = add_legend(&cloud, &words, &font, 1.0);
+        assert_eq!(image.dimensions(), (800, 400 + 12 * 2 + 26 * 2));
+        assert_eq!(image.height(), 400 + legend_height(2, 1.0));
+        // The cloud is kept on top and text is drawn in the band
+        assert_eq!(*image.get_pixel(799, 399), BAR_COLOR);
+        let band = imageops::crop_imm(&image, 0, 400, 800, image.height() - 400);
+        let drawn = band
+            .to_image()
+            .pixels()
+            .filter(|pixel| **pixel != LEGEND_BACKGROUND)
+            .count();
+        assert!(drawn > 0);
+
+        assert_eq!(
+            add_legend(&cloud, &words, &font, 2.0).height(),
+            400 + legend_height(2, 2.0)
+        );
+        assert_eq!(legend_height(2, 2.0), 2 * legend_height(2, 1.0));
+    }
+
+    #[test]
+    fn bar_chart_dimensions() {
+        let font = load_font(None).unwrap();
+        let words: Vec<(String, f32)> = [("rust", 10.0), ("borrow", 4.0)]
+            .into_iter()
+            .map(|(word, weight)| (word.to_string(), weight))
+            .collect();
+
+        let chart = render_bar_chart(&words, &font, 1.0);
+        assert_eq!(chart.dimensions(), (800, 12 * 2 + 28 * 2 - 6));
+
+        let chart = render_bar_chart(&words, &font, 2.0);
+        assert_eq!(
+            chart.dimensions(),
+            (bar_chart_width(2.0), bar_chart_height(2, 2.0))
+        );
+        assert_eq!(chart.width(), 1600);
+    }
+
+    #[test]
+    fn weights_clamped_into_range() {
+        let clamp = parse_weight_clamp("2, 50").unwrap();
+        let weights: Vec<f32> = [0.5, 2.0, 17.0, 50.0, 900.0]
+            .into_iter()
+            .map(|weight| clamp.apply(weight))
+            .collect();
+        assert_eq!(weights, [2.0, 2.0, 17.0, 50.0, 50.0]);
+
+        for bad in ["50", "0,10", "10,2", "1,inf", "a,b"] {
+            assert!(parse_weight_clamp(bad).is_err(), "{}", bad);
+        }
+        let fixed = parse_weight_clamp("5,5").unwrap();
+        assert_eq!(fixed.apply(1.0), fixed.apply(1000.0));
+    }
+
+    #[test]
+    fn font_sizes_estimated_from_share_of_canvas() {
+        // Half of 800x400 for one four-letter word: sqrt(160000 / 2.4)
+        let sizes = estimate_font_sizes(&[("rust", 1.0)], 800, 400);
+        assert!((sizes[0] - 258.2).abs() < 0.1, "{}", sizes[0]);
+        // Never taller than the canvas
+        assert_eq!(estimate_font_sizes(&[("a", 1.0)], 800, 400), [400.0]);
+
+        // Four times the weight is twice the size, a longer word is smaller
+        let sizes = estimate_font_sizes(
+            &[("rust", 4.0), ("crab", 1.0), ("rustacean", 1.0)],
+            800,
+            400,
+        );
+        assert!((sizes[0] - 2.0 * sizes[1]).abs() < 1e-3);
+        assert!(sizes[2] < sizes[1]);
+
+        // 5000 equal words get about 3.7px each, below a readable size
+        let many = vec![("word", 1.0); 5000];
+        let sizes = estimate_font_sizes(&many, 800, 400);
+        assert!(sizes.iter().all(|size| *size < 8.0 && *size > 3.0));
+
+        assert_eq!(estimate_font_sizes(&[("rust", 0.0)], 800, 400), [0.0]);
+    }
+
+    #[test]
+    fn scale_factor_two_doubles_the_canvas() {
+        assert_eq!(cloud_dimensions(1.0), (CLOUD_WIDTH, CLOUD_HEIGHT));
+        assert_eq!(cloud_dimensions(2.0), (2 * CLOUD_WIDTH, 2 * CLOUD_HEIGHT));
+        assert_eq!(cloud_dimensions(0.0001), (1, 1));
+
+        // Font sizes grow with the canvas, so the layout keeps its proportions
+        let words = [("rust", 3.0), ("crab", 1.0)];
+        let (width, height) = cloud_dimensions(1.0);
+        let sizes = estimate_font_sizes(&words, width, height);
+        let (width, height) = cloud_dimensions(2.0);
+        let doubled = estimate_font_sizes(&words, width, height);
+        for (size, doubled) in sizes.iter().zip(&doubled) {
+            assert!((doubled - 2.0 * size).abs() < 1e-3, "{} {}", size, doubled);
+        }
+
+        assert_eq!(parse_scale_factor("2"), Ok(2.0));
+        for bad in ["0", "-1", "NaN", "inf", "big"] {
+            assert!(parse_scale_factor(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn image_cut_into_2x2_tiles() {
+        let tiles = parse_tiles("2x2").unwrap();
+        assert!(parse_tiles("0x2").is_err());
+        assert!(parse_tiles("2by2").is_err());
+
+        // Odd sizes: the last row and column take the extra pixel
+        let mut image = RgbaImage::from_pixel(5, 3, LEGEND_BACKGROUND);
+        image.put_pixel(4, 2, BAR_COLOR);
+        let dir = crate::tests::temp_path("tiles");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let paths = save_tiles(&image, tiles, &dir.join("cloud.png")).unwrap();
+        let names: Vec<_> = paths
+            .iter()
+            .map(|path| path.file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "cloud_r1_c1.png",
+                "cloud_r1_c2.png",
+                "cloud_r2_c1.png",
+                "cloud_r2_c2.png"
+            ]
+        );
+        let sizes: Vec<(u32, u32)> = paths
+            .iter()
+            .map(|path| image::open(path).unwrap().to_rgba8().dimensions())
+            .collect();
+        assert_eq!(sizes, [(2, 1), (3, 1), (2, 2), (3, 2)]);
+
+        let corner = image::open(&paths[3]).unwrap().to_rgba8();
+        assert_eq!(*corner.get_pixel(2, 1), BAR_COLOR);
+    }
+
+    #[test]
+    fn oversized_image_rejected() {
+        let max_bytes = 512 * 1024 * 1024;
+        let (width, height) = cloud_dimensions(1.0);
+        assert!(
+            check_image_size(
+                "word cloud",
+                width as u64,
+                height as u64,
+                max_bytes
+            )
+            .is_ok()
+        );
+
+        // 100x the default canvas is 80000x40000 pixels, 12.8 GB of RGBA
+        let (width, height) = cloud_dimensions(100.0);
+        let error = check_image_size(
+            "word cloud",
+            width as u64,
+            height as u64,
+            max_bytes,
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The word cloud would be 80000x40000 pixels (12800000000 bytes), \
+             over --max-image-bytes 536870912"
+        );
+
+        // The limit itself is allowed
+        assert!(check_image_size("bar chart", 100, 100, 40_000).is_ok());
+        assert!(check_image_size("bar chart", 100, 100, 39_999).is_err());
+    }
+}
@@ -0,0 +1,207 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Overall shape of an export file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// Telegram Desktop export of one chat: header fields plus `messages`
+    SingleChat,
+    /// Telegram Desktop export of the whole account, chats under `chats.list`
+    Account,
+    /// Bare JSON array of messages
+    MessageArray,
+    #[default]
+    Unknown,
+}
+
+impl ExportFormat {
+    /// Shape implied by a top-level key of an export object
+    pub fn from_key(key: &str) -> Option<Self> {
+        match key {
+            "chats" | "personal_information" => Some(Self::Account),
+            "messages" => Some(Self::SingleChat),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::SingleChat => "single chat export",
+            Self::Account => "full account export",
+            Self::MessageArray => "array of messages",
+            Self::Unknown => "unrecognized format",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Kind of chat a single chat export comes from, the header's `type`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChatType {
+    PersonalChat,
+    BotChat,
+    SavedMessages,
+    PrivateGroup,
+    PrivateSupergroup,
+    PublicSupergroup,
+    PrivateChannel,
+    PublicChannel,
+}
+
+impl ChatType {
+    pub fn from_export(value: &str) -> Option<Self> {
+        let chat_type = match value {
+            "personal_chat" => Self::PersonalChat,
+            "bot_chat" => Self::BotChat,
+            "saved_messages" => Self::SavedMessages,
+            "private_group" => Self::PrivateGroup,
+            "private_supergroup" => Self::PrivateSupergroup,
+            "public_supergroup" => Self::PublicSupergroup,
+            "private_channel" => Self::PrivateChannel,
+            "public_channel" => Self::PublicChannel,
+            _ => return None,
+        };
+        Some(chat_type)
+    }
+
+    /// Channel posts are all `from` the channel, the author is only in the
+    /// post signature
+    pub fn is_channel(self) -> bool {
+        matches!(self, Self::PrivateChannel | Self::PublicChannel)
+    }
+}
+
+impl fmt::Display for ChatType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::PersonalChat => "personal chat",
+            Self::BotChat => "bot chat",
+            Self::SavedMessages => "saved messages",
+            Self::PrivateGroup => "private group",
+            Self::PrivateSupergroup => "private supergroup",
+            Self::PublicSupergroup => "public supergroup",
+            Self::PrivateChannel => "private channel",
+            Self::PublicChannel => "public channel",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Top-level fields of an export, read with `set_field` as the messages
+/// are parsed
+#[derive(Debug, Default, Clone)]
+pub struct ExportHeader {
+    pub name: Option<String>,
+    pub r#type: Option<String>,
+    pub id: Option<i64>,
+    /// Account exports: address book and frequent contacts
+    contacts: Option<PeopleList>,
+    frequent_contacts: Option<PeopleList>,
+    /// Participant lists some exports and export tools add
+    participants: Option<Vec<Person>>,
+}
+
+/// `{"list": [...]}` wrapper used by account exports
+#[derive(Debug, Default, Clone, Deserialize)]
+struct PeopleList {
+    #[serde(default)]
+    list: Vec<Person>,
+}
+
+/// A contact or participant, whose `id` messages refer to as `from_id`
+#[derive(Debug, Clone, Deserialize)]
+struct Person {
+    #[serde(alias = "user_id")]
+    id: Option<serde_json::Value>,
+    name: Option<String>,
+    first_name: Option<String>,
+    last_name: Option<String>,
+}
+
+impl Person {
+    /// Id in the `from_id` form: numbers become `user<id>`
+    fn sender_id(&self) -> Option<String> {
+        match self.id.as_ref()? {
+            serde_json::Value::Number(id) => Some(format!("user{}", id)),
+            serde_json::Value::String(id) => Some(id.clone()),
+            _ => None,
+        }
+    }
+
+    fn display_name(&self) -> Option<String> {
+        if let Some(name) = self.name.as_ref().filter(|name| !name.is_empty()) {
+            return Some(name.clone());
+        }
+        let full_name = [&self.first_name, &self.last_name]
+            .into_iter()
+            .flatten()
+            .filter(|part| !part.is_empty())
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(" ");
+        (!full_name.is_empty()).then_some(full_name)
+    }
+}
+
+impl ExportHeader {
+    /// Whether `set_field` reads the top-level key `key`
+    pub fn is_field(key: &str) -> bool {
+        matches!(
+            key,
+            "name"
+                | "type"
+                | "id"
+                | "contacts"
+                | "frequent_contacts"
+                | "participants"
+                | "members"
+        )
+    }
+
+    /// Fill in the field for top-level key `key`. Values of an unexpected
+    /// shape leave the field empty.
+    pub fn set_field(&mut self, key: &str, value: serde_json::Value) {
+        fn read<T: for<'de> Deserialize<'de>>(
+            value: serde_json::Value,
+        ) -> Option<T> {
+            serde_json::from_value(value).ok()
+        }
+        match key {
+            "name" => self.name = read(value),
+            "type" => self.r#type = read(value),
+            "id" => self.id = read(value),
+            "contacts" => self.contacts = read(value),
+            "frequent_contacts" => self.frequent_contacts = read(value),
+            "participants" | "members" => self.participants = read(value),
+            _ => {}
+        }
+    }
+
+    /// Display names by `from_id` from the contact and participant lists,
+    /// for messages that carry only an id
+    pub fn user_names(&self) -> HashMap<String, String> {
+        let lists = [&self.contacts, &self.frequent_contacts]
+            .into_iter()
+            .flatten()
+            .map(|people| people.list.as_slice())
+            .chain(self.participants.as_deref());
+
+        let mut names = HashMap::new();
+        for person in lists.flatten() {
+            if let (Some(id), Some(name)) =
+                (person.sender_id(), person.display_name())
+            {
+                names.entry(id).or_insert(name);
+            }
+        }
+        names
+    }
+
+    /// The chat type, `None` for account exports and unknown types
+    pub fn chat_type(&self) -> Option<ChatType> {
+        self.r#type.as_deref().and_then(ChatType::from_export)
+    }
+}
@@ -0,0 +1,908 @@
+use crate::parse::{Message, SimpleMessage};
+use crate::time;
+use crate::tokenizer::{self, Token};
+use clap::ValueEnum;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// How many messages and tokens survive each pipeline stage
+#[derive(Debug, Default, Serialize)]
+pub struct Funnel {
+    /// Messages read from the exports
+    pub messages: usize,
+    /// Messages left with text after message filters
+    pub with_text: usize,
+    pub tokens: usize,
+    pub after_stopwords: usize,
+    pub after_stem: usize,
+    /// Distinct words counted
+    pub unique: usize,
+    /// Words drawn in the cloud
+    pub r#final: usize,
+}
+
+/// Facts about the counted messages, gathered one message at a time
+#[derive(Debug, Default)]
+pub struct MessageSummary {
+    pub time_range: time::TimeRange,
+    pub participants: HashSet<String>,
+    /// Emoji used in message texts
+    pub emoji: HashMap<String, usize>,
+}
+
+impl MessageSummary {
+    pub fn add(&mut self, message: &SimpleMessage) {
+        if let Some(timestamp) = message.timestamp {
+            self.time_range.add(timestamp);
+        }
+        if !self.participants.contains(&message.username) {
+            self.participants.insert(message.username.clone());
+        }
+        let mut buf = [0; 4];
+        for c in message.text.chars() {
+            // Nothing below the copyright sign is an emoji on its own, skip
+            // the lookup for ASCII
+            if (c as u32) < 0xA9 {
+                continue;
+            }
+            if let Some(emoji) = emojis::get(c.encode_utf8(&mut buf)) {
+                *self.emoji.entry(emoji.as_str().to_string()).or_insert(0) += 1;
+            }
+        }
+    }
+}
+
+/// Change in one word's count between two frequency maps
+#[derive(Debug)]
+pub struct WordDelta {
+    pub word: String,
+    pub before: usize,
+    pub after: usize,
+}
+
+impl WordDelta {
+    pub fn delta(&self) -> i64 {
+        self.after as i64 - self.before as i64
+    }
+
+    /// One of `appeared`, `disappeared`, `increased` or `decreased`
+    pub fn change(&self) -> &'static str {
+        match (self.before, self.after) {
+            (0, _) => "appeared",
+            (_, 0) => "disappeared",
+            (before, after) if after > before => "increased",
+            _ => "decreased",
+        }
+    }
+}
+
+/// Words whose count differs between `before` and `after`, biggest
+/// changes first, ties broken alphabetically
+pub fn diff_counts(
+    before: &HashMap<String, usize>,
+    after: &HashMap<String, usize>,
+) -> Vec<WordDelta> {
+    let mut deltas: Vec<WordDelta> = before
+        .keys()
+        .chain(after.keys().filter(|word| !before.contains_key(*word)))
+        .map(|word| WordDelta {
+            word: word.clone(),
+            before: before.get(word).copied().unwrap_or(0),
+            after: after.get(word).copied().unwrap_or(0),
+        })
+        .filter(|delta| delta.before != delta.after)
+        .collect();
+    deltas.sort_by(|a, b| {
+        b.delta()
+            .abs()
+            .cmp(&a.delta().abs())
+            .then_with(|| a.word.cmp(&b.word))
+    });
+    deltas
+}
+
+/// Sum of token weights per word.
+///
+/// With a halflife, each token is weighted by `0.5^(age / halflife)` where
+/// age is counted back from the newest message; tokens from messages
+/// without a timestamp weigh 1. Emphasized tokens are further multiplied
+/// by `emphasis`.
+pub fn token_weights(
+    tokens: &[Token],
+    messages: &[SimpleMessage],
+    halflife_days: Option<f64>,
+    emphasis: f64,
+) -> HashMap<String, f64> {
+    let newest = messages.iter().filter_map(|msg| msg.timestamp).max();
+
+    let mut weights = HashMap::new();
+    for token in tokens {
+        let timestamp = messages[token.message].timestamp;
+        let mut weight = match (halflife_days, newest, timestamp) {
+            (Some(halflife), Some(newest), Some(timestamp)) => {
+                let halflife = halflife * time::SECONDS_PER_DAY as f64;
+                0.5f64.powf((newest - timestamp) as f64 / halflife)
+            }
+            _ => 1.0,
+        };
+        if token.emphasized {
+            weight *= emphasis;
+        }
+        *weights.entry(token.word.clone()).or_insert(0.0) += weight;
+    }
+    weights
+}
+
+/// Validate a positive `--weight-emphasized` factor
+pub fn parse_factor(value: &str) -> Result<f64, String> {
+    let factor: f64 = value
+        .parse()
+        .map_err(|_| format!("invalid factor '{}'", value))?;
+    if !factor.is_finite() || factor <= 0.0 {
+        return Err(format!("factor must be positive, got '{}'", value));
+    }
+    Ok(factor)
+}
+
+/// Validate a `--min-count-percent` value, above 0 and at most 100
+pub fn parse_percent(value: &str) -> Result<f64, String> {
+    let percent: f64 = value
+        .parse()
+        .map_err(|_| format!("invalid percentage '{}'", value))?;
+    if !(percent > 0.0 && percent <= 100.0) {
+        return Err(format!(
+            "percentage must be above 0 and at most 100, got '{}'",
+            value
+        ));
+    }
+    Ok(percent)
+}
+
+/// Smallest count that reaches `percent` of the most frequent word's count
+pub fn count_floor(word_counts: &HashMap<String, usize>, percent: f64) -> usize {
+    let max_count = word_counts.values().copied().max().unwrap_or(0);
+    (max_count as f64 * percent / 100.0).ceil() as usize
+}
+
+/// `percent` of the heaviest word's weight
+pub fn weight_floor(weights: &HashMap<String, f64>, percent: f64) -> f64 {
+    let max_weight = weights.values().copied().fold(0.0, f64::max);
+    max_weight * percent / 100.0
+}
+
+/// A count or weight for display: whole numbers as they are, others with
+/// two decimals
+pub fn format_weight(weight: f32) -> String {
+    if weight.fract() == 0.0 {
+        format!("{:.0}", weight)
+    } else {
+        format!("{:.2}", weight)
+    }
+}
+
+/// Number of distinct messages each word appears in
+pub fn document_frequency(tokens: &[Token]) -> HashMap<String, usize> {
+    // Remember the last message each word was seen in, so repeats within a
+    // message count once. Tokens come grouped by message.
+    let mut seen: HashMap<&str, (usize, usize)> = HashMap::new();
+    for token in tokens {
+        let entry = seen.entry(&token.word).or_insert((token.message, 0));
+        if entry.1 == 0 || entry.0 != token.message {
+            *entry = (token.message, entry.1 + 1);
+        }
+    }
+    seen.into_iter()
+        .map(|(word, (_, df))| (word.to_string(), df))
+        .collect()
+}
+
+/// Number of distinct users who wrote each word
+pub fn sender_counts(
+    tokens: &[Token],
+    messages: &[SimpleMessage],
+) -> HashMap<String, usize> {
+    let mut senders: HashMap<&str, HashSet<&str>> = HashMap::new();
+    for token in tokens {
+        senders
+            .entry(&token.word)
+            .or_default()
+            .insert(&messages[token.message].username);
+    }
+    senders
+        .into_iter()
+        .map(|(word, users)| (word.to_string(), users.len()))
+        .collect()
+}
+
+/// Words ordered by descending frequency, ties broken alphabetically
+pub fn rank_words(word_counts: &HashMap<String, usize>) -> Vec<(String, usize)> {
+    let mut ranked: Vec<_> = word_counts
+        .iter()
+        .map(|(word, count)| (word.clone(), *count))
+        .collect();
+    ranked.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    ranked
+}
+
+/// Words used exactly once, alphabetically
+pub fn hapax_legomena(word_counts: &HashMap<String, usize>) -> Vec<String> {
+    let mut words: Vec<String> = word_counts
+        .iter()
+        .filter(|(_, count)| **count == 1)
+        .map(|(word, _)| word.clone())
+        .collect();
+    words.sort();
+    words
+}
+
+/// The `top` longest words by characters with their counts, ties broken by
+/// count and then alphabetically
+pub fn longest_words(
+    word_counts: &HashMap<String, usize>,
+    top: usize,
+) -> Vec<(&str, usize)> {
+    let mut words: Vec<(&str, usize, usize)> = word_counts
+        .iter()
+        .map(|(word, count)| (word.as_str(), word.chars().count(), *count))
+        .collect();
+    words.sort_by(|a, b| {
+        b.1.cmp(&a.1)
+            .then_with(|| b.2.cmp(&a.2))
+            .then_with(|| a.0.cmp(b.0))
+    });
+    words
+        .into_iter()
+        .take(top)
+        .map(|(word, _, count)| (word, count))
+        .collect()
+}
+
+/// Least-squares slope of log(frequency) against log(rank).
+/// Natural language usually lands near -1.
+pub fn zipf_slope(ranked: &[(String, usize)]) -> Option<f64> {
+    if ranked.len() < 2 {
+        return None;
+    }
+
+    let points: Vec<(f64, f64)> = ranked
+        .iter()
+        .enumerate()
+        .map(|(i, (_, count))| (((i + 1) as f64).ln(), (*count as f64).ln()))
+        .collect();
+
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+    let covariance: f64 = points
+        .iter()
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum();
+    let variance: f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+
+    if variance == 0.0 {
+        None
+    } else {
+        Some(covariance / variance)
+    }
+}
+
+/// Rank each user's words by TF-IDF, treating all of a user's messages as
+/// one document. Returns users alphabetically with their `top` words.
+pub fn tfidf_by_user(
+    tokens: &[Token],
+    messages: &[SimpleMessage],
+    top: usize,
+) -> Vec<(String, Vec<(String, f64)>)> {
+    let mut user_counts: BTreeMap<&str, HashMap<&str, usize>> = BTreeMap::new();
+    for token in tokens {
+        let user = messages[token.message].username.as_str();
+        *user_counts
+            .entry(user)
+            .or_default()
+            .entry(token.word.as_str())
+            .or_insert(0) += 1;
+    }
+
+    // Number of users using each word
+    let mut document_frequency: HashMap<&str, usize> = HashMap::new();
+    for counts in user_counts.values() {
+        for word in counts.keys() {
+            *document_frequency.entry(word).or_insert(0) += 1;
+        }
+    }
+
+    // Smoothed idf keeps words shared by everyone above zero
+    let documents = user_counts.len() as f64;
+    let idf = |word: &str| {
+        ((1.0 + documents) / (1.0 + document_frequency[word] as f64)).ln() + 1.0
+    };
+
+    user_counts
+        .into_iter()
+        .map(|(user, counts)| {
+            let total = counts.values().sum::<usize>() as f64;
+            let mut scores: Vec<(String, f64)> = counts
+                .iter()
+                .map(|(word, count)| {
+                    (word.to_string(), *count as f64 / total * idf(word))
+                })
+                .collect();
+            scores
+                .sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            scores.truncate(top);
+            (user.to_string(), scores)
+        })
+        .collect()
+}
+
+/// Word counts per hour of day (index 0-23) in the given UTC offset.
+/// Tokens from messages without a timestamp are left out.
+pub fn count_by_hour(
+    tokens: &[Token],
+    messages: &[SimpleMessage],
+    utc_offset: i64,
+) -> Vec<HashMap<String, usize>> {
+    let mut buckets = vec![HashMap::new(); 24];
+    for token in tokens {
+        if let Some(timestamp) = messages[token.message].timestamp {
+            let hour = time::hour_of_day(timestamp, utc_offset) as usize;
+            *buckets[hour].entry(token.word.clone()).or_insert(0) += 1;
+        }
+    }
+    buckets
+}
+
+/// Word counts per forum topic, ordered by topic title.
+/// Tokens from messages outside any topic are left out.
+pub fn count_by_topic(
+    tokens: &[Token],
+    messages: &[SimpleMessage],
+) -> Vec<(String, HashMap<String, usize>)> {
+    count_by_group(tokens, messages, |message| message.topic.clone())
+}
+
+/// Word counts per media type of captioned messages, ordered by type.
+/// Tokens from plain text messages are left out.
+pub fn count_by_media_type(
+    tokens: &[Token],
+    messages: &[SimpleMessage],
+) -> Vec<(String, HashMap<String, usize>)> {
+    count_by_group(tokens, messages, |message| message.media_type.clone())
+}
+
+/// Word counts per calendar year in the given UTC offset, oldest first.
+/// Tokens from messages without a timestamp are left out.
+pub fn count_by_year(
+    tokens: &[Token],
+    messages: &[SimpleMessage],
+    utc_offset: i64,
+) -> Vec<(String, HashMap<String, usize>)> {
+    count_by_group(tokens, messages, |message| {
+        let timestamp = message.timestamp?;
+        Some(time::year_of(timestamp, utc_offset).to_string())
+    })
+}
+
+fn count_by_group(
+    tokens: &[Token],
+    messages: &[SimpleMessage],
+    group_of: impl Fn(&SimpleMessage) -> Option<String>,
+) -> Vec<(String, HashMap<String, usize>)> {
+    let message_groups: Vec<Option<String>> =
+        messages.iter().map(group_of).collect();
+
+    let mut groups: BTreeMap<&str, HashMap<String, usize>> = BTreeMap::new();
+    for token in tokens {
+        if let Some(group) = &message_groups[token.message] {
+            *groups
+                .entry(group)
+                .or_default()
+                .entry(token.word.clone())
+                .or_insert(0) += 1;
+        }
+    }
+    groups
+        .into_iter()
+        .map(|(group, counts)| (group.to_string(), counts))
+        .collect()
+}
+
+/// Reactions left by one user, with a count per emoji
+#[derive(Debug)]
+pub struct UserReactions {
+    pub user: String,
+    pub total: usize,
+    /// Most used first
+    pub emoji: Vec<(String, usize)>,
+}
+
+/// Tally reactions per user from the `recent` reactor lists. Telegram only
+/// exports the latest few reactors of each reaction, so busy messages are
+/// undercounted. Returns the most active users first.
+pub fn reactions_by_user(messages: &[Message]) -> Vec<UserReactions> {
+    let mut per_user: HashMap<String, HashMap<String, usize>> = HashMap::new();
+    for message in messages {
+        for reaction in &message.reactions {
+            for reactor in &reaction.recent {
+                let user = reactor.from.as_ref().unwrap_or(&reactor.from_id);
+                *per_user
+                    .entry(user.clone())
+                    .or_default()
+                    .entry(reaction.emoji.clone())
+                    .or_insert(0) += 1;
+            }
+        }
+    }
+
+    let mut stats: Vec<UserReactions> = per_user
+        .into_iter()
+        .map(|(user, emoji_counts)| {
+            let emoji = rank_words(&emoji_counts);
+            let total = emoji.iter().map(|(_, count)| count).sum();
+            UserReactions { user, total, emoji }
+        })
+        .collect();
+    stats.sort_by(|a, b| b.total.cmp(&a.total).then_with(|| a.user.cmp(&b.user)));
+    stats
+}
+
+/// Count who replies to whom as `(replier, replied to, replies)`, most
+/// replies first. Replies to messages that aren't in `messages`, and
+/// messages without a known sender or an id, are skipped.
+pub fn reply_counts(messages: &[Message]) -> Vec<(String, String, usize)> {
+    let senders: HashMap<i64, &String> = messages
+        .iter()
+        .filter_map(|msg| Some((msg.id?, msg.sender()?)))
+        .collect();
+
+    let mut counts: HashMap<(&String, &String), usize> = HashMap::new();
+    for message in messages {
+        let replied_to =
+            message.reply_to_message_id.and_then(|id| senders.get(&id));
+        if let (Some(from), Some(to)) = (message.sender(), replied_to) {
+            *counts.entry((from, to)).or_insert(0) += 1;
+        }
+    }
+
+    let mut counts: Vec<(String, String, usize)> = counts
+        .into_iter()
+        .map(|((from, to), replies)| (from.clone(), to.clone(), replies))
+        .collect();
+    counts.sort_by(|a, b| {
+        b.2.cmp(&a.2)
+            .then_with(|| a.0.cmp(&b.0))
+            .then_with(|| a.1.cmp(&b.1))
+    });
+    counts
+}
+
+/// How message length is measured for `length_histogram`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LengthUnit {
+    Words,
+    Chars,
+}
+
+/// Occurrences of each character in the message texts, most frequent
+/// first. With `alphanumeric_only`, whitespace, punctuation and symbols are
+/// left out.
+pub fn char_frequencies(
+    messages: &[SimpleMessage],
+    alphanumeric_only: bool,
+) -> Vec<(char, usize)> {
+    let mut counts: HashMap<char, usize> = HashMap::new();
+    for message in messages {
+        for c in message.text.chars() {
+            if !alphanumeric_only || c.is_alphanumeric() {
+                *counts.entry(c).or_insert(0) += 1;
+            }
+        }
+    }
+
+    let mut counts: Vec<(char, usize)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts
+}
+
+/// Messages per length bucket, buckets doubling in size (1, 2-3, 4-7, ...)
+/// and returned as `(from, to, messages)` with both ends inclusive.
+/// Empty messages go to a `0` bucket.
+pub fn length_histogram(
+    messages: &[SimpleMessage],
+    unit: LengthUnit,
+) -> Vec<(usize, usize, usize)> {
+    let mut counts: BTreeMap<u32, usize> = BTreeMap::new();
+    for message in messages {
+        let length = match unit {
+            LengthUnit::Words => tokenizer::count_message_words(&message.text),
+            LengthUnit::Chars => message.text.chars().count(),
+        };
+        // Bucket 0 holds empty messages, bucket k lengths 2^(k-1)..2^k-1
+        let bucket = usize::BITS - length.leading_zeros();
+        *counts.entry(bucket).or_insert(0) += 1;
+    }
+
+    let last = counts.keys().next_back().copied().unwrap_or(0);
+    (0..=last)
+        .map(|bucket| {
+            let (from, to) = match bucket {
+                0 => (0, 0),
+                _ => (1 << (bucket - 1), (1 << bucket) - 1),
+            };
+            (from, to, counts.get(&bucket).copied().unwrap_or(0))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One token per message holding its whole text
+    fn whole_text_tokens(messages: &[SimpleMessage]) -> Vec<Token> {
+        messages
+            .iter()
+            .enumerate()
+            .map(|(message, simple)| Token {
+                word: simple.text.clone(),
+                original: simple.text.clone(),
+                message,
+                emphasized: false,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn summary_counts_emoji_below_u2000() {
+        let mut summary = MessageSummary::default();
+        summary.add(&SimpleMessage {
+            username: "Alice".to_string(),
+            text: "Rust © 2024 🔥🔥".to_string(),
+            ..Default::default()
+        });
+        assert_eq!(summary.emoji.len(), 2);
+        assert!(summary.emoji.keys().any(|emoji| emoji.starts_with('©')));
+        assert_eq!(summary.emoji["🔥"], 2);
+    }
+
+    #[test]
+    fn reactions_counted_per_user() {
+        let reactor = |from: &str, from_id: &str| serde_json::json!({"from": from, "from_id": from_id, "date": "2024-03-01T10:00:00"});
+        let messages: Vec<Message> = serde_json::from_value(serde_json::json!([
+            {
+                "id": 1,
+                "type": "message",
+                "from": "Alice",
+                "text": "first",
+                "reactions": [
+                    {"type": "emoji", "count": 2, "emoji": "👍",
+                     "recent": [reactor("Bob", "user2"), reactor("Carol", "user3")]},
+                    {"type": "emoji", "count": 1, "emoji": "🔥",
+                     "recent": [reactor("Bob", "user2")]},
+                ],
+            },
+            {
+                "id": 2,
+                "type": "message",
+                "from": "Carol",
+                "text": "second",
+                "reactions": [
+                    {"type": "emoji", "count": 1, "emoji": "👍",
+                     "recent": [reactor("Bob", "user2")]},
+                ],
+            },
+        ]))
+        .unwrap();
+
+        let stats = reactions_by_user(&messages);
+        let totals: Vec<(&str, usize)> = stats
+            .iter()
+            .map(|user| (user.user.as_str(), user.total))
+            .collect();
+        assert_eq!(totals, [("Bob", 3), ("Carol", 1)]);
+        assert_eq!(
+            stats[0].emoji,
+            [("👍".to_string(), 2), ("🔥".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn lengths_fall_into_doubling_buckets() {
+        let messages: Vec<SimpleMessage> =
+            ["", "a", "abc", "abcd", "abcdefg", "abcdefgh"]
+                .into_iter()
+                .map(|text| SimpleMessage {
+                    text: text.to_string(),
+                    ..Default::default()
+                })
+                .collect();
+
+        assert_eq!(
+            length_histogram(&messages, LengthUnit::Chars),
+            [(0, 0, 1), (1, 1, 1), (2, 3, 1), (4, 7, 2), (8, 15, 1)]
+        );
+        // In words every non-empty text here is one word
+        assert_eq!(
+            length_histogram(&messages, LengthUnit::Words),
+            [(0, 0, 1), (1, 1, 5)]
+        );
+    }
+
+    #[test]
+    fn captions_grouped_by_photo_and_video() {
+        let messages: Vec<Message> = serde_json::from_value(serde_json::json!([
+            {"id": 1, "type": "message", "from": "Alice", "text": "sunset",
+             "photo": "photos/photo_1.jpg", "width": 1280, "height": 720},
+            {"id": 2, "type": "message", "from": "Bob", "text": "demo",
+             "file": "video_files/demo.mp4", "media_type": "video_file"},
+            {"id": 3, "type": "message", "from": "Bob", "text": "plain"},
+        ]))
+        .unwrap();
+        let kinds: Vec<Option<&str>> =
+            messages.iter().map(Message::media_kind).collect();
+        assert_eq!(kinds, [Some("photo"), Some("video_file"), None]);
+
+        let simple =
+            crate::parse::simplify_messages(&messages, &Default::default());
+        let groups = count_by_media_type(&whole_text_tokens(&simple), &simple);
+        let words: Vec<(&str, Vec<&str>)> = groups
+            .iter()
+            .map(|(kind, counts)| {
+                (kind.as_str(), counts.keys().map(String::as_str).collect())
+            })
+            .collect();
+        assert_eq!(
+            words,
+            [("photo", vec!["sunset"]), ("video_file", vec!["demo"])]
+        );
+    }
+
+    #[test]
+    fn words_split_by_year_in_utc_offset() {
+        let message = |text: &str, timestamp: Option<i64>| SimpleMessage {
+            text: text.to_string(),
+            timestamp,
+            ..Default::default()
+        };
+        let messages = [
+            // 2023-12-31 23:30 and 2024-01-01 00:30 UTC
+            message("eve", Some(1704065400)),
+            message("morning", Some(1704069000)),
+            message("undated", None),
+        ];
+        let tokens = whole_text_tokens(&messages);
+
+        let years: Vec<(String, Vec<String>)> =
+            count_by_year(&tokens, &messages, 0)
+                .into_iter()
+                .map(|(year, counts)| (year, counts.into_keys().collect()))
+                .collect();
+        assert_eq!(
+            years,
+            [
+                ("2023".to_string(), vec!["eve".to_string()]),
+                ("2024".to_string(), vec!["morning".to_string()])
+            ]
+        );
+
+        // An hour east of UTC both fall in 2024
+        let years = count_by_year(&tokens, &messages, 3600);
+        assert_eq!(years.len(), 1);
+        assert_eq!(years[0].0, "2024");
+        assert_eq!(years[0].1.len(), 2);
+    }
+
+    #[test]
+    fn words_split_by_hour_in_utc_offset() {
+        let message = |text: &str, timestamp: Option<i64>| SimpleMessage {
+            text: text.to_string(),
+            timestamp,
+            ..Default::default()
+        };
+        let messages = [
+            // 2024-03-01 09:15 and 23:45 UTC
+            message("standup", Some(1709284500)),
+            message("deploy", Some(1709336700)),
+            message("undated", None),
+        ];
+        let tokens = whole_text_tokens(&messages);
+
+        let hours_of = |buckets: Vec<HashMap<String, usize>>| {
+            buckets
+                .into_iter()
+                .enumerate()
+                .filter(|(_, counts)| !counts.is_empty())
+                .map(|(hour, counts)| (hour, counts.into_keys().collect()))
+                .collect::<Vec<(usize, Vec<String>)>>()
+        };
+        assert_eq!(
+            hours_of(count_by_hour(&tokens, &messages, 0)),
+            [
+                (9, vec!["standup".to_string()]),
+                (23, vec!["deploy".to_string()])
+            ]
+        );
+
+        // At +03:00 the late deploy moves past midnight
+        let offset = crate::time::parse_utc_offset("+03:00").unwrap();
+        assert_eq!(
+            hours_of(count_by_hour(&tokens, &messages, offset)),
+            [
+                (2, vec!["deploy".to_string()]),
+                (12, vec!["standup".to_string()])
+            ]
+        );
+    }
+
+    #[test]
+    fn token_one_halflife_old_weighs_half() {
+        let day = crate::time::SECONDS_PER_DAY;
+        let now = 1709283600;
+        let message = |text: &str, timestamp: Option<i64>| SimpleMessage {
+            text: text.to_string(),
+            timestamp,
+            ..Default::default()
+        };
+        let messages = [
+            message("older", Some(now - 4 * day)),
+            message("now", Some(now)),
+            message("old", Some(now - 2 * day)),
+            message("undated", None),
+        ];
+        let tokens = whole_text_tokens(&messages);
+
+        // Ages count back from the newest message, not from today
+        let weights = token_weights(&tokens, &messages, Some(2.0), 1.0);
+        assert_eq!(weights["now"], 1.0);
+        assert_eq!(weights["old"], 0.5);
+        assert_eq!(weights["older"], 0.25);
+        assert_eq!(weights["undated"], 1.0);
+
+        let weights = token_weights(&tokens, &messages, None, 1.0);
+        assert!(weights.values().all(|weight| *weight == 1.0));
+    }
+
+    #[test]
+    fn replies_counted_per_pair() {
+        let messages: Vec<Message> = serde_json::from_value(serde_json::json!([
+            {"id": 1, "type": "message", "from": "Alice", "text": "question"},
+            {"id": 2, "type": "message", "from": "Bob", "text": "answer",
+             "reply_to_message_id": 1},
+            {"id": 3, "type": "message", "from": "Carol", "text": "me too",
+             "reply_to_message_id": 1},
+            {"id": 4, "type": "message", "from": "Bob", "text": "and",
+             "reply_to_message_id": 1},
+            {"id": 5, "type": "message", "from": "Alice", "text": "thanks",
+             "reply_to_message_id": 2},
+            // Replied-to message not in the export
+            {"id": 6, "type": "message", "from": "Carol", "text": "old",
+             "reply_to_message_id": 99},
+        ]))
+        .unwrap();
+
+        let pair = |from: &str, to: &str, replies| {
+            (from.to_string(), to.to_string(), replies)
+        };
+        assert_eq!(
+            reply_counts(&messages),
+            [
+                pair("Bob", "Alice", 2),
+                pair("Alice", "Bob", 1),
+                pair("Carol", "Alice", 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn messages_without_id_are_not_reply_targets() {
+        let mut messages: Vec<Message> =
+            serde_json::from_value(serde_json::json!([
+                {"type": "message", "from": "Alice", "text": "no id"},
+                {"type": "message", "from": "Bob", "text": "no id either",
+                 "reply_to_message_id": 3},
+                {"id": 3, "type": "service", "from": "Carol",
+                 "action": "topic_created", "title": "Rust"},
+                // Used to hit whichever id-less message defaulted to 0 last
+                {"id": 4, "type": "message", "from": "Dan", "text": "what?",
+                 "reply_to_message_id": 0},
+            ]))
+            .unwrap();
+
+        assert_eq!(
+            reply_counts(&messages),
+            [("Bob".to_string(), "Carol".to_string(), 1)]
+        );
+
+        crate::parse::resolve_topics(&mut messages);
+        let topics: Vec<&str> = messages
+            .iter()
+            .map(|msg| msg.topic.as_deref().unwrap())
+            .collect();
+        assert_eq!(topics, ["General", "Rust", "Rust", "General"]);
+
+        crate::filter::filter_by_ids(&mut messages, &[0].into());
+        assert_eq!(messages.len(), 4);
+    }
+
+    #[test]
+    fn longest_words_by_characters() {
+        let word_counts: HashMap<String, usize> = [
+            ("достопримечательность", 1),
+            ("internationalization", 2),
+            ("abcdefghijklmnopqrst", 5),
+            ("rust", 9),
+        ]
+        .into_iter()
+        .map(|(word, count)| (word.to_string(), count))
+        .collect();
+
+        // 21 Cyrillic characters take more bytes but still count as 21;
+        // the two 20-character words are ordered by count
+        assert_eq!(
+            longest_words(&word_counts, 3),
+            [
+                ("достопримечательность", 1),
+                ("abcdefghijklmnopqrst", 5),
+                ("internationalization", 2)
+            ]
+        );
+        assert_eq!(longest_words(&word_counts, 10).len(), 4);
+    }
+
+    #[test]
+    fn word_unique_to_a_user_ranks_first_for_them() {
+        let messages: Vec<SimpleMessage> = [
+            ("Alice", "rust rust rust go"),
+            ("Alice", "rust go"),
+            ("Bob", "rust go zig"),
+            ("Carol", "go rust"),
+        ]
+        .into_iter()
+        .map(|(username, text)| SimpleMessage {
+            username: username.to_string(),
+            text: text.to_string(),
+            ..Default::default()
+        })
+        .collect();
+        let tokens: Vec<Token> = messages
+            .iter()
+            .enumerate()
+            .flat_map(|(message, simple)| {
+                simple.text.split(' ').map(move |word| Token {
+                    word: word.to_string(),
+                    original: word.to_string(),
+                    message,
+                    emphasized: false,
+                })
+            })
+            .collect();
+
+        let per_user = tfidf_by_user(&tokens, &messages, 2);
+        let users: Vec<&str> =
+            per_user.iter().map(|(user, _)| user.as_str()).collect();
+        assert_eq!(users, ["Alice", "Bob", "Carol"]);
+
+        // Said once against six times for "rust", but only by Bob
+        let bob = &per_user[1].1;
+        assert_eq!(bob[0].0, "zig");
+        assert!(bob[0].1 > bob[1].1);
+        assert!(per_user[0].1.iter().all(|(word, _)| word != "zig"));
+    }
+
+    #[test]
+    fn hapaxes_are_the_words_counted_once() {
+        let word_counts: HashMap<String, usize> =
+            [("zig", 1), ("rust", 7), ("crab", 1), ("go", 2), ("асм", 1)]
+                .into_iter()
+                .map(|(word, count)| (word.to_string(), count))
+                .collect();
+        let hapaxes = hapax_legomena(&word_counts);
+        assert_eq!(hapaxes, ["crab", "zig", "асм"]);
+
+        let path = crate::tests::temp_path("hapax.txt");
+        crate::export::save_word_list(&hapaxes, &path).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(written, "crab\nzig\nасм\n");
+    }
+}
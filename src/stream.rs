@@ -0,0 +1,341 @@
+use crate::interrupt;
+use crate::parse::{self, InputEncoding, Message, ParseStats, ReadOptions};
+use crate::schema::{ExportFormat, ExportHeader};
+use crate::tokenizer::Token;
+use anyhow::Result;
+use encoding_rs::{CoderResult, Decoder};
+use serde::de::{
+    self, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor,
+};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+use std::rc::Rc;
+
+// Read buffer, also the sample --encoding auto looks at
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// Count words message by message: each message is tokenized by `tokenize`
+/// right after it is parsed and dropped once counted. Files are read
+/// through a buffer, so only the message being counted and the counts stay
+/// in memory, never the file, the full message, simplified message and
+/// token vectors. `tokenize` also gets the header fields of the file read
+/// so far.
+pub fn count_words<P: AsRef<Path>>(
+    file_paths: &[P],
+    read_options: &ReadOptions,
+    mut tokenize: impl FnMut(&Message, &ExportHeader) -> Vec<Token>,
+) -> Result<(HashMap<String, usize>, ParseStats)> {
+    let mut word_counts = HashMap::new();
+    let mut stats = ParseStats::default();
+
+    for file_path in file_paths {
+        let file_path = file_path.as_ref();
+        let (format, mut file_stats) = for_each_message(
+            file_path,
+            read_options.encoding,
+            &mut |msg, header| {
+                for token in tokenize(&msg, header) {
+                    *word_counts.entry(token.word).or_insert(0) += 1;
+                }
+            },
+        )?;
+        parse::check_format(
+            file_path,
+            format,
+            file_stats.header.as_ref(),
+            read_options.strict,
+        )?;
+        file_stats.set_file(file_path);
+        parse::check_stats(file_path, &file_stats, read_options.strict)?;
+        stats.merge(&file_stats);
+    }
+
+    Ok((word_counts, stats))
+}
+
+/// Read the export at `file_path` with a streaming JSON reader, handing
+/// each message to `visit` as it is parsed. Messages are the elements of
+/// `messages` arrays (at any depth, for account exports) or of a top-level
+/// array.
+///
+/// A message that doesn't fit `Message` is skipped like in
+/// `parse::for_each_message`. Broken JSON can't be resynced from a stream:
+/// it ends the file with an issue, the messages before it are kept.
+/// Offsets point just before each message.
+fn for_each_message(
+    file_path: &Path,
+    encoding: InputEncoding,
+    visit: &mut dyn FnMut(Message, &ExportHeader),
+) -> Result<(ExportFormat, ParseStats)> {
+    let file =
+        File::open(file_path).map_err(|e| parse::read_error(file_path, e))?;
+    let reader = DecodingReader::new(
+        BufReader::with_capacity(BUFFER_SIZE, file),
+        encoding,
+    )
+    .map_err(|e| parse::read_error(file_path, e))?;
+    let offset = Rc::new(Cell::new(0));
+    let reader = CountingReader {
+        inner: reader,
+        count: Rc::clone(&offset),
+    };
+
+    let mut walker = Walker {
+        visit,
+        offset: Rc::clone(&offset),
+        format: ExportFormat::Unknown,
+        header: ExportHeader::default(),
+        stats: ParseStats::default(),
+    };
+    let mut deserializer = serde_json::Deserializer::from_reader(reader);
+    let result = Walk {
+        walker: &mut walker,
+        place: Place::Top,
+    }
+    .deserialize(&mut deserializer);
+    match result {
+        Ok(()) => {}
+        // Stopped by Ctrl-C, keep what was counted
+        Err(_) if interrupt::is_interrupted() => {}
+        Err(e) if e.is_io() => {
+            return Err(parse::read_error(file_path, e.into()));
+        }
+        Err(e) => walker
+            .stats
+            .add_failure(offset.get(), format!("Stopped reading: {}", e)),
+    }
+
+    let Walker {
+        format,
+        header,
+        mut stats,
+        ..
+    } = walker;
+    if matches!(format, ExportFormat::SingleChat | ExportFormat::Account) {
+        stats.header = Some(header);
+    }
+    Ok((format, stats))
+}
+
+/// State shared by the `Walk` seeds while one file is read
+struct Walker<'a> {
+    visit: &'a mut dyn FnMut(Message, &ExportHeader),
+    /// Bytes read so far
+    offset: Rc<Cell<usize>>,
+    format: ExportFormat,
+    header: ExportHeader,
+    stats: ParseStats,
+}
+
+impl Walker<'_> {
+    fn read_messages<'de, A: SeqAccess<'de>>(
+        &mut self,
+        mut seq: A,
+    ) -> Result<(), A::Error> {
+        loop {
+            if interrupt::is_interrupted() {
+                return Err(de::Error::custom("interrupted"));
+            }
+            let offset = self.offset.get();
+            let Some(value) = seq.next_element::<serde_json::Value>()? else {
+                return Ok(());
+            };
+            match serde_json::from_value::<Message>(value) {
+                Ok(message) => {
+                    self.stats.add_message(&message, offset);
+                    (self.visit)(message, &self.header);
+                }
+                Err(e) => self.stats.add_failure(
+                    offset,
+                    format!("Failed to parse message: {}", e),
+                ),
+            }
+        }
+    }
+}
+
+/// Where a value sits in the export
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Place {
+    /// The whole file: header object or bare array of messages
+    Top,
+    /// A `messages` array
+    Messages,
+    /// Anything else, searched for `messages` arrays
+    Section,
+}
+
+/// Reads one value without keeping it, except for messages and header
+/// fields
+struct Walk<'w, 'a> {
+    walker: &'w mut Walker<'a>,
+    place: Place,
+}
+
+impl<'de> DeserializeSeed<'de> for Walk<'_, '_> {
+    type Value = ();
+
+    fn deserialize<D: de::Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Walk<'_, '_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a Telegram export")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        match self.place {
+            Place::Top => {
+                self.walker.format = ExportFormat::MessageArray;
+                self.walker.read_messages(seq)
+            }
+            Place::Messages => self.walker.read_messages(seq),
+            Place::Section => {
+                while seq
+                    .next_element_seed(Walk {
+                        walker: &mut *self.walker,
+                        place: Place::Section,
+                    })?
+                    .is_some()
+                {}
+                Ok(())
+            }
+        }
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(key) = map.next_key::<String>()? {
+            if self.place == Place::Top {
+                if let Some(format) = ExportFormat::from_key(&key)
+                    && self.walker.format != ExportFormat::Account
+                {
+                    self.walker.format = format;
+                }
+                if ExportHeader::is_field(&key) {
+                    let value = map.next_value::<serde_json::Value>()?;
+                    self.walker.header.set_field(&key, value);
+                    continue;
+                }
+            }
+            // `messages` should be an array, an object there is no message
+            if self.place == Place::Messages {
+                map.next_value::<IgnoredAny>()?;
+                continue;
+            }
+            let place = if key == "messages" {
+                Place::Messages
+            } else {
+                Place::Section
+            };
+            map.next_value_seed(Walk {
+                walker: &mut *self.walker,
+                place,
+            })?;
+        }
+        Ok(())
+    }
+
+    // Scalars hold no messages
+    fn visit_bool<E: de::Error>(self, _: bool) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_i64<E: de::Error>(self, _: i64) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_u64<E: de::Error>(self, _: u64) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_f64<E: de::Error>(self, _: f64) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_str<E: de::Error>(self, _: &str) -> Result<(), E> {
+        Ok(())
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<(), E> {
+        Ok(())
+    }
+}
+
+/// Decodes the file to UTF-8 as it is read, dropping any BOM
+struct DecodingReader<R> {
+    inner: R,
+    decoder: Decoder,
+    buffer: Vec<u8>,
+    start: usize,
+    end: usize,
+    finished: bool,
+    had_errors: bool,
+}
+
+impl<R: BufRead> DecodingReader<R> {
+    fn new(mut inner: R, encoding: InputEncoding) -> io::Result<Self> {
+        let encoding = parse::sniff_encoding(inner.fill_buf()?, encoding, true);
+        Ok(Self {
+            inner,
+            decoder: encoding.new_decoder_with_bom_removal(),
+            buffer: vec![0; BUFFER_SIZE],
+            start: 0,
+            end: 0,
+            finished: false,
+            had_errors: false,
+        })
+    }
+}
+
+impl<R: BufRead> Read for DecodingReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.start == self.end && !self.finished {
+            let input = self.inner.fill_buf()?;
+            let last = input.is_empty();
+            let (result, read, written, had_errors) =
+                self.decoder.decode_to_utf8(input, &mut self.buffer, last);
+            self.inner.consume(read);
+            self.start = 0;
+            self.end = written;
+            self.had_errors |= had_errors;
+            self.finished = last && result == CoderResult::InputEmpty;
+        }
+        if self.finished && self.start == self.end && self.had_errors {
+            eprintln!(
+                "Warning: Some bytes are not valid {}, replaced them",
+                self.decoder.encoding().name()
+            );
+            self.had_errors = false;
+        }
+
+        let n = out.len().min(self.end - self.start);
+        out[..n].copy_from_slice(&self.buffer[self.start..self.start + n]);
+        self.start += n;
+        Ok(n)
+    }
+}
+
+/// Counts the bytes read through it, for message offsets
+struct CountingReader<R> {
+    inner: R,
+    count: Rc<Cell<usize>>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(out)?;
+        self.count.set(self.count.get() + n);
+        Ok(n)
+    }
+}
@@ -0,0 +1,162 @@
+use crate::colors::{self, Rgb};
+use crate::graph::escape_xml;
+use ab_glyph::{Font, FontVec, PxScale, ScaleFont};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use std::fmt::Write;
+
+// Smallest font size in pixels, and the largest relative to canvas height
+const MIN_FONT_SIZE: f32 = 8.0;
+const MAX_FONT_SIZE_RATIO: f32 = 0.25;
+// Space between words and around the canvas edge, relative to font size
+const WORD_GAP: f32 = 0.4;
+const LINE_SPACING: f32 = 1.2;
+const MARGIN: f32 = 10.0;
+
+const TEXT_COLOR: Rgb = [40, 40, 40];
+// Name given to the embedded font, system fonts follow as fallbacks
+const EMBEDDED_FAMILY: &str = "CloudFont";
+const FALLBACK_FAMILIES: &str = "'DejaVu Sans', sans-serif";
+
+/// Lay words out in centered rows, in the given order, and return an SVG
+/// document. `words` pairs each word with its weight, font sizes are
+/// proportional to weight with the heaviest word at a quarter of the
+/// canvas height. `font` measures
+/// text widths and, with `embed_font` set to its file contents, is inlined
+/// as a base64 `@font-face` so the file renders the same everywhere.
+pub fn render_svg(
+    words: &[(&str, f32)],
+    word_colors: Option<&[Rgb]>,
+    width: usize,
+    height: usize,
+    font: &FontVec,
+    embed_font: Option<&[u8]>,
+) -> String {
+    let max_size = height as f32 * MAX_FONT_SIZE_RATIO;
+    let max_weight = words.iter().map(|(_, w)| *w).fold(0.0, f32::max);
+    let max_width = width as f32 - MARGIN * 2.0;
+
+    // Rows of (word index, font size, text width)
+    let mut rows: Vec<Vec<(usize, f32, f32)>> = Vec::new();
+    let mut row_width = 0.0;
+    for (i, (word, weight)) in words.iter().enumerate() {
+        let size = if max_weight > 0.0 {
+            (max_size * weight / max_weight).max(MIN_FONT_SIZE)
+        } else {
+            MIN_FONT_SIZE
+        };
+        let text_width = measure(font, word, size);
+        let gap = size * WORD_GAP;
+        match rows.last_mut() {
+            Some(row) if row_width + gap + text_width <= max_width => {
+                row_width += gap + text_width;
+                row.push((i, size, text_width));
+            }
+            _ => {
+                row_width = text_width;
+                rows.push(vec![(i, size, text_width)]);
+            }
+        }
+    }
+
+    let row_heights: Vec<f32> = rows
+        .iter()
+        .map(|row| row.iter().map(|(_, size, _)| *size).fold(0.0, f32::max))
+        .collect();
+    let content_height: f32 =
+        row_heights.iter().map(|h| h * LINE_SPACING).sum::<f32>();
+    let canvas_height = (content_height + MARGIN * 2.0).max(height as f32);
+
+    let family = match embed_font {
+        Some(_) => format!("'{}', {}", EMBEDDED_FAMILY, FALLBACK_FAMILIES),
+        None => FALLBACK_FAMILIES.to_string(),
+    };
+
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}" viewBox="0 0 {} {}">"#,
+        width,
+        canvas_height.ceil(),
+        width,
+        canvas_height.ceil()
+    );
+    if let Some(font_data) = embed_font {
+        let _ = writeln!(
+            svg,
+            "<style>@font-face {{ font-family: '{}'; src: url(data:font/ttf;base64,{}); }}</style>",
+            EMBEDDED_FAMILY,
+            STANDARD.encode(font_data)
+        );
+    }
+    let _ = writeln!(svg, r#"<rect width="100%" height="100%" fill="white"/>"#);
+    let _ = writeln!(svg, r#"<g font-family="{}">"#, escape_xml(&family));
+
+    // Center the block of rows vertically
+    let mut y = (canvas_height - content_height) / 2.0;
+    for (row, row_height) in rows.iter().zip(&row_heights) {
+        let words_width: f32 = row.iter().map(|(_, _, w)| w).sum();
+        let gaps: f32 =
+            row.iter().skip(1).map(|(_, size, _)| size * WORD_GAP).sum();
+        let mut x = (width as f32 - words_width - gaps) / 2.0;
+        y += row_height * LINE_SPACING;
+
+        for (n, (i, size, text_width)) in row.iter().enumerate() {
+            if n > 0 {
+                x += size * WORD_GAP;
+            }
+            let color = word_colors.map_or(TEXT_COLOR, |colors| colors[*i]);
+            let _ = writeln!(
+                svg,
+                r#"<text x="{:.1}" y="{:.1}" font-size="{:.1}" fill="{}">{}</text>"#,
+                x,
+                y - row_height * (LINE_SPACING - 1.0),
+                size,
+                colors::to_hex(color),
+                escape_xml(words[*i].0)
+            );
+            x += text_width;
+        }
+    }
+
+    svg.push_str("</g>\n</svg>\n");
+    svg
+}
+
+/// Advance width of `text` at `size` pixels
+fn measure(font: &FontVec, text: &str, size: f32) -> f32 {
+    let scaled = font.as_scaled(PxScale::from(size));
+    text.chars()
+        .map(|c| scaled.h_advance(font.glyph_id(c)))
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render;
+
+    #[test]
+    fn embedded_font_and_one_text_per_word() {
+        let font = render::load_font(None).unwrap();
+        let font_data = render::font_data(None).unwrap();
+        let words = [("rust", 10.0), ("cargo", 5.0), ("<crab>", 1.0)];
+
+        let svg = render_svg(&words, None, 800, 400, &font, Some(&font_data));
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\""));
+        assert!(svg.contains("@font-face { font-family: 'CloudFont'"));
+        assert!(
+            svg.contains(&format!("base64,{})", STANDARD.encode(&font_data)))
+        );
+        assert_eq!(svg.matches("<text ").count(), 3);
+        assert!(svg.contains(">rust</text>"));
+        assert!(svg.contains(">&lt;crab&gt;</text>"));
+
+        let plain = render_svg(&words, None, 800, 400, &font, None);
+        assert!(!plain.contains("@font-face"));
+        assert!(plain.contains(&format!(
+            "font-family=\"{}\"",
+            escape_xml(FALLBACK_FAMILIES)
+        )));
+    }
+}
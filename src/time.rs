@@ -0,0 +1,104 @@
+pub const SECONDS_PER_DAY: i64 = 86_400;
+
+/// Parse a UTC offset such as `+03:00`, `-0530`, `+3` or `UTC` into seconds
+pub fn parse_utc_offset(value: &str) -> Result<i64, String> {
+    let invalid = || format!("expected UTC offset like +03:00, got '{}'", value);
+
+    if value.eq_ignore_ascii_case("utc") || value == "Z" {
+        return Ok(0);
+    }
+
+    let (sign, rest) = match value.as_bytes().first() {
+        Some(b'+') => (1, &value[1..]),
+        Some(b'-') => (-1, &value[1..]),
+        _ => return Err(invalid()),
+    };
+    let (hours, minutes) = match rest.split_once(':') {
+        Some((hours, minutes)) => (hours, minutes),
+        None if rest.len() == 4 => rest.split_at(2),
+        None => (rest, "0"),
+    };
+    let hours: i64 = hours.parse().map_err(|_| invalid())?;
+    let minutes: i64 = minutes.parse().map_err(|_| invalid())?;
+    if hours > 14 || minutes >= 60 {
+        return Err(invalid());
+    }
+
+    Ok(sign * (hours * 3600 + minutes * 60))
+}
+
+/// Hour of day (0-23) of a unix timestamp shifted by `offset` seconds
+pub fn hour_of_day(timestamp: i64, offset: i64) -> u32 {
+    ((timestamp + offset).rem_euclid(SECONDS_PER_DAY) / 3600) as u32
+}
+
+/// Parse a positive, possibly fractional number of days
+pub fn parse_days(value: &str) -> Result<f64, String> {
+    let days: f64 = value
+        .parse()
+        .map_err(|_| format!("invalid number of days '{}'", value))?;
+    if !days.is_finite() || days <= 0.0 {
+        return Err(format!("number of days must be positive, got '{}'", value));
+    }
+    Ok(days)
+}
+
+/// First and last timestamp seen
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TimeRange {
+    pub first: Option<i64>,
+    pub last: Option<i64>,
+}
+
+impl TimeRange {
+    pub fn add(&mut self, timestamp: i64) {
+        self.first = Some(self.first.map_or(timestamp, |t| t.min(timestamp)));
+        self.last = Some(self.last.map_or(timestamp, |t| t.max(timestamp)));
+    }
+
+    pub fn span_days(&self) -> Option<f64> {
+        Some((self.last? - self.first?) as f64 / SECONDS_PER_DAY as f64)
+    }
+}
+
+/// `YYYY-MM-DD HH:MM` for a unix timestamp shifted by `offset` seconds
+pub fn format_datetime(timestamp: i64, offset: i64) -> String {
+    let local = timestamp + offset;
+    let (year, month, day) = civil_from_days(local.div_euclid(SECONDS_PER_DAY));
+    let seconds = local.rem_euclid(SECONDS_PER_DAY);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60
+    )
+}
+
+/// `YYYY-MM-DD` of a unix timestamp shifted by `offset` seconds
+pub fn format_date(timestamp: i64, offset: i64) -> String {
+    let (year, month, day) =
+        civil_from_days((timestamp + offset).div_euclid(SECONDS_PER_DAY));
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Calendar year of a unix timestamp shifted by `offset` seconds
+pub fn year_of(timestamp: i64, offset: i64) -> i64 {
+    civil_from_days((timestamp + offset).div_euclid(SECONDS_PER_DAY)).0
+}
+
+/// Calendar date of a day count since 1970-01-01, after Howard Hinnant's
+/// `civil_from_days`
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
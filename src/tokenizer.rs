@@ -1,71 +1,639 @@
+use crate::interrupt;
 use crate::parse::SimpleMessage;
+use clap::ValueEnum;
 use regex::Regex;
+use rust_stemmers::{Algorithm, Stemmer};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::sync::LazyLock;
+use unicode_normalization::{UnicodeNormalization, is_nfc, is_nfkc};
 
 #[derive(Debug)]
 pub struct Token {
     pub word: String,
+    /// Surface form as it appeared in the message, kept through stemming
+    pub original: String,
+    /// Index of the source message in the tokenized slice
+    pub message: usize,
+    /// Found in a bold, italic or underlined part of the message
+    pub emphasized: bool,
+}
+
+// Regex to match valid words (letters and some special characters)
+// This will exclude emojis, punctuation, and other symbols
+static WORD_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"[\p{L}\p{N}_-]+").unwrap());
+// http(s) links up to the next whitespace, for `url_tokens`
+static URL_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"https?://\S+").unwrap());
+// Words as above, or runs of punctuation and ASCII-art symbols ("!!!", ":)")
+static WORD_OR_PUNCTUATION_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"[\p{L}\p{N}_-]+|[\p{P}\p{Sm}\p{Sc}\p{Sk}]+").unwrap()
+});
+// The two above, also taking apostrophes inside words ("don't") for
+// `inner_punctuation` to split or remove
+static APOSTROPHE_WORD_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"[\p{L}\p{N}_-]+(?:['’][\p{L}\p{N}_-]+)*").unwrap()
+});
+static APOSTROPHE_WORD_OR_PUNCTUATION_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| {
+        Regex::new(
+            r"[\p{L}\p{N}_-]+(?:['’][\p{L}\p{N}_-]+)*|[\p{P}\p{Sm}\p{Sc}\p{Sk}]+",
+        )
+        .unwrap()
+    });
+
+// What `inner_punctuation` splits words on or removes
+const INNER_PUNCTUATION: [char; 4] = ['-', '_', '\'', '’'];
+
+/// What to do with hyphens, underscores and apostrophes inside a word
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum InnerPunctuation {
+    /// Break the word into parts ("что-то" -> "что", "то")
+    Split,
+    /// Glue the parts together ("что-то" -> "чтото", "don't" -> "dont")
+    Remove,
+}
+
+/// Unicode normalization form applied to text before tokenizing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum UnicodeForm {
+    /// Canonical composition
+    Nfc,
+    /// Compatibility composition, also folding ligatures, full-width forms
+    /// and the like
+    Nfkc,
+    /// Leave text as is
+    None,
+}
+
+/// Locale-specific lowercasing rules, picked with `--locale`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaseLocale {
+    /// Unicode default case mapping
+    Default,
+    /// Turkish and Azerbaijani dotted and dotless I: "I" -> "ı", "İ" -> "i"
+    Turkic,
+}
+
+impl CaseLocale {
+    pub fn to_lowercase(self, text: &str) -> String {
+        match self {
+            Self::Default => text.to_lowercase(),
+            Self::Turkic => {
+                text.replace('I', "ı").replace('İ', "i").to_lowercase()
+            }
+        }
+    }
+}
+
+/// Parse a `--locale` code such as `tr` or `tr-TR`. Only the language part
+/// matters and only Turkic languages change case folding.
+pub fn parse_locale(value: &str) -> Result<CaseLocale, String> {
+    let lang = value.split(['-', '_']).next().unwrap_or_default();
+    if lang.is_empty() || !lang.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err(format!("invalid locale '{}'", value));
+    }
+    match lang.to_ascii_lowercase().as_str() {
+        "tr" | "az" => Ok(CaseLocale::Turkic),
+        _ => Ok(CaseLocale::Default),
+    }
+}
+
+fn normalize_unicode(text: &str, form: UnicodeForm) -> Cow<'_, str> {
+    match form {
+        UnicodeForm::Nfc if !is_nfc(text) => Cow::Owned(text.nfc().collect()),
+        UnicodeForm::Nfkc if !is_nfkc(text) => Cow::Owned(text.nfkc().collect()),
+        _ => Cow::Borrowed(text),
+    }
+}
+
+/// Replace each emoji with its name, words joined by underscores so the
+/// name stays one token ("👍" -> "thumbs_up"). Variation selectors, joiners
+/// and skin tone modifiers are dropped, so a ZWJ sequence turns into the
+/// names of its parts.
+fn emoji_to_words(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut buf = [0; 4];
+    for c in text.chars() {
+        match c {
+            '\u{FE0F}' | '\u{200D}' | '\u{1F3FB}'..='\u{1F3FF}' => continue,
+            _ => {}
+        }
+        // Plain ASCII like digits and '#' are only emoji as keycaps
+        let emoji = (!c.is_ascii())
+            .then(|| emojis::get(c.encode_utf8(&mut buf)))
+            .flatten();
+        match emoji {
+            Some(emoji) => {
+                result.push(' ');
+                result.push_str(&emoji.name().replace(' ', "_"));
+                result.push(' ');
+            }
+            None => result.push(c),
+        }
+    }
+    result
+}
+
+#[derive(Debug)]
+pub struct TokenizeOptions {
+    pub unicode_form: UnicodeForm,
+    /// Lowercase words, so counting ignores case
+    pub lowercase: bool,
+    pub locale: CaseLocale,
+    /// Replace emoji with their names ("🔥" -> "fire")
+    pub emoji_as_words: bool,
+    /// Also count runs of punctuation as tokens of their own
+    pub include_punctuation: bool,
+    /// Drop words holding anything but letters (digits, '-', '_')
+    pub letters_only: bool,
+    /// Multi-word terms kept as single tokens, see `phrase_regex`
+    pub phrases: Option<Regex>,
+    /// Count each http(s) link as one token instead of splitting it
+    pub url_tokens: bool,
+    pub min_length: usize,
+    /// No upper bound when `None`
+    pub max_length: Option<usize>,
+    /// Keep inner hyphens/underscores when `None`
+    pub inner_punctuation: Option<InnerPunctuation>,
+    /// Break camelCase and snake_case identifiers into their words, see
+    /// `split_identifier`
+    pub split_identifiers: bool,
+}
+
+impl Default for TokenizeOptions {
+    fn default() -> Self {
+        Self {
+            unicode_form: UnicodeForm::Nfc,
+            lowercase: true,
+            locale: CaseLocale::Default,
+            emoji_as_words: false,
+            include_punctuation: false,
+            letters_only: false,
+            phrases: None,
+            url_tokens: false,
+            min_length: 3,
+            max_length: None,
+            inner_punctuation: None,
+            split_identifiers: false,
+        }
+    }
 }
 
 pub fn tokenize_messages(
     messages: &[SimpleMessage],
-    min_length: usize,
+    options: &TokenizeOptions,
 ) -> Vec<Token> {
-    // Regex to match valid words (letters and some special characters)
-    // This will exclude emojis, punctuation, and other symbols
-    let word_regex = Regex::new(r"[\p{L}\p{N}_-]+").unwrap();
-
     let mut tokens = Vec::new();
 
-    for message in messages {
-        // Find all word matches in the message text
-        for capture in word_regex.find_iter(&message.text) {
-            let word = capture.as_str().to_lowercase();
+    for (message_idx, message) in messages.iter().enumerate() {
+        if interrupt::is_interrupted() {
+            break;
+        }
+
+        let (text, emphasized) =
+            normalize_text(&message.text, &message.emphasized, options);
+        let source = Source {
+            message: message_idx,
+            emphasized: &emphasized,
+        };
+
+        // Whole URLs become single tokens, the text around them is
+        // tokenized as usual
+        let mut rest = 0;
+        if options.url_tokens {
+            for url in URL_REGEX.find_iter(&text) {
+                let link = url.as_str().trim_end_matches([
+                    '.', ',', ';', ':', '!', '?', ')', '"', '\'',
+                ]);
+                push_phrases_and_words(
+                    &text[rest..url.start()],
+                    rest,
+                    &source,
+                    options,
+                    &mut tokens,
+                );
+                let end = url.start() + link.len();
+                tokens.push(source.token(link.to_string(), url.start()..end));
+                rest = end;
+            }
+        }
+        push_phrases_and_words(
+            &text[rest..],
+            rest,
+            &source,
+            options,
+            &mut tokens,
+        );
+    }
+
+    tokens
+}
+
+/// Message the text being split comes from
+struct Source<'a> {
+    message: usize,
+    /// Byte ranges of the normalized text formatted as bold, italic or
+    /// underlined
+    emphasized: &'a [Range<usize>],
+}
+
+impl Source<'_> {
+    /// Token for `word`, found at byte range `at` of the normalized text
+    fn token(&self, word: String, at: Range<usize>) -> Token {
+        Token {
+            original: word.clone(),
+            word,
+            message: self.message,
+            // Formatting can start or end mid-word
+            emphasized: self
+                .emphasized
+                .iter()
+                .any(|span| span.start < at.end && at.start < span.end),
+        }
+    }
+}
+
+/// Unicode-normalize `text`, with `emoji_as_words` also naming its emoji,
+/// and move its `emphasized` ranges to match. With emphasized parts, each
+/// part is converted on its own so its ends stay known.
+fn normalize_text<'a>(
+    text: &'a str,
+    emphasized: &[Range<usize>],
+    options: &TokenizeOptions,
+) -> (Cow<'a, str>, Vec<Range<usize>>) {
+    let convert = |part: &'a str| {
+        let normalized = normalize_unicode(part, options.unicode_form);
+        if options.emoji_as_words {
+            Cow::Owned(emoji_to_words(&normalized))
+        } else {
+            normalized
+        }
+    };
+    if emphasized.is_empty() {
+        return (convert(text), Vec::new());
+    }
+
+    let mut result = String::with_capacity(text.len());
+    let mut ranges = Vec::new();
+    let mut rest = 0;
+    for span in emphasized {
+        let start = span.start.max(rest);
+        if start >= span.end {
+            continue;
+        }
+        result.push_str(&convert(&text[rest..start]));
+        let converted_start = result.len();
+        result.push_str(&convert(&text[start..span.end]));
+        ranges.push(converted_start..result.len());
+        rest = span.end;
+    }
+    result.push_str(&convert(&text[rest..]));
+    (Cow::Owned(result), ranges)
+}
 
-            // Skip words that are too short
-            if word.len() < min_length {
+/// Split `text`, found at byte `offset` of the message text, into tokens,
+/// listed phrases as single tokens and the text around them into words as
+/// usual
+fn push_phrases_and_words(
+    text: &str,
+    offset: usize,
+    source: &Source,
+    options: &TokenizeOptions,
+    tokens: &mut Vec<Token>,
+) {
+    let mut rest = 0;
+    if let Some(phrases) = &options.phrases {
+        for phrase in phrases.find_iter(text) {
+            // `\b` treats '-' and '_' as boundaries, words here don't
+            let glued = text[..phrase.start()]
+                .chars()
+                .next_back()
+                .is_some_and(is_word_char)
+                || text[phrase.end()..]
+                    .chars()
+                    .next()
+                    .is_some_and(is_word_char);
+            if glued {
                 continue;
             }
+            push_words(
+                &text[rest..phrase.start()],
+                offset + rest,
+                source,
+                options,
+                tokens,
+            );
+            let word = normalize_phrase(phrase.as_str(), options);
+            let at = offset + phrase.start()..offset + phrase.end();
+            tokens.push(source.token(word, at));
+            rest = phrase.end();
+        }
+    }
+    push_words(&text[rest..], offset + rest, source, options, tokens);
+}
+
+/// Split `text`, found at byte `offset` of the message text, into word
+/// tokens
+fn push_words(
+    text: &str,
+    offset: usize,
+    source: &Source,
+    options: &TokenizeOptions,
+    tokens: &mut Vec<Token>,
+) {
+    let regex = match (options.include_punctuation, options.inner_punctuation) {
+        (true, Some(_)) => &APOSTROPHE_WORD_OR_PUNCTUATION_REGEX,
+        (true, None) => &WORD_OR_PUNCTUATION_REGEX,
+        (false, Some(_)) => &APOSTROPHE_WORD_REGEX,
+        (false, None) => &WORD_REGEX,
+    };
+
+    // Find all word matches in the text
+    for capture in regex.find_iter(text) {
+        let at = offset + capture.start()..offset + capture.end();
+        // Punctuation, including hyphen and underscore runs like "---"
+        if !has_word_char(capture.as_str()) {
+            let length = capture.as_str().chars().count();
+            if options.include_punctuation
+                && length >= options.min_length
+                && options.max_length.is_none_or(|max| length <= max)
+            {
+                tokens.push(source.token(capture.as_str().to_string(), at));
+            }
+            continue;
+        }
 
-            tokens.push(Token { word });
+        // "rust2" and "c-x" go entirely rather than leaving "rust" or "c"
+        if options.letters_only
+            && !capture.as_str().chars().all(char::is_alphabetic)
+        {
+            continue;
+        }
+
+        // Case marks the word boundaries, so split before lowercasing
+        let parts = if options.split_identifiers {
+            split_identifier(capture.as_str())
+        } else {
+            vec![capture.as_str()]
+        };
+
+        let mut words = Vec::new();
+        for part in parts {
+            let word = if options.lowercase {
+                options.locale.to_lowercase(part)
+            } else {
+                part.to_string()
+            };
+
+            match options.inner_punctuation {
+                None => words.push(word),
+                Some(InnerPunctuation::Split) => words.extend(
+                    word.split(INNER_PUNCTUATION)
+                        .filter(|part| !part.is_empty())
+                        .map(String::from),
+                ),
+                Some(InnerPunctuation::Remove) => {
+                    words.push(word.replace(INNER_PUNCTUATION, ""))
+                }
+            }
+        }
+
+        for word in words {
+            // Skip runs of underscores and hyphens like "___" or "--"
+            if !has_word_char(&word) {
+                continue;
+            }
+
+            // Skip words that are too short or too long
+            let length = word.chars().count();
+            if length < options.min_length
+                || options.max_length.is_some_and(|max| length > max)
+            {
+                continue;
+            }
+
+            tokens.push(source.token(word, at.clone()));
         }
     }
+}
 
-    tokens
+/// Split an identifier into its words: at underscores, where a lowercase
+/// letter or digit meets an uppercase one ("readMessages" -> "read",
+/// "Messages"), and before the last capital of an acronym ("HTTPServer" ->
+/// "HTTP", "Server"). Other words come back whole.
+fn split_identifier(word: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    for segment in word.split('_').filter(|segment| !segment.is_empty()) {
+        let chars: Vec<(usize, char)> = segment.char_indices().collect();
+        let mut start = 0;
+        for i in 1..chars.len() {
+            let (at, c) = chars[i];
+            let prev = chars[i - 1].1;
+            let next_is_lower = chars
+                .get(i + 1)
+                .is_some_and(|(_, next)| next.is_lowercase());
+            let boundary = c.is_uppercase()
+                && (prev.is_lowercase()
+                    || prev.is_numeric()
+                    || (prev.is_uppercase() && next_is_lower));
+            if boundary {
+                parts.push(&segment[start..at]);
+                start = at;
+            }
+        }
+        parts.push(&segment[start..]);
+    }
+    parts
+}
+
+/// Regex matching any of the multi-word `phrases` as whole words, ignoring
+/// case and the amount of whitespace between words. Longer phrases win
+/// over phrases they contain.
+pub fn phrase_regex(phrases: &[String]) -> Option<Regex> {
+    let mut patterns: Vec<String> = phrases
+        .iter()
+        .map(|phrase| {
+            phrase
+                .split_whitespace()
+                .map(regex::escape)
+                .collect::<Vec<_>>()
+                .join(r"\s+")
+        })
+        .filter(|pattern| !pattern.is_empty())
+        .collect();
+    if patterns.is_empty() {
+        return None;
+    }
+    patterns.sort_by_key(|pattern| std::cmp::Reverse(pattern.len()));
+    let pattern = format!(r"(?i)\b(?:{})\b", patterns.join("|"));
+    Some(Regex::new(&pattern).expect("escaped phrases form a valid regex"))
+}
+
+/// Phrase as counted: words joined by single spaces
+fn normalize_phrase(phrase: &str, options: &TokenizeOptions) -> String {
+    let phrase = phrase.split_whitespace().collect::<Vec<_>>().join(" ");
+    if options.lowercase {
+        options.locale.to_lowercase(&phrase)
+    } else {
+        phrase
+    }
+}
+
+/// Number of words in a message text, before any length or stop-word checks
+pub fn count_message_words(text: &str) -> usize {
+    WORD_REGEX
+        .find_iter(text)
+        .filter(|word| has_word_char(word.as_str()))
+        .count()
+}
+
+/// Characters `WORD_REGEX` matches
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-'
+}
+
+/// Whether a regex match has a letter or digit, not just `_` and `-`
+fn has_word_char(word: &str) -> bool {
+    word.chars().any(char::is_alphanumeric)
+}
+
+pub fn filter_short_messages(
+    messages: Vec<SimpleMessage>,
+    min_words: usize,
+) -> Vec<SimpleMessage> {
+    messages
+        .into_iter()
+        .filter(|message| count_message_words(&message.text) >= min_words)
+        .collect()
 }
 
 // Optional: Function to filter tokens by language-specific stop words
 pub fn filter_stop_words(
     tokens: Vec<Token>,
     stop_words: &[String],
+    locale: CaseLocale,
 ) -> Vec<Token> {
     tokens
         .into_iter()
-        .filter(|token| !stop_words.contains(&token.word))
+        .filter(|token| {
+            // Tokens keep their case with --no-lowercase
+            let word = if token.word.chars().any(char::is_uppercase) {
+                Cow::Owned(locale.to_lowercase(&token.word))
+            } else {
+                Cow::Borrowed(token.word.as_str())
+            };
+            !stop_words.iter().any(|stop_word| *stop_word == word)
+        })
         .collect()
 }
 
 // Optional: Function to stem words for better counting
-pub fn stem_tokens(tokens: Vec<Token>, lang: &str) -> Vec<Token> {
-    use rust_stemmers::{Algorithm, Stemmer};
-
+fn create_stemmer(lang: &str) -> Stemmer {
     // Select stemmer based on language
-    let stemmer = match lang.to_lowercase().as_str() {
+    match lang.to_lowercase().as_str() {
         "ru" => Stemmer::create(Algorithm::Russian),
         "en" => Stemmer::create(Algorithm::English),
         // Add other languages as needed
         _ => Stemmer::create(Algorithm::English), // Default to English
+    }
+}
+
+/// Language of `word` among the `--lang` languages: Russian for Cyrillic
+/// words when it is listed, otherwise the first other language
+pub fn lang_for_word<'a>(word: &str, langs: &'a [String]) -> &'a str {
+    let is_russian = |lang: &&String| lang.eq_ignore_ascii_case("ru");
+    let cyrillic = word.chars().any(|c| matches!(c, '\u{0400}'..='\u{04FF}'));
+    let lang = if cyrillic {
+        langs.iter().find(is_russian)
+    } else {
+        langs.iter().find(|lang| !is_russian(lang))
     };
+    lang.or(langs.first()).map_or("en", String::as_str)
+}
+
+/// One stemmer per `--lang` language, picked per word by `lang_for_word`
+struct Stemmers<'a> {
+    langs: &'a [String],
+    stemmers: HashMap<&'a str, Stemmer>,
+}
+
+impl<'a> Stemmers<'a> {
+    fn new(langs: &'a [String]) -> Self {
+        let stemmers = langs
+            .iter()
+            .map(|lang| (lang.as_str(), create_stemmer(lang)))
+            .collect();
+        Self { langs, stemmers }
+    }
+
+    fn stem(&self, word: &str) -> String {
+        let lang = lang_for_word(word, self.langs);
+        match self.stemmers.get(lang) {
+            Some(stemmer) => stemmer.stem(word).to_string(),
+            None => create_stemmer(lang).stem(word).to_string(),
+        }
+    }
+}
+
+/// Stem each token by its language. Words in `exceptions` are kept as
+/// they are.
+pub fn stem_tokens(
+    tokens: Vec<Token>,
+    langs: &[String],
+    exceptions: &HashSet<String>,
+) -> Vec<Token> {
+    let stemmers = Stemmers::new(langs);
 
     tokens
         .into_iter()
-        .map(|token| Token {
-            word: stemmer.stem(&token.word).to_string(),
+        .map(|token| {
+            // Multi-word phrases from --keep-phrases-file and whole links
+            // are kept as listed
+            if token.word.contains(' ')
+                || token.word.contains("://")
+                || exceptions.contains(&token.word)
+            {
+                return token;
+            }
+            Token {
+                word: stemmers.stem(&token.word),
+                ..token
+            }
         })
         .collect()
 }
 
-pub fn count_words(tokens: &[Token]) -> std::collections::HashMap<String, usize> {
-    let mut word_counts = std::collections::HashMap::new();
+/// Lowercase and dedup stop words so they match lowercased tokens
+pub fn normalize_stop_words(
+    stop_words: Vec<String>,
+    locale: CaseLocale,
+) -> Vec<String> {
+    let mut normalized: Vec<String> = stop_words
+        .into_iter()
+        .map(|word| locale.to_lowercase(word.trim()))
+        .filter(|word| !word.is_empty())
+        .collect();
+    normalized.sort();
+    normalized.dedup();
+    normalized
+}
+
+/// Stem a stop-word list so it can be matched against stemmed tokens
+pub fn stem_stop_words(stop_words: &[String], langs: &[String]) -> Vec<String> {
+    let stemmers = Stemmers::new(langs);
+
+    let mut stemmed: Vec<String> =
+        stop_words.iter().map(|word| stemmers.stem(word)).collect();
+    stemmed.sort();
+    stemmed.dedup();
+    stemmed
+}
+
+pub fn count_words(tokens: &[Token]) -> HashMap<String, usize> {
+    let mut word_counts = HashMap::new();
 
     for token in tokens {
         *word_counts.entry(token.word.clone()).or_insert(0) += 1;
@@ -74,6 +642,43 @@ pub fn count_words(tokens: &[Token]) -> std::collections::HashMap<String, usize>
     word_counts
 }
 
+/// Group surface forms under the stem they were reduced to, with counts
+pub fn count_stem_forms(
+    tokens: &[Token],
+) -> HashMap<String, HashMap<String, usize>> {
+    let mut stem_forms: HashMap<String, HashMap<String, usize>> = HashMap::new();
+
+    for token in tokens {
+        *stem_forms
+            .entry(token.word.clone())
+            .or_default()
+            .entry(token.original.clone())
+            .or_insert(0) += 1;
+    }
+
+    stem_forms
+}
+
+/// Stems merged from at least `min_forms` distinct surface forms, the
+/// forms sorted, stems with the most forms first
+pub fn stem_collisions(
+    stem_forms: &HashMap<String, HashMap<String, usize>>,
+    min_forms: usize,
+) -> Vec<(&str, Vec<&str>)> {
+    let mut collisions: Vec<(&str, Vec<&str>)> = stem_forms
+        .iter()
+        .filter(|(_, forms)| forms.len() >= min_forms)
+        .map(|(stem, forms)| {
+            let mut forms: Vec<&str> = forms.keys().map(String::as_str).collect();
+            forms.sort_unstable();
+            (stem.as_str(), forms)
+        })
+        .collect();
+    collisions
+        .sort_by(|a, b| b.1.len().cmp(&a.1.len()).then_with(|| a.0.cmp(b.0)));
+    collisions
+}
+
 #[rustfmt::skip]
 pub fn get_russian_stopwords() -> Vec<String> {
     vec![
@@ -121,3 +726,276 @@ pub fn get_russian_stopwords() -> Vec<String> {
     .map(String::from)
     .collect()
 }
+
+#[rustfmt::skip]
+pub fn get_english_stopwords() -> Vec<String> {
+    vec![
+        "a", "about", "above", "after", "again", "against", "all", "also", "am", "an",
+        "and", "any", "are", "as", "at", "be", "because", "been", "before", "being",
+        "below", "between", "both", "but", "by", "can", "could", "did", "do", "does",
+        "doing", "don", "down", "during", "each", "even", "few", "for", "from", "further",
+        "get", "got", "had", "has", "have", "having", "he", "her", "here", "hers",
+        "herself", "him", "himself", "his", "how", "if", "in", "into", "is", "it",
+        "its", "itself", "just", "like", "me", "more", "most", "my", "myself", "no",
+        "nor", "not", "now", "of", "off", "on", "once", "only", "or", "other",
+        "our", "ours", "ourselves", "out", "over", "own", "really", "same", "she", "should",
+        "so", "some", "such", "than", "that", "the", "their", "theirs", "them", "themselves",
+        "then", "there", "these", "they", "this", "those", "through", "to", "too", "under",
+        "until", "up", "very", "was", "we", "were", "what", "when", "where", "which",
+        "while", "who", "whom", "why", "will", "with", "would", "you", "your", "yours",
+        "yourself", "yourselves",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect()
+}
+
+/// Built-in stop words for a `--lang` language, if there is a list for it
+pub fn builtin_stopwords(lang: &str) -> Option<Vec<String>> {
+    match lang.to_lowercase().as_str() {
+        "ru" => Some(get_russian_stopwords()),
+        "en" => Some(get_english_stopwords()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(word: &str) -> Token {
+        Token {
+            word: word.to_string(),
+            original: word.to_string(),
+            message: 0,
+            emphasized: false,
+        }
+    }
+
+    fn options() -> TokenizeOptions {
+        TokenizeOptions {
+            min_length: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Words of `text` as one message
+    fn words(text: &str, options: &TokenizeOptions) -> Vec<String> {
+        let message = SimpleMessage {
+            text: text.to_string(),
+            ..Default::default()
+        };
+        tokenize_messages(&[message], options)
+            .into_iter()
+            .map(|token| token.word)
+            .collect()
+    }
+
+    #[test]
+    fn turkish_stop_words_fold_dotted_and_dotless_i() {
+        let stop_words = normalize_stop_words(
+            vec!["KIŞ".to_string(), "İyi".to_string()],
+            CaseLocale::Turkic,
+        );
+        assert_eq!(stop_words, ["iyi", "kış"]);
+
+        // Tokens keep their case with --no-lowercase
+        let tokens = vec![token("KIŞ"), token("İYİ"), token("KİŞİ")];
+        let kept: Vec<String> =
+            filter_stop_words(tokens, &stop_words, CaseLocale::Turkic)
+                .into_iter()
+                .map(|token| token.word)
+                .collect();
+        assert_eq!(kept, ["KİŞİ"]);
+
+        // The default mapping turns "I" into "i", so nothing matches
+        let stop_words =
+            normalize_stop_words(vec!["KIŞ".to_string()], CaseLocale::Default);
+        assert_eq!(stop_words, ["kiş"]);
+    }
+
+    #[test]
+    fn decomposed_and_composed_forms_merge() {
+        let decomposed = "cafe\u{301}";
+        assert_eq!(words(decomposed, &options()), ["café"]);
+        assert_eq!(words("café", &options()), ["café"]);
+
+        let options = TokenizeOptions {
+            unicode_form: UnicodeForm::None,
+            ..options()
+        };
+        assert_ne!(words(decomposed, &options), ["café"]);
+    }
+
+    #[test]
+    fn emoji_become_their_names() {
+        let named = TokenizeOptions {
+            emoji_as_words: true,
+            ..options()
+        };
+        assert_eq!(
+            words("ship it🔥👍", &named),
+            ["ship", "it", "fire", "thumbs_up"]
+        );
+        assert_eq!(words("ship it🔥", &options()), ["ship", "it"]);
+    }
+
+    #[test]
+    fn underscore_and_hyphen_runs_are_dropped() {
+        assert_eq!(
+            words("see ____ and --- here", &options()),
+            ["see", "and", "here"]
+        );
+        assert_eq!(count_message_words("____ --- ok"), 1);
+        // Inner punctuation of real words stays
+        assert_eq!(
+            words("snake_case well-known", &options()),
+            ["snake_case", "well-known"]
+        );
+    }
+
+    #[test]
+    fn listed_phrase_is_one_token() {
+        let phrased = TokenizeOptions {
+            phrases: phrase_regex(&["borrow checker".to_string()]),
+            ..options()
+        };
+        assert_eq!(
+            words("The Borrow\n checker won, borrow it", &phrased),
+            ["the", "borrow checker", "won", "borrow", "it"]
+        );
+    }
+
+    #[test]
+    fn punctuation_runs_counted_when_included() {
+        assert_eq!(words("wow!!! ok?", &options()), ["wow", "ok"]);
+
+        let with_punctuation = TokenizeOptions {
+            include_punctuation: true,
+            ..options()
+        };
+        assert_eq!(
+            words("wow!!! ok?", &with_punctuation),
+            ["wow", "!!!", "ok", "?"]
+        );
+    }
+
+    #[test]
+    fn stem_with_three_forms_is_flagged() {
+        let stem = |stem: &str, form: &str| Token {
+            word: stem.to_string(),
+            original: form.to_string(),
+            message: 0,
+            emphasized: false,
+        };
+        let tokens = [
+            stem("univers", "university"),
+            stem("univers", "universe"),
+            stem("univers", "universal"),
+            stem("univers", "universe"),
+            stem("run", "running"),
+            stem("run", "runs"),
+        ];
+        let stem_forms = count_stem_forms(&tokens);
+        assert_eq!(stem_forms["univers"]["universe"], 2);
+
+        let collisions = stem_collisions(&stem_forms, 3);
+        assert_eq!(
+            collisions,
+            [("univers", vec!["universal", "universe", "university"])]
+        );
+        assert_eq!(stem_collisions(&stem_forms, 2).len(), 2);
+    }
+
+    #[test]
+    fn letters_only_drops_words_with_digits_or_hyphens() {
+        let text = "rust2 c-x rust snake_case ёжик";
+        assert_eq!(
+            words(text, &options()),
+            ["rust2", "c-x", "rust", "snake_case", "ёжик"]
+        );
+
+        let letters = TokenizeOptions {
+            letters_only: true,
+            ..options()
+        };
+        assert_eq!(words(text, &letters), ["rust", "ёжик"]);
+    }
+
+    #[test]
+    fn identifiers_split_into_words() {
+        let text = "readMessages word_count HTTPServer parse2Json plain";
+        assert_eq!(
+            words(text, &options()),
+            [
+                "readmessages",
+                "word_count",
+                "httpserver",
+                "parse2json",
+                "plain"
+            ]
+        );
+
+        let split = TokenizeOptions {
+            split_identifiers: true,
+            ..options()
+        };
+        assert_eq!(
+            words(text, &split),
+            [
+                "read", "messages", "word", "count", "http", "server", "parse2",
+                "json", "plain"
+            ]
+        );
+    }
+
+    #[test]
+    fn inner_punctuation_split_or_removed() {
+        let text = "e-mail don't won’t что-то и_т_д";
+        assert_eq!(
+            words(text, &options()),
+            ["e-mail", "don", "t", "won", "t", "что-то", "и_т_д"]
+        );
+
+        let split = TokenizeOptions {
+            inner_punctuation: Some(InnerPunctuation::Split),
+            ..options()
+        };
+        assert_eq!(
+            words(text, &split),
+            [
+                "e", "mail", "don", "t", "won", "t", "что", "то", "и", "т", "д"
+            ]
+        );
+
+        let remove = TokenizeOptions {
+            inner_punctuation: Some(InnerPunctuation::Remove),
+            ..options()
+        };
+        assert_eq!(
+            words(text, &remove),
+            ["email", "dont", "wont", "чтото", "итд"]
+        );
+    }
+
+    #[test]
+    fn tokens_longer_than_max_length_dropped() {
+        let hash = "a".repeat(60);
+        let text = format!("commit {} merged", hash);
+        assert_eq!(
+            words(&text, &options()),
+            ["commit", hash.as_str(), "merged"]
+        );
+
+        let options = TokenizeOptions {
+            max_length: Some(59),
+            ..options()
+        };
+        assert_eq!(words(&text, &options), ["commit", "merged"]);
+        let options = TokenizeOptions {
+            max_length: Some(60),
+            ..options
+        };
+        assert_eq!(words(&text, &options), ["commit", hash.as_str(), "merged"]);
+    }
+}
@@ -0,0 +1,40 @@
+"""Argument checks of clouds.py, run with `python -m unittest discover tests`"""
+
+import argparse
+import sys
+import unittest
+from pathlib import Path
+from unittest import mock
+
+# The plotting libraries aren't needed to check arguments
+for name in ('matplotlib', 'matplotlib.pyplot', 'wordcloud'):
+    sys.modules.setdefault(name, mock.MagicMock())
+sys.path.insert(0, str(Path(__file__).resolve().parent.parent))
+
+import clouds  # noqa: E402
+
+
+class RotateProportionTest(unittest.TestCase):
+    def test_accepts_range_ends(self):
+        self.assertEqual(clouds.proportion('0.0'), 0.0)
+        self.assertEqual(clouds.proportion('1'), 1.0)
+        self.assertEqual(clouds.proportion('0.25'), 0.25)
+
+    def test_rejects_out_of_range_and_non_numbers(self):
+        for value in ('1.5', '-0.1', 'half', ''):
+            with self.subTest(value=value):
+                with self.assertRaises(argparse.ArgumentTypeError):
+                    clouds.proportion(value)
+
+    def test_command_line_rejects_bad_value(self):
+        argv = ['clouds.py', '-i', 'in.txt', '-o', 'out.png',
+                '--rotate-proportion', '2']
+        with mock.patch.object(sys, 'argv', argv), \
+                mock.patch('sys.stderr'), \
+                self.assertRaises(SystemExit) as exit:
+            clouds.main()
+        self.assertEqual(exit.exception.code, 2)
+
+
+if __name__ == '__main__':
+    unittest.main()